@@ -94,7 +94,7 @@ pub fn render_main_rs(entities: &[EntitySpec]) -> String {
             entity.name
         ));
         controller_inits.push_str(&format!(
-            "    app.register_controller({snake}_controller);\n"
+            "    app.register_controller({snake}_controller).map_err(anyhow::Error::new)?;\n"
         ));
     }
 
@@ -117,7 +117,7 @@ fn main() -> Result<()> {{
     let controller = HealthController::new(system_info);
 
     let mut app = ember_core::App::new();
-    app.register_controller(controller);
+    app.register_controller(controller).map_err(anyhow::Error::new)?;
 {controller_inits}
     log_startup("ember-service", "ember-runtime");
     app.run().map_err(anyhow::Error::new)?;
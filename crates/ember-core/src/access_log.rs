@@ -0,0 +1,176 @@
+#![forbid(unsafe_code)]
+
+//! Per-request access logging.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use ember_ext_auth::SecurityContext;
+use ember_ext_http::{HandlerFuture, HttpResponse, Middleware, Next, Request, Route};
+use tracing::info;
+
+use crate::middleware::route_template;
+use crate::server::HttpVersion;
+use crate::server_config::{AccessLogConfig, AccessLogFormat};
+
+/// Logs one line per request under the `ember::access` target.
+///
+/// The structured format records method, route template, path, status,
+/// response bytes, latency, peer, user agent and authenticated subject as
+/// separate fields; the Common and Combined formats write the classic Apache
+/// text line instead. Register it first so the latency covers every other
+/// middleware and the logged size is what goes on the wire. Streamed bodies
+/// are logged when their headers are sent, without a byte count.
+#[derive(Debug)]
+pub struct LoggingMiddleware {
+    config: AccessLogConfig,
+    routes: Vec<Route>,
+    seen: AtomicU64,
+}
+
+impl LoggingMiddleware {
+    /// Log every request in the structured format.
+    pub fn new() -> Self {
+        Self::from_config(AccessLogConfig::default(), &[])
+    }
+
+    /// Build the middleware from `ember.server.access_log` for an app serving
+    /// `routes`, which are used to report the matched route template.
+    pub fn from_config(config: AccessLogConfig, routes: &[Route]) -> Self {
+        Self {
+            config,
+            routes: routes.to_vec(),
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    fn is_excluded(&self, path: &str) -> bool {
+        self.config.exclude_paths.iter().any(|excluded| match excluded.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == excluded,
+        })
+    }
+
+    /// Spread sampled lines evenly: log whenever the running count crosses
+    /// the next multiple of `1 / sample_rate`.
+    fn is_sampled(&self) -> bool {
+        let rate = self.config.sample_rate.clamp(0.0, 1.0);
+        if rate >= 1.0 {
+            return true;
+        }
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((seen + 1.0) * rate).floor() > (seen * rate).floor()
+    }
+
+    fn log(&self, request: &Request, response: Option<&HttpResponse>, latency: Duration) {
+        let status = response.map_or(500, |response| response.status);
+        let bytes = response.and_then(|response| response.stream.is_none().then_some(response.body.len()));
+        let subject = request
+            .extensions
+            .get::<SecurityContext>()
+            .map(|context| context.subject.as_str());
+        let user_agent = request.header("user-agent");
+        match self.config.format {
+            AccessLogFormat::Structured => info!(
+                target: "ember::access",
+                method = %request.method,
+                route = route_template(&self.routes, &request.method, &request.raw_path),
+                path = %request.path,
+                status,
+                bytes,
+                latency_ms = latency.as_secs_f64() * 1000.0,
+                peer = request.remote_addr.map(tracing::field::display),
+                user_agent,
+                subject,
+                "request handled"
+            ),
+            format => {
+                let mut line = common_line(request, status, bytes, subject);
+                if format == AccessLogFormat::Combined {
+                    let referer = request.header("referer").unwrap_or("-");
+                    let _ = write!(
+                        line,
+                        " \"{}\" \"{}\"",
+                        escape(referer),
+                        escape(user_agent.unwrap_or("-"))
+                    );
+                }
+                info!(target: "ember::access", "{line}");
+            }
+        }
+    }
+}
+
+impl Default for LoggingMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for LoggingMiddleware {
+    fn around<'a>(&'a self, request: &'a mut Request, next: Next<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            if !self.config.enabled || self.is_excluded(&request.path) {
+                return next.run(request).await;
+            }
+            let started = Instant::now();
+            let result = next.run(request).await;
+            let response = result.as_ref().ok();
+            if response.is_none_or(|response| response.status >= 500) || self.is_sampled() {
+                self.log(request, response, started.elapsed());
+            }
+            result
+        })
+    }
+}
+
+/// `host ident authuser [date] "request" status bytes`
+fn common_line(request: &Request, status: u16, bytes: Option<usize>, subject: Option<&str>) -> String {
+    let host = request
+        .remote_addr
+        .map_or_else(|| "-".to_string(), |addr| addr.ip().to_string());
+    let version = request
+        .extensions
+        .get::<HttpVersion>()
+        .map_or("HTTP/1.1", |version| version.0);
+    let target = match &request.raw_query {
+        Some(query) => format!("{}?{query}", request.path),
+        None => request.path.clone(),
+    };
+    let bytes = bytes.map_or_else(|| "-".to_string(), |bytes| bytes.to_string());
+    format!(
+        "{host} - {} [{}] \"{} {} {version}\" {status} {bytes}",
+        subject.map_or_else(|| "-".to_string(), |subject| escape(subject).replace(' ', "_")),
+        clf_timestamp(SystemTime::now()),
+        escape(&request.method),
+        escape(&target),
+    )
+}
+
+/// Format a time as `10/Oct/2000:13:55:36 +0000`.
+fn clf_timestamp(time: SystemTime) -> String {
+    // `Tue, 10 Oct 2000 13:55:36 GMT`
+    let http_date = httpdate::fmt_http_date(time);
+    let fields: Vec<&str> = http_date.split(' ').collect();
+    match fields.as_slice() {
+        [_, day, month, year, clock, _] => format!("{day}/{month}/{year}:{clock} +0000"),
+        _ => http_date,
+    }
+}
+
+/// Escape quotes, backslashes and control characters inside a quoted field.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            ch if ch.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", ch as u32);
+            }
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,74 @@
+#![forbid(unsafe_code)]
+
+//! Request body extraction for controller arguments.
+
+use ember_ext_http::{BodyFormat, HttpResponse, Request};
+use serde::de::DeserializeOwned;
+use tracing::debug;
+
+use crate::multipart;
+
+/// A body argument decoded from `application/x-www-form-urlencoded`.
+///
+/// ```ignore
+/// #[post("/login")]
+/// pub fn login(&self, form: Form<Credentials>) -> Json<Session> { /* ... */ }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Form<T>(pub T);
+
+impl<T: DeserializeOwned> Form<T> {
+    /// Decode the request body, answering `415` for other content types and
+    /// `400` for bodies that do not match `T`.
+    pub fn from_request(request: &Request) -> Result<Self, HttpResponse> {
+        match media_type(request).as_deref() {
+            Some("application/x-www-form-urlencoded") => decode_form(&request.body).map(Self),
+            _ => Err(unsupported(&["application/x-www-form-urlencoded"])),
+        }
+    }
+}
+
+/// Decode a body argument according to the request's `Content-Type`.
+///
+/// Every compiled-in [`BodyFormat`] is understood, including structured
+/// suffixes such as `+json`, as are `application/x-www-form-urlencoded` and
+/// the text fields of `multipart/form-data`. Bodies without a content type are
+/// read as JSON; anything else gets `415`.
+pub fn decode_body<T: DeserializeOwned>(request: &Request) -> Result<T, HttpResponse> {
+    let format = match media_type(request).as_deref() {
+        None => BodyFormat::Json,
+        Some("application/x-www-form-urlencoded") => return decode_form(&request.body),
+        Some("multipart/form-data") => return multipart::decode_fields(request),
+        Some(essence) => match BodyFormat::from_content_type(essence) {
+            Some(format) => format,
+            None => {
+                let mut accepted: Vec<&str> = BodyFormat::ALL.iter().map(|format| format.media_type()).collect();
+                accepted.extend(["application/x-www-form-urlencoded", "multipart/form-data"]);
+                return Err(unsupported(&accepted));
+            }
+        },
+    };
+    format.deserialize(&request.body).map_err(|err| {
+        debug!(error = %err, format = format.name(), "rejecting undecodable body");
+        HttpResponse::problem(400, err.to_string())
+    })
+}
+
+pub(crate) fn decode_form<T: DeserializeOwned>(body: &[u8]) -> Result<T, HttpResponse> {
+    serde_urlencoded::from_bytes(body).map_err(|err| {
+        debug!(error = %err, "rejecting invalid form body");
+        HttpResponse::problem(400, format!("invalid form body: {err}"))
+    })
+}
+
+/// The lower-cased media type of the request body, without parameters.
+pub(crate) fn media_type(request: &Request) -> Option<String> {
+    let content_type = request.header("content-type")?;
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    (!essence.is_empty()).then(|| essence.to_ascii_lowercase())
+}
+
+/// Answer `415`, naming the accepted types.
+fn unsupported(accepted: &[&str]) -> HttpResponse {
+    HttpResponse::problem(415, format!("unsupported Content-Type; expected {}", accepted.join(", ")))
+}
@@ -0,0 +1,247 @@
+#![forbid(unsafe_code)]
+
+//! Response compression and request body decompression.
+
+use std::io::{self, Read, Write};
+
+use ember_ext_http::{HttpResponse, Middleware, MiddlewareFuture, Request};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use tracing::debug;
+
+use crate::middleware::add_vary;
+use crate::server_config::CompressionConfig;
+
+/// A content coding the middleware can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn from_token(token: &str) -> Option<Self> {
+        match token.to_ascii_lowercase().as_str() {
+            "br" => Some(Self::Brotli),
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            _ => None,
+        }
+    }
+
+    fn token(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    fn encode(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli => {
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                writer.write_all(body)?;
+                Ok(writer.into_inner())
+            }
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Self::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Compresses responses according to `Accept-Encoding` and decodes
+/// compressed request bodies.
+///
+/// Responses are compressed only when their content type is on the allowlist
+/// and their body reaches the minimum size; every such response carries
+/// `Vary: Accept-Encoding` whether or not this client got it compressed.
+/// Request bodies are decoded before the handler runs, and the decoded size
+/// is held to the server's body limit.
+#[derive(Debug, Clone)]
+pub struct CompressionMiddleware {
+    config: CompressionConfig,
+    encodings: Vec<Encoding>,
+    max_body_bytes: usize,
+}
+
+impl CompressionMiddleware {
+    /// Build the middleware; decoded request bodies may not exceed `max_body_bytes`.
+    pub fn new(config: CompressionConfig, max_body_bytes: usize) -> Self {
+        let encodings = config
+            .encodings
+            .iter()
+            .filter_map(|token| Encoding::from_token(token))
+            .collect();
+        Self {
+            config,
+            encodings,
+            max_body_bytes,
+        }
+    }
+
+    fn decode_request(&self, request: &mut Request) -> Result<(), HttpResponse> {
+        let Some(coding) = request.header("content-encoding").map(str::trim) else {
+            return Ok(());
+        };
+        if coding.is_empty() || coding.eq_ignore_ascii_case("identity") {
+            request.headers.remove("content-encoding");
+            return Ok(());
+        }
+        let limit = self.max_body_bytes as u64;
+        let mut decoded = Vec::new();
+        let result = match Encoding::from_token(coding) {
+            Some(Encoding::Gzip) => GzDecoder::new(request.body.as_slice())
+                .take(limit + 1)
+                .read_to_end(&mut decoded),
+            Some(Encoding::Deflate) => ZlibDecoder::new(request.body.as_slice())
+                .take(limit + 1)
+                .read_to_end(&mut decoded),
+            _ => {
+                return Err(HttpResponse::problem(415, format!("unsupported Content-Encoding: {coding}"))
+                    .with_header("Accept-Encoding", "gzip, deflate"));
+            }
+        };
+        if let Err(err) = result {
+            debug!(error = %err, "rejecting undecodable request body");
+            return Err(HttpResponse::problem(400, "invalid compressed request body"));
+        }
+        if decoded.len() as u64 > limit {
+            return Err(HttpResponse::problem(
+                413,
+                format!("decoded request body exceeds the {} byte limit", self.max_body_bytes),
+            ));
+        }
+        request.body = decoded;
+        request.headers.remove("content-encoding");
+        request.headers.insert("content-length", request.body.len().to_string());
+        Ok(())
+    }
+
+    fn is_compressible(&self, response: &HttpResponse) -> bool {
+        if response.stream.is_some()
+            || response.body.len() < self.config.min_size_bytes
+            || matches!(response.status, 100..=199 | 204 | 206 | 304)
+            || response.headers.contains("content-encoding")
+        {
+            return false;
+        }
+        let no_transform = response
+            .headers
+            .get_all("cache-control")
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+        if no_transform {
+            return false;
+        }
+        let Some(content_type) = response.content_type.as_deref() else {
+            return false;
+        };
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        self.config.content_types.iter().any(|allowed| {
+            let allowed = allowed.to_ascii_lowercase();
+            if allowed.ends_with('/') {
+                essence.starts_with(&allowed)
+            } else {
+                essence == allowed
+            }
+        })
+    }
+
+    fn compress_response(&self, request: &Request, response: &mut HttpResponse) {
+        if !self.is_compressible(response) {
+            return;
+        }
+        add_vary(response, "Accept-Encoding");
+        let Some(encoding) = negotiate(&self.encodings, request.header("accept-encoding")) else {
+            return;
+        };
+        let compressed = match encoding.encode(&response.body) {
+            Ok(compressed) => compressed,
+            Err(err) => {
+                debug!(error = %err, encoding = encoding.token(), "compression failed; sending identity");
+                return;
+            }
+        };
+        if compressed.len() >= response.body.len() {
+            return;
+        }
+        response.body = compressed;
+        response.headers.insert("Content-Encoding", encoding.token());
+        if let Some(etag) = response.headers.get("etag")
+            && !etag.starts_with("W/")
+        {
+            let weak = format!("W/{etag}");
+            response.headers.insert("ETag", weak);
+        }
+    }
+}
+
+impl Middleware for CompressionMiddleware {
+    fn before<'a>(&'a self, request: &'a mut Request) -> MiddlewareFuture<'a, Option<HttpResponse>> {
+        let outcome = if self.config.decompress_requests {
+            self.decode_request(request).err()
+        } else {
+            None
+        };
+        Box::pin(async move { Ok(outcome) })
+    }
+
+    fn after<'a>(&'a self, request: &'a Request, response: &'a mut HttpResponse) -> MiddlewareFuture<'a, ()> {
+        if self.config.enabled {
+            self.compress_response(request, response);
+        }
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Pick the best coding from `Accept-Encoding`, honouring q-values and
+/// breaking ties by server preference.
+fn negotiate(supported: &[Encoding], accept: Option<&str>) -> Option<Encoding> {
+    let accept = accept?;
+    let mut wildcard = None;
+    let mut explicit: Vec<(Encoding, f32)> = Vec::new();
+    let mut refused: Vec<Encoding> = Vec::new();
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let token = parts.next().unwrap_or_default().trim();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if token == "*" {
+            wildcard = Some(quality);
+        } else if let Some(encoding) = Encoding::from_token(token) {
+            if quality > 0.0 {
+                explicit.push((encoding, quality));
+            } else {
+                refused.push(encoding);
+            }
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in supported {
+        let quality = explicit
+            .iter()
+            .find(|(candidate, _)| *candidate == encoding)
+            .map(|(_, quality)| *quality)
+            .or_else(|| wildcard.filter(|_| !refused.contains(&encoding)));
+        match quality {
+            Some(quality) if quality > 0.0 && best.is_none_or(|(_, current)| quality > current) => {
+                best = Some((encoding, quality));
+            }
+            _ => {}
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
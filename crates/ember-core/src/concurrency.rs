@@ -0,0 +1,299 @@
+#![forbid(unsafe_code)]
+
+//! Connection and in-flight request limits with load shedding.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ember_ext_http::{HandlerFuture, HttpResponse, Middleware, Next, Request};
+use ember_ext_runtime::LoadShedHook;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+use crate::server_config::ConcurrencyConfig;
+use crate::tls::IoStream;
+
+/// Factor applied to the adaptive limit when requests are too slow.
+const BACKOFF: f64 = 0.9;
+
+/// How long a shed connection may take to receive its `503`.
+const SHED_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Shed connections answered at once; further ones are closed without a response.
+const MAX_SHEDDING: usize = 64;
+
+/// Request bytes read and discarded after a shed response, so closing the
+/// socket does not reset the connection before the client reads the `503`.
+const SHED_DRAIN_BYTES: u64 = 16 * 1024;
+
+/// The response for shed load.
+fn overloaded(retry_after_seconds: u64, hook: Option<&LoadShedHook>) -> HttpResponse {
+    if let Some(hook) = hook {
+        hook();
+    }
+    HttpResponse::problem(503, "service overloaded").with_header("Retry-After", retry_after_seconds.to_string())
+}
+
+/// Caps the number of connections served at once.
+///
+/// The accept loop takes a slot before accepting, so connections over the
+/// limit wait in the listen backlog rather than holding descriptors. Once a
+/// slot has not freed up within the queue timeout, the limit starts shedding:
+/// pending connections are accepted one after another and answered with a
+/// canned `503` without reading a request, until a slot frees up again.
+pub(crate) struct ConnectionLimit {
+    slots: Arc<Semaphore>,
+    queue_timeout: Duration,
+    retry_after_seconds: u64,
+    hook: Option<LoadShedHook>,
+    shedding: Arc<Semaphore>,
+}
+
+impl ConnectionLimit {
+    /// The limit from `ember.server.concurrency`, if connections are limited.
+    pub(crate) fn from_config(config: &ConcurrencyConfig, hook: Option<LoadShedHook>) -> Option<Self> {
+        config.max_connections.map(|max| Self {
+            slots: Arc::new(Semaphore::new(max)),
+            queue_timeout: config.queue_timeout(),
+            retry_after_seconds: config.retry_after_seconds,
+            hook,
+            shedding: Arc::new(Semaphore::new(MAX_SHEDDING)),
+        })
+    }
+
+    /// Wait for a free slot, held until the connection closes; `None` once
+    /// the queue timeout elapses.
+    pub(crate) async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        let slot = Arc::clone(&self.slots).acquire_owned();
+        tokio::time::timeout(self.queue_timeout, slot).await.ok()?.ok()
+    }
+
+    /// A free slot, if there is one right now.
+    pub(crate) fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.slots).try_acquire_owned().ok()
+    }
+
+    /// Answer a connection that found no slot; the returned future writes the
+    /// response and closes the socket.
+    ///
+    /// Plain connections get a `503` with `Retry-After` and `Connection: close`
+    /// written straight to the socket, without reading a request. TLS
+    /// connections, and any beyond [`MAX_SHEDDING`] still being answered, are
+    /// closed at once without a response.
+    pub(crate) fn shed(&self, socket: Box<dyn IoStream>, tls: bool) -> Option<impl Future<Output = ()> + Send + 'static> {
+        debug!("connection limit reached, shedding connection");
+        let response = overloaded(self.retry_after_seconds, self.hook.as_ref());
+        if tls {
+            return None;
+        }
+        let permit = Arc::clone(&self.shedding).try_acquire_owned().ok()?;
+        let mut head = format!("HTTP/1.1 503 {}\r\n", response.reason_phrase());
+        if let Some(content_type) = &response.content_type {
+            head.push_str(&format!("Content-Type: {content_type}\r\n"));
+        }
+        for (name, value) in response.headers.iter() {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str(&format!("Connection: close\r\nContent-Length: {}\r\n\r\n", response.body.len()));
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&response.body);
+        Some(async move {
+            let _permit = permit;
+            let mut socket = socket;
+            let _ = tokio::time::timeout(SHED_WRITE_TIMEOUT, async {
+                socket.write_all(&bytes).await?;
+                socket.shutdown().await?;
+                tokio::io::copy(&mut (&mut socket).take(SHED_DRAIN_BYTES), &mut tokio::io::sink()).await
+            })
+            .await;
+        })
+    }
+}
+
+/// Limits the number of requests handled at once.
+///
+/// Requests over the limit wait up to the queue timeout and are then answered
+/// with `503` and `Retry-After`. With `adaptive` set the limit follows handler
+/// latency instead of staying fixed. A streamed response frees its slot when
+/// the handler returns, not when the stream ends.
+pub struct ConcurrencyLimitMiddleware {
+    limiter: Limiter,
+    queue_timeout: Duration,
+    retry_after_seconds: u64,
+    hook: Option<LoadShedHook>,
+}
+
+impl ConcurrencyLimitMiddleware {
+    /// Build the middleware from `ember.server.concurrency`; `None` when
+    /// neither `max_in_flight_requests` nor `adaptive` is set.
+    pub fn from_config(config: &ConcurrencyConfig) -> Option<Self> {
+        let max = config.max_in_flight_requests;
+        let limiter = match &config.adaptive {
+            Some(adaptive) => {
+                let max = max.unwrap_or(usize::MAX).max(1);
+                let min = adaptive.min_limit.clamp(1, max);
+                Limiter {
+                    state: Mutex::new(LimiterState {
+                        in_flight: 0,
+                        limit: adaptive.initial_limit.clamp(min, max) as f64,
+                        last_decrease: Instant::now(),
+                    }),
+                    released: Notify::new(),
+                    adaptive: Some(Adaptive {
+                        min: min as f64,
+                        max: max as f64,
+                        target: adaptive.target_latency(),
+                    }),
+                }
+            }
+            None => Limiter {
+                state: Mutex::new(LimiterState {
+                    in_flight: 0,
+                    limit: max? as f64,
+                    last_decrease: Instant::now(),
+                }),
+                released: Notify::new(),
+                adaptive: None,
+            },
+        };
+        Some(Self {
+            limiter,
+            queue_timeout: config.queue_timeout(),
+            retry_after_seconds: config.retry_after_seconds,
+            hook: None,
+        })
+    }
+
+    /// Run `hook` for every request shed, e.g. the hooks of
+    /// [`App::load_shed_hook`](crate::App::load_shed_hook).
+    pub fn with_shed_hook(mut self, hook: LoadShedHook) -> Self {
+        self.hook = Some(hook);
+        self
+    }
+
+    /// The current in-flight limit.
+    pub fn limit(&self) -> usize {
+        self.limiter.lock().limit as usize
+    }
+}
+
+impl fmt::Debug for ConcurrencyLimitMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConcurrencyLimitMiddleware")
+            .field("limiter", &self.limiter)
+            .field("queue_timeout", &self.queue_timeout)
+            .field("retry_after_seconds", &self.retry_after_seconds)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Middleware for ConcurrencyLimitMiddleware {
+    fn around<'a>(&'a self, request: &'a mut Request, next: Next<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let Some(_slot) = self.limiter.acquire(self.queue_timeout).await else {
+                debug!(method = %request.method, path = %request.path, "in-flight limit reached, shedding request");
+                return Ok(overloaded(self.retry_after_seconds, self.hook.as_ref()));
+            };
+            next.run(request).await
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Adaptive {
+    min: f64,
+    max: f64,
+    target: Duration,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    in_flight: usize,
+    limit: f64,
+    last_decrease: Instant,
+}
+
+impl LimiterState {
+    fn has_capacity(&self) -> bool {
+        self.in_flight < self.limit as usize
+    }
+}
+
+/// A counting limiter whose limit can change while requests wait.
+#[derive(Debug)]
+struct Limiter {
+    state: Mutex<LimiterState>,
+    released: Notify,
+    adaptive: Option<Adaptive>,
+}
+
+impl Limiter {
+    fn lock(&self) -> std::sync::MutexGuard<'_, LimiterState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    async fn acquire(&self, timeout: Duration) -> Option<Slot<'_>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // Register for a wakeup before checking, so a release in between is not missed.
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            {
+                let mut state = self.lock();
+                if state.has_capacity() {
+                    state.in_flight += 1;
+                    return Some(Slot {
+                        limiter: self,
+                        started: Instant::now(),
+                    });
+                }
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                // A wakeup meant for us may have arrived as we gave up; pass it on.
+                if self.lock().has_capacity() {
+                    self.released.notify_one();
+                }
+                return None;
+            }
+        }
+    }
+
+    /// Free a slot and let the adaptive limit react to the request's latency.
+    fn release(&self, latency: Duration) {
+        let mut state = self.lock();
+        let busy = state.in_flight as f64 * 2.0 >= state.limit;
+        state.in_flight -= 1;
+        if let Some(adaptive) = &self.adaptive {
+            if latency > adaptive.target {
+                if state.last_decrease.elapsed() >= adaptive.target {
+                    state.limit = (state.limit * BACKOFF).max(adaptive.min);
+                    state.last_decrease = Instant::now();
+                    let limit = state.limit as usize;
+                    debug!(limit, latency_ms = latency.as_millis() as u64, "concurrency limit lowered");
+                }
+            } else if busy {
+                state.limit = (state.limit + 1.0 / state.limit).min(adaptive.max);
+            }
+        }
+        let has_capacity = state.has_capacity();
+        drop(state);
+        if has_capacity {
+            self.released.notify_one();
+        }
+    }
+}
+
+/// A held in-flight slot, released on drop.
+struct Slot<'a> {
+    limiter: &'a Limiter,
+    started: Instant,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.limiter.release(self.started.elapsed());
+    }
+}
@@ -0,0 +1,234 @@
+#![forbid(unsafe_code)]
+
+//! Cross-origin resource sharing.
+
+use ember_ext_http::{HttpResponse, Middleware, MiddlewareFuture, Request, Route};
+use tracing::debug;
+
+use crate::middleware::add_vary;
+use crate::server_config::CorsConfig;
+
+/// Adds `Access-Control-*` headers and answers CORS preflight requests.
+///
+/// A preflight (`OPTIONS` with `Origin` and `Access-Control-Request-Method`)
+/// is answered directly for any path that matches a registered route, so
+/// controllers never need their own `OPTIONS` handlers. Register it ahead of
+/// authentication: browsers send preflights without credentials.
+#[derive(Debug, Clone)]
+pub struct CorsMiddleware {
+    config: CorsConfig,
+    routes: Vec<Route>,
+}
+
+impl CorsMiddleware {
+    /// Build the middleware for an app serving `routes`.
+    pub fn new(config: CorsConfig, routes: &[Route]) -> Self {
+        Self {
+            config,
+            routes: routes.to_vec(),
+        }
+    }
+
+    fn origin_allowed(&self, origin: &str) -> bool {
+        self.config
+            .allowed_origins
+            .iter()
+            .any(|pattern| matches_pattern(pattern, origin))
+    }
+
+    fn allows_any_origin(&self) -> bool {
+        self.config.allowed_origins.iter().any(|pattern| pattern == "*")
+    }
+
+    /// Methods a cross-origin caller may use on `path`.
+    fn methods_for(&self, path: &str) -> Vec<&'static str> {
+        let mut methods: Vec<&'static str> = Vec::new();
+        for route in self.routes.iter().filter(|route| route.matches_path(path)) {
+            let configured = self.config.allowed_methods.is_empty()
+                || self
+                    .config
+                    .allowed_methods
+                    .iter()
+                    .any(|method| method.eq_ignore_ascii_case(route.method));
+            if configured && !methods.contains(&route.method) {
+                methods.push(route.method);
+            }
+        }
+        methods
+    }
+
+    fn headers_allowed<'r>(&self, requested: &'r str) -> Result<Vec<&'r str>, &'r str> {
+        let requested: Vec<&str> = requested
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        if self.config.allowed_headers.iter().any(|name| name == "*") {
+            return Ok(requested);
+        }
+        match requested.iter().find(|name| {
+            !self
+                .config
+                .allowed_headers
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(name))
+        }) {
+            Some(rejected) => Err(rejected),
+            None => Ok(requested),
+        }
+    }
+
+    fn preflight(&self, request: &Request, origin: &str, method: &str) -> Option<HttpResponse> {
+        let methods = self.methods_for(&request.raw_path);
+        if methods.is_empty() {
+            return None;
+        }
+        let mut response = HttpResponse::empty(204);
+        add_vary(&mut response, "Origin");
+        add_vary(&mut response, "Access-Control-Request-Method");
+        add_vary(&mut response, "Access-Control-Request-Headers");
+        if !self.origin_allowed(origin) {
+            debug!(origin, path = %request.path, "rejecting CORS preflight from disallowed origin");
+            return Some(rejected(response, "origin not allowed"));
+        }
+        if !methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(method)) {
+            debug!(origin, method, path = %request.path, "rejecting CORS preflight for disallowed method");
+            return Some(rejected(response, "method not allowed"));
+        }
+        let headers = match self.headers_allowed(request.header("access-control-request-headers").unwrap_or("")) {
+            Ok(headers) => headers,
+            Err(header) => {
+                debug!(origin, header, path = %request.path, "rejecting CORS preflight for disallowed header");
+                return Some(rejected(response, "header not allowed"));
+            }
+        };
+
+        self.allow_origin(&mut response, origin);
+        response.headers.insert("Access-Control-Allow-Methods", methods.join(", "));
+        if !headers.is_empty() {
+            response.headers.insert("Access-Control-Allow-Headers", headers.join(", "));
+        }
+        if let Some(max_age) = self.config.max_age_seconds {
+            response.headers.insert("Access-Control-Max-Age", max_age.to_string());
+        }
+        Some(response)
+    }
+
+    /// Echo an allowed origin. `*` is never combined with credentials: the
+    /// config refuses it, and a config that skipped validation gets a plain `*`.
+    fn allow_origin(&self, response: &mut HttpResponse, origin: &str) {
+        if self.allows_any_origin() {
+            response.headers.insert("Access-Control-Allow-Origin", "*");
+            return;
+        }
+        response.headers.insert("Access-Control-Allow-Origin", origin);
+        add_vary(response, "Origin");
+        if self.config.allow_credentials {
+            response.headers.insert("Access-Control-Allow-Credentials", "true");
+        }
+    }
+}
+
+impl Middleware for CorsMiddleware {
+    fn before<'a>(&'a self, request: &'a mut Request) -> MiddlewareFuture<'a, Option<HttpResponse>> {
+        let preflight = match (request.method.as_str(), request.header("origin"), request.header("access-control-request-method")) {
+            ("OPTIONS", Some(origin), Some(method)) => self.preflight(request, origin, method),
+            _ => None,
+        };
+        Box::pin(async move { Ok(preflight) })
+    }
+
+    fn after<'a>(&'a self, request: &'a Request, response: &'a mut HttpResponse) -> MiddlewareFuture<'a, ()> {
+        if let Some(origin) = request.header("origin") {
+            if self.origin_allowed(origin) {
+                self.allow_origin(response, origin);
+                if !self.config.exposed_headers.is_empty() {
+                    response
+                        .headers
+                        .insert("Access-Control-Expose-Headers", self.config.exposed_headers.join(", "));
+                }
+            } else if !self.allows_any_origin() {
+                add_vary(response, "Origin");
+            }
+        }
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Answer a refused preflight with a `403` problem and no `Access-Control-Allow-*` headers.
+fn rejected(response: HttpResponse, reason: &str) -> HttpResponse {
+    let mut problem = HttpResponse::problem(403, format!("CORS preflight rejected: {reason}"));
+    for (name, value) in response.headers.iter() {
+        problem.headers.append(name, value);
+    }
+    problem
+}
+
+/// Match an origin against a pattern where `*` stands for any run of characters.
+fn matches_pattern(pattern: &str, origin: &str) -> bool {
+    let pattern = pattern.trim_end_matches('/').to_ascii_lowercase();
+    let origin = origin.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = origin.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &[&str], allow_credentials: bool) -> CorsMiddleware {
+        let config = CorsConfig {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            allow_credentials,
+            ..CorsConfig::default()
+        };
+        let route = Route {
+            method: "GET",
+            path: "/books",
+            etag: false,
+            cache_control: None,
+        };
+        CorsMiddleware::new(config, &[route])
+    }
+
+    #[test]
+    fn credentials_cannot_be_shared_with_any_origin() {
+        assert!(cors(&["*"], true).config.validate().is_err());
+        assert!(cors(&["**"], true).config.validate().is_err());
+        assert!(cors(&["*"], false).config.validate().is_ok());
+        assert!(cors(&["https://*.example.com"], true).config.validate().is_ok());
+
+        let mut response = HttpResponse::empty(200);
+        cors(&["*"], true).allow_origin(&mut response, "https://evil.example");
+        assert_eq!(response.headers.get("access-control-allow-origin"), Some("*"));
+        assert_eq!(response.headers.get("access-control-allow-credentials"), None);
+    }
+
+    #[test]
+    fn refused_preflights_are_problems() {
+        let mut request = Request::new("OPTIONS", "/books");
+        request.headers.insert("Origin", "https://evil.example");
+        request.headers.insert("Access-Control-Request-Method", "GET");
+        let response = cors(&["https://app.example"], true)
+            .preflight(&request, "https://evil.example", "GET")
+            .expect("answered");
+        assert_eq!(response.status, 403);
+        assert_eq!(response.content_type.as_deref(), Some("application/problem+json"));
+        assert_eq!(response.headers.get("access-control-allow-origin"), None);
+        assert!(response.headers.get("vary").is_some_and(|vary| vary.contains("Origin")));
+    }
+}
@@ -0,0 +1,264 @@
+#![forbid(unsafe_code)]
+
+//! Strong ETags, conditional requests and per-route `Cache-Control`.
+
+use ember_ext_http::{HandlerFuture, HttpResponse, Middleware, Next, Request, Route};
+use tracing::debug;
+
+use crate::middleware::find_route;
+use crate::server_config::EtagConfig;
+use crate::static_files::fnv1a;
+
+/// Tags `GET` responses with strong ETags and evaluates conditional requests.
+///
+/// For routes declared with `etag`, or every `GET` route when
+/// `ember.server.etag.enabled` is set, a successful buffered response gets an
+/// `ETag` hashed from its body unless the handler set one, and a matching
+/// `If-None-Match` turns it into `304 Not Modified`. `PUT`, `PATCH` and
+/// `DELETE` requests carrying `If-Match` first run the `GET` route for the
+/// path and get `412 Precondition Failed` unless one of the listed tags is
+/// current. A failing or non-`2xx` `GET` means there is no current
+/// representation, which fails every `If-Match`, `*` included. Routes
+/// declared with `cache_control` send it on successful responses that set no
+/// `Cache-Control` of their own.
+///
+/// The middleware belongs inside `CompressionMiddleware`, which marks the tags
+/// of compressed responses weak. `If-None-Match` compares tags weakly, so it
+/// matches such weakened tags; `If-Match` compares them strongly and never
+/// matches a weak tag.
+///
+/// The `If-Match` check and the write it guards are not atomic: another
+/// request may change the resource after its tag is read and before the
+/// handler runs. Handlers that must not lose updates need their own check,
+/// such as a version column compared in the `UPDATE`.
+#[derive(Debug, Clone)]
+pub struct HttpCacheMiddleware {
+    all_routes: bool,
+    routes: Vec<Route>,
+}
+
+impl HttpCacheMiddleware {
+    /// Build the middleware for an app serving `routes`; `None` when neither
+    /// the config nor any route asks for tags or `Cache-Control`.
+    pub fn new(config: &EtagConfig, routes: &[Route]) -> Option<Self> {
+        let wanted = config.enabled || routes.iter().any(|route| route.etag || route.cache_control.is_some());
+        wanted.then(|| Self {
+            all_routes: config.enabled,
+            routes: routes.to_vec(),
+        })
+    }
+
+    fn tags(&self, route: &Route) -> bool {
+        route.method.eq_ignore_ascii_case("GET") && (self.all_routes || route.etag)
+    }
+}
+
+impl Middleware for HttpCacheMiddleware {
+    fn around<'a>(&'a self, request: &'a mut Request, next: Next<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let route = find_route(&self.routes, &request.method, &request.raw_path);
+            let modifies = matches!(request.method.as_str(), "PUT" | "PATCH" | "DELETE");
+            if let Some(if_match) = request.header("if-match").filter(|_| modifies).map(str::to_string)
+                && find_route(&self.routes, "GET", &request.raw_path).is_some_and(|get| self.tags(get))
+            {
+                let current = current_tag(request, next).await;
+                if !current.as_deref().is_some_and(|tag| tag_listed(&if_match, tag, Comparison::Strong)) {
+                    debug!(method = %request.method, path = %request.path, "If-Match precondition failed");
+                    let detail = format!("{} does not match any tag in If-Match", request.path);
+                    return Ok(HttpResponse::problem(412, detail));
+                }
+            }
+
+            let mut response = next.run(&mut *request).await?;
+            let Some(route) = route else {
+                return Ok(response);
+            };
+            let readable = matches!(request.method.as_str(), "GET" | "HEAD");
+            if readable
+                && self.tags(route)
+                && let Some(tag) = response_tag(&response)
+            {
+                response.headers.insert("ETag", tag.clone());
+                if request
+                    .header("if-none-match")
+                    .is_some_and(|listed| tag_listed(listed, &tag, Comparison::Weak))
+                {
+                    response.status = 304;
+                    response.content_type = None;
+                    response.body.clear();
+                }
+            }
+            let successful = (200..300).contains(&response.status) || response.status == 304;
+            if let Some(directives) = route.cache_control
+                && successful
+                && response.headers.get("cache-control").is_none()
+            {
+                response.headers.insert("Cache-Control", directives);
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// The tag of the resource at the request's path, read by running its `GET`
+/// route; `None` when the resource has no current representation, including
+/// when the route fails or answers with a non-`2xx` status.
+async fn current_tag<'r>(request: &'r mut Request, next: Next<'r>) -> Option<String> {
+    let method = std::mem::replace(&mut request.method, "GET".to_string());
+    let body = std::mem::take(&mut request.body);
+    let current = next.run(&mut *request).await;
+    request.method = method;
+    request.body = body;
+    match current {
+        Ok(response) => response_tag(&response),
+        Err(err) => {
+            debug!(error = %err, path = %request.path, "no current representation for If-Match");
+            None
+        }
+    }
+}
+
+/// The tag a successful buffered response is sent with: the handler's own
+/// `ETag`, or a strong one hashed from the body.
+fn response_tag(response: &HttpResponse) -> Option<String> {
+    if !(200..300).contains(&response.status) || response.stream.is_some() || response.upgrade.is_some() {
+        return None;
+    }
+    let tag = match response.headers.get("etag") {
+        Some(tag) => tag.to_string(),
+        None => format!("\"{:016x}-{:x}\"", fnv1a(&response.body), response.body.len()),
+    };
+    Some(tag)
+}
+
+/// How entity tags are compared (RFC 9110, section 8.8.3.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    /// Both tags must be strong and identical.
+    Strong,
+    /// The opaque tags must be identical, whether weak or not.
+    Weak,
+}
+
+/// Return `true` if a comma-separated tag list holds `*` or a tag matching
+/// `tag` under `comparison`.
+fn tag_listed(listed: &str, tag: &str, comparison: Comparison) -> bool {
+    let matches = |candidate: &str| {
+        let (candidate_weak, candidate) = split_weak(candidate);
+        let (tag_weak, tag) = split_weak(tag);
+        candidate == tag && (comparison == Comparison::Weak || !(candidate_weak || tag_weak))
+    };
+    listed
+        .split(',')
+        .any(|candidate| candidate.trim() == "*" || matches(candidate))
+}
+
+/// Separate the `W/` prefix from a tag, returning whether it was weak.
+fn split_weak(tag: &str) -> (bool, &str) {
+    let tag = tag.trim();
+    match tag.strip_prefix("W/") {
+        Some(opaque) => (true, opaque),
+        None => (false, tag),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use ember_ext_http::{HandlerFuture, HttpHandler};
+
+    use super::*;
+
+    const ROUTES: &[Route] = &[
+        Route {
+            method: "GET",
+            path: "/doc",
+            etag: true,
+            cache_control: None,
+        },
+        Route {
+            method: "PUT",
+            path: "/doc",
+            etag: false,
+            cache_control: None,
+        },
+    ];
+
+    /// Serves one document, answering `404` once it is gone and failing while broken.
+    #[derive(Default)]
+    struct Doc {
+        body: Mutex<Option<String>>,
+        broken: Mutex<bool>,
+    }
+
+    impl HttpHandler for Doc {
+        fn handle<'a>(&'a self, request: &'a Request) -> HandlerFuture<'a> {
+            Box::pin(async move {
+                if *self.broken.lock().unwrap() {
+                    return Err(ember_ext_exceptions::EmberError::msg("database unavailable"));
+                }
+                let mut body = self.body.lock().unwrap();
+                match (request.method.as_str(), body.as_ref()) {
+                    ("GET", Some(current)) => Ok(HttpResponse::text(200, current.clone())),
+                    ("GET", None) => Ok(HttpResponse::problem(404, "")),
+                    _ => {
+                        *body = Some(String::from_utf8_lossy(&request.body).into_owned());
+                        Ok(HttpResponse::empty(204))
+                    }
+                }
+            })
+        }
+    }
+
+    async fn send(doc: &Doc, method: &str, header: Option<(&str, &str)>) -> HttpResponse {
+        let middleware: Vec<Arc<dyn Middleware>> =
+            vec![Arc::new(HttpCacheMiddleware::new(&EtagConfig::default(), ROUTES).unwrap())];
+        let mut request = Request::new(method, "/doc");
+        if let Some((name, value)) = header {
+            request.headers.insert(name, value);
+        }
+        request.body = b"new".to_vec();
+        Next::new(&middleware, doc).run(&mut request).await.unwrap()
+    }
+
+    fn doc(body: &str) -> Doc {
+        Doc {
+            body: Mutex::new(Some(body.to_string())),
+            ..Doc::default()
+        }
+    }
+
+    #[test]
+    fn if_match_compares_strongly_and_if_none_match_weakly() {
+        assert!(tag_listed("\"a\", \"b\"", "\"b\"", Comparison::Strong));
+        assert!(!tag_listed("W/\"b\"", "\"b\"", Comparison::Strong));
+        assert!(!tag_listed("\"b\"", "W/\"b\"", Comparison::Strong));
+        assert!(tag_listed("W/\"b\"", "\"b\"", Comparison::Weak));
+        assert!(tag_listed("*", "\"b\"", Comparison::Strong));
+        assert!(!tag_listed("\"c\"", "\"b\"", Comparison::Weak));
+    }
+
+    #[tokio::test]
+    async fn if_match_guards_writes_with_the_current_tag() {
+        let doc = doc("old");
+        let tag = send(&doc, "GET", None).await.headers.get("etag").unwrap().to_string();
+        assert_eq!(send(&doc, "GET", Some(("If-None-Match", &format!("W/{tag}")))).await.status, 304);
+
+        let weak = format!("W/{tag}");
+        assert_eq!(send(&doc, "PUT", Some(("If-Match", &weak))).await.status, 412);
+        assert_eq!(send(&doc, "PUT", Some(("If-Match", &tag))).await.status, 204);
+        assert_eq!(send(&doc, "PUT", Some(("If-Match", &tag))).await.status, 412);
+    }
+
+    #[tokio::test]
+    async fn if_match_fails_without_a_current_representation() {
+        let missing = Doc::default();
+        assert_eq!(send(&missing, "PUT", Some(("If-Match", "*"))).await.status, 412);
+
+        let broken = doc("old");
+        *broken.broken.lock().unwrap() = true;
+        assert_eq!(send(&broken, "PUT", Some(("If-Match", "*"))).await.status, 412);
+        assert_eq!(send(&doc("old"), "PUT", Some(("If-Match", "*"))).await.status, 204);
+    }
+}
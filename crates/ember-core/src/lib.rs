@@ -2,8 +2,26 @@
 
 //! Core types and minimal runtime API for Ember.
 
-use std::net::ToSocketAddrs;
-use std::collections::HashMap;
+mod access_log;
+mod body;
+mod compression;
+mod concurrency;
+mod cors;
+mod http_cache;
+mod listener;
+mod middleware;
+mod multipart;
+mod rate_limit;
+mod request_id;
+mod server;
+mod server_config;
+mod shutdown;
+mod sse;
+mod static_files;
+mod tls;
+mod websocket;
+
+use std::fmt;
 use std::path::{Path, PathBuf};
 
 use ember_ext_config::load_config_yaml_or_env;
@@ -11,20 +29,50 @@ use ember_ext_db::{DbContext, HasDbConfig};
 use ember_logging::log_startup;
 use serde::de::DeserializeOwned;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
-use tracing::error;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
 
 /// Facade re-exports for Ember extensions.
 pub use ember_ext_exceptions::{EmberError, ProblemDetails};
-pub use ember_ext_http::{Json, Route, Router};
-pub use ember_ext_runtime::App;
+pub use ember_ext_http::{
+    allowed_methods, reason_phrase, route_index, unrouted_response, BodyFormat, BodyStream, Extensions, HandlerFuture,
+    Headers, HttpHandler, HttpResponse, IntoResponse, Json, Middleware, MiddlewareFuture, Next, Request, Route, Router,
+    Upgrade, Upgraded, percent_decode,
+};
+pub use ember_ext_runtime::{App, LoadShedHook};
+pub use body::{decode_body, Form};
+pub use compression::CompressionMiddleware;
+pub use concurrency::ConcurrencyLimitMiddleware;
+pub use cors::CorsMiddleware;
+pub use http_cache::HttpCacheMiddleware;
+pub use access_log::LoggingMiddleware;
+pub use middleware::SecurityMiddleware;
+pub use multipart::{Multipart, Part};
+pub use rate_limit::{
+    InMemoryRateLimitStore, RateLimitDecision, RateLimitFuture, RateLimitMiddleware, RateLimitPolicy, RateLimitStage,
+    RateLimitStore,
+};
+pub use request_id::{RequestId, RequestIdMiddleware};
+pub use server_config::{
+    AccessLogConfig, AccessLogFormat, AdaptiveConcurrencyConfig, CompressionConfig, ConcurrencyConfig, CorsConfig, EtagConfig,
+    MultipartConfig, RateLimitAlgorithm, RateLimitConfig, RateLimitKey, RequestIdConfig, RouteRateLimit, ServerConfig, TlsConfig,
+};
+pub use shutdown::ShutdownHandle;
+pub use sse::{Sse, SseEvent, SseSender};
+pub use static_files::{EmbeddedFile, StaticFiles};
+pub use websocket::{
+    CloseFrame, WebSocket, WebSocketMessage, WebSocketOutcome, WebSocketReceiver, WebSocketSender, WebSocketUpgrade,
+};
 
 /// Commonly used Ember types.
 pub mod prelude {
     pub use crate::{
-        run_with_db_and_controller, run_with_db_and_controller_and_auth, App, EmberError,
-        HasEmberService, HttpHandler, HttpResponse, Json, ProblemDetails, Route, Router, RunOptions,
+        run_with_db_and_app, run_with_db_and_app_and_auth, run_with_db_and_controller,
+        run_with_db_and_controller_and_auth, App, EmberError, Form,
+        HandlerFuture, HasEmberService, HttpHandler, HttpResponse, IntoResponse, Json, Middleware, Multipart, Next,
+        ProblemDetails, Request, RequestId, Route, Router,
+        RunOptions, ServerConfig, ShutdownHandle, Sse, StaticFiles, TlsConfig, WebSocket, WebSocketMessage,
     };
 }
 
@@ -34,47 +82,14 @@ pub trait HasEmberService {
     fn service_name(&self) -> Option<&str>;
     /// Listen address for logging.
     fn listen_addr(&self) -> Option<&str>;
-}
-
-/// Minimal HTTP response returned by Ember handlers.
-#[derive(Debug, Clone)]
-pub struct HttpResponse {
-    /// HTTP status code.
-    pub status: u16,
-    /// Content type header value.
-    pub content_type: &'static str,
-    /// Response body bytes.
-    pub body: Vec<u8>,
-}
-
-impl HttpResponse {
-    /// Create an empty response with the given status code.
-    pub fn empty(status: u16) -> Self {
-        Self {
-            status,
-            content_type: "text/plain",
-            body: Vec::new(),
-        }
-    }
-
-    /// Create a plain text response.
-    pub fn text(status: u16, body: impl Into<String>) -> Self {
-        Self {
-            status,
-            content_type: "text/plain",
-            body: body.into().into_bytes(),
-        }
+    /// Built-in HTTP server settings; defaults apply when `None`.
+    fn server_config(&self) -> Option<&ServerConfig> {
+        None
     }
 }
 
-/// Trait for controllers that can handle HTTP requests.
-pub trait HttpHandler {
-    /// Handle an HTTP request and return a response.
-    fn handle(&self, method: &str, path: &str, body: &[u8]) -> Result<HttpResponse, EmberError>;
-}
-
 /// Options for running an Ember application.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct RunOptions<'a> {
     /// Base directory for locating `application*.yaml`.
     pub base_dir: &'a Path,
@@ -88,6 +103,25 @@ pub struct RunOptions<'a> {
     pub service_env: &'a str,
     /// Environment variable with the listen address for logging.
     pub listen_env: &'a str,
+    /// Handle that stops the service; clone it before running to stop it programmatically.
+    pub shutdown: ShutdownHandle,
+    /// Backend for `ember.server.rate_limit`; counts in memory when `None`.
+    pub rate_limit_store: Option<Arc<dyn RateLimitStore>>,
+}
+
+impl fmt::Debug for RunOptions<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RunOptions")
+            .field("base_dir", &self.base_dir)
+            .field("profile_env", &self.profile_env)
+            .field("config_env", &self.config_env)
+            .field("yaml_base_name", &self.yaml_base_name)
+            .field("service_env", &self.service_env)
+            .field("listen_env", &self.listen_env)
+            .field("shutdown", &self.shutdown)
+            .field("rate_limit_store", &self.rate_limit_store.is_some())
+            .finish()
+    }
 }
 
 impl<'a> RunOptions<'a> {
@@ -100,13 +134,25 @@ impl<'a> RunOptions<'a> {
             yaml_base_name: "application",
             service_env: "EMBER_SERVICE",
             listen_env: "EMBER_LISTEN",
+            shutdown: ShutdownHandle::new(),
+            rate_limit_store: None,
         }
     }
 
-    fn resolve_yaml_path(&self) -> PathBuf {
-        let profile = std::env::var(self.profile_env)
+    /// Count rate limits in a shared `store` instead of in memory.
+    pub fn with_rate_limit_store(mut self, store: Arc<dyn RateLimitStore>) -> Self {
+        self.rate_limit_store = Some(store);
+        self
+    }
+
+    fn profile(&self) -> Option<String> {
+        std::env::var(self.profile_env)
             .ok()
-            .filter(|value| !value.trim().is_empty());
+            .filter(|value| !value.trim().is_empty())
+    }
+
+    fn resolve_yaml_path(&self) -> PathBuf {
+        let profile = self.profile();
         let mut path = match profile.as_deref() {
             Some(value) => self
                 .base_dir
@@ -127,47 +173,15 @@ pub async fn run_with_db_and_controller<TConfig, TController, F>(
 ) -> Result<(), EmberError>
 where
     TConfig: DeserializeOwned + HasDbConfig + HasEmberService,
-    TController: ember_ext_runtime::ControllerMetadata + HttpHandler + Clone + Send + Sync + 'static,
+    TController: ember_ext_runtime::ControllerMetadata + HttpHandler + Send + Sync + 'static,
     F: FnOnce(TConfig) -> TController,
 {
-    let _ = dotenvy::dotenv();
-    if let Err(err) = ember_logging::init() {
-        return Err(EmberError::msg(format!("failed to initialize logging: {err}")));
-    }
-    let yaml_path = options.resolve_yaml_path();
-    let config = load_config_yaml_or_env::<TConfig>(
-        yaml_path.to_string_lossy().as_ref(),
-        options.config_env,
-    )?;
-    let service_name = config
-        .service_name()
-        .filter(|value| !value.trim().is_empty())
-        .map(str::to_string)
-        .or_else(|| {
-            std::env::var(options.service_env)
-                .ok()
-                .filter(|value| !value.trim().is_empty())
-        })
-        .unwrap_or_else(|| "ember-service".to_string());
-    let listen = config
-        .listen_addr()
-        .filter(|value| !value.trim().is_empty())
-        .map(str::to_string)
-        .or_else(|| {
-            std::env::var(options.listen_env)
-                .ok()
-                .filter(|value| !value.trim().is_empty())
-        })
-        .unwrap_or_else(|| "0.0.0.0:8080".to_string());
-    log_startup(&service_name, &listen);
-    let db = DbContext::new(config.db_config().clone());
-    let _pool = db.connect_and_migrate_entities().await?;
-
-    let controller = build_controller(config);
-    let mut app = App::new();
-    app.register_controller(controller.clone());
-    app.run()?;
-    run_basic_http(&listen, controller).await
+    run_with_db_and_app::<TConfig, _>(options, |config| {
+        let mut app = App::new();
+        app.register_controller(build_controller(config))?;
+        Ok(app)
+    })
+    .await
 }
 
 /// Run an Ember application with DB setup, controller, and auth filter.
@@ -177,9 +191,27 @@ pub async fn run_with_db_and_controller_and_auth<TConfig, TController, TFilter,
 ) -> Result<(), EmberError>
 where
     TConfig: DeserializeOwned + HasDbConfig + HasEmberService,
-    TController: ember_ext_runtime::ControllerMetadata + HttpHandler + Clone + Send + Sync + 'static,
+    TController: ember_ext_runtime::ControllerMetadata + HttpHandler + Send + Sync + 'static,
     TFilter: ember_ext_auth::SecurityFilter + Send + Sync + 'static,
     F: FnOnce(TConfig) -> (TController, TFilter),
+{
+    run_with_db_and_app_and_auth::<TConfig, _, _>(options, |config| {
+        let (controller, filter) = build_controller_and_filter(config);
+        let mut app = App::new();
+        app.register_controller(controller)?;
+        Ok((app, filter))
+    })
+    .await
+}
+
+/// Run an Ember application whose controllers are registered on an [`App`].
+///
+/// Every controller registered by `build_app` is served from every listen
+/// endpoint; duplicate routes fail startup.
+pub async fn run_with_db_and_app<TConfig, F>(options: RunOptions<'_>, build_app: F) -> Result<(), EmberError>
+where
+    TConfig: DeserializeOwned + HasDbConfig + HasEmberService,
+    F: FnOnce(TConfig) -> Result<App, EmberError>,
 {
     let _ = dotenvy::dotenv();
     if let Err(err) = ember_logging::init() {
@@ -210,202 +242,284 @@ where
                 .filter(|value| !value.trim().is_empty())
         })
         .unwrap_or_else(|| "0.0.0.0:8080".to_string());
-    log_startup(&service_name, &listen);
+    let server_config = config.server_config().cloned().unwrap_or_default();
+    if let Some(cors) = &server_config.cors {
+        cors.validate()?;
+    }
+    let endpoints = if server_config.listen.is_empty() {
+        vec![listen]
+    } else {
+        server_config.listen.clone()
+    };
+    log_startup(&service_name, &endpoints.join(", "));
+    let shutdown = options.shutdown.clone();
     let db = DbContext::new(config.db_config().clone());
-    let _pool = db.connect_and_migrate_entities().await?;
+    let pool = db.connect_and_migrate_entities().await?;
+    shutdown.on_shutdown(move || async move { pool.close().await });
 
-    let (controller, filter) = build_controller_and_filter(config);
-    let mut app = App::new();
-    app.register_controller(controller.clone());
+    let mut app = build_app(config)?;
+    app.expose_error_details(options.profile().as_deref() == Some("dev"));
+    if let Some(rate_limit) = &server_config.rate_limit {
+        let store = match &options.rate_limit_store {
+            Some(store) => Arc::clone(store),
+            None => Arc::new(InMemoryRateLimitStore::new()),
+        };
+        let limiter = |stage| {
+            RateLimitMiddleware::new(rate_limit.clone(), app.routes())
+                .with_store(Arc::clone(&store))
+                .for_stage(stage)
+        };
+        let (before_auth, after_auth) = (limiter(RateLimitStage::BeforeAuth), limiter(RateLimitStage::AfterAuth));
+        app.register_middleware_first(before_auth);
+        app.register_middleware(after_auth);
+    }
+    let compression = &server_config.compression;
+    if compression.enabled || compression.decompress_requests {
+        app.register_middleware(CompressionMiddleware::new(
+            compression.clone(),
+            server_config.max_body_bytes,
+        ));
+    }
+    if let Some(cache) = HttpCacheMiddleware::new(&server_config.etag, app.routes()) {
+        app.register_middleware(cache);
+    }
+    if let Some(cors) = &server_config.cors {
+        let cors = CorsMiddleware::new(cors.clone(), app.routes());
+        app.register_middleware_first(cors);
+    }
+    if let Some(mut limiter) = ConcurrencyLimitMiddleware::from_config(&server_config.concurrency) {
+        if let Some(hook) = app.load_shed_hook() {
+            limiter = limiter.with_shed_hook(hook);
+        }
+        app.register_middleware_first(limiter);
+    }
+    if server_config.access_log.enabled {
+        let access_log = LoggingMiddleware::from_config(server_config.access_log.clone(), app.routes());
+        app.register_middleware_first(access_log);
+    }
+    if server_config.request_id.enabled {
+        app.register_middleware_first(RequestIdMiddleware::new(server_config.request_id.clone()));
+    }
     app.run()?;
-    run_basic_http_with_auth(&listen, controller, filter).await
+    shutdown.listen_for_signals();
+    let served = run_basic_http(&endpoints, app, &server_config, &shutdown).await;
+    shutdown.run_hooks().await;
+    served?;
+    info!("service stopped");
+    Ok(())
 }
 
-async fn run_basic_http<T>(listen: &str, handler: T) -> Result<(), EmberError>
+/// Run an Ember application with DB setup, an [`App`] of controllers, and an auth filter.
+///
+/// The filter is registered as a [`SecurityMiddleware`] after any middleware
+/// registered by `build_app_and_filter`. Request ids, the access log,
+/// concurrency limits, CORS and rate limits keyed by IP, API key or route run
+/// before it; rate limits keyed by subject, compression and ETag handling run
+/// inside it.
+pub async fn run_with_db_and_app_and_auth<TConfig, TFilter, F>(
+    options: RunOptions<'_>,
+    build_app_and_filter: F,
+) -> Result<(), EmberError>
 where
-    T: HttpHandler + Send + Sync + 'static,
+    TConfig: DeserializeOwned + HasDbConfig + HasEmberService,
+    TFilter: ember_ext_auth::SecurityFilter + Send + Sync + 'static,
+    F: FnOnce(TConfig) -> Result<(App, TFilter), EmberError>,
 {
-    let mut addrs = listen
-        .to_socket_addrs()
-        .map_err(|err| EmberError::msg(format!("invalid listen address: {err}")))?;
-    let addr = addrs
-        .next()
-        .ok_or_else(|| EmberError::msg("listen address resolved to no sockets"))?;
-    let listener = TcpListener::bind(addr).await.map_err(|err| {
-        error!(error = %err, listen = %listen, "failed to bind listen address");
-        EmberError::msg(format!("failed to bind listen address: {err}"))
-    })?;
-    let handler = Arc::new(handler);
-    loop {
-        let (mut socket, _) = listener
-            .accept()
-            .await
-            .map_err(|err| EmberError::msg(format!("accept failed: {err}")))?;
-        let handler = Arc::clone(&handler);
-        tokio::spawn(async move {
-            let response = match read_http_request(&mut socket).await {
-                Ok((method, path, _headers, body)) => handler
-                    .handle(&method, &path, &body)
-                    .unwrap_or_else(|err| HttpResponse::text(500, err.to_string())),
-                Err(err) => HttpResponse::text(400, err.to_string()),
-            };
-            let _ = write_http_response(&mut socket, response).await;
-        });
-    }
+    run_with_db_and_app::<TConfig, _>(options, |config| {
+        let (mut app, filter) = build_app_and_filter(config)?;
+        app.register_middleware(SecurityMiddleware::new(filter));
+        Ok(app)
+    })
+    .await
 }
 
-async fn run_basic_http_with_auth<T, F>(listen: &str, handler: T, filter: F) -> Result<(), EmberError>
-where
-    T: HttpHandler + Send + Sync + 'static,
-    F: ember_ext_auth::SecurityFilter + Send + Sync + 'static,
-{
-    let mut addrs = listen
-        .to_socket_addrs()
-        .map_err(|err| EmberError::msg(format!("invalid listen address: {err}")))?;
-    let addr = addrs
-        .next()
-        .ok_or_else(|| EmberError::msg("listen address resolved to no sockets"))?;
-    let listener = TcpListener::bind(addr).await.map_err(|err| {
-        error!(error = %err, listen = %listen, "failed to bind listen address");
-        EmberError::msg(format!("failed to bind listen address: {err}"))
-    })?;
-    let handler = Arc::new(handler);
-    let filter = Arc::new(filter);
+async fn run_basic_http(
+    endpoints: &[String],
+    app: App,
+    server_config: &ServerConfig,
+    shutdown: &ShutdownHandle,
+) -> Result<(), EmberError> {
+    let listeners = listener::bind(endpoints, server_config)?;
+    let acceptor = Arc::new(tls::StreamAcceptor::from_config(server_config, shutdown)?);
+    for listener in listeners.iter() {
+        if acceptor.is_tls() {
+            info!(listen = %listener, "serving HTTPS");
+        } else {
+            info!(listen = %listener, "serving HTTP");
+        }
+    }
+    if acceptor.is_tls() {
+        let https_port = listeners.iter().find_map(|listener| listener.tcp_addr()).map_or(443, |addr| addr.port());
+        tls::start_redirect_listener(server_config, https_port, shutdown).await?;
+    }
+    let connection_limit = concurrency::ConnectionLimit::from_config(&server_config.concurrency, app.load_shed_hook());
+    let app = Arc::new(app);
+    let config = Arc::new(server_config.clone());
+    let mut connections = JoinSet::new();
+    let mut shedding = false;
     loop {
-        let (mut socket, _) = listener
-            .accept()
-            .await
-            .map_err(|err| EmberError::msg(format!("accept failed: {err}")))?;
-        let handler = Arc::clone(&handler);
-        let filter = Arc::clone(&filter);
-        tokio::spawn(async move {
-            let response = match read_http_request(&mut socket).await {
-                Ok((method, path, headers, body)) => {
-                    let (path_only, _) = match path.split_once('?') {
-                        Some((p, q)) => (p, Some(q)),
-                        None => (path.as_str(), None),
-                    };
-                    let request = ember_ext_auth::SecurityRequest {
-                        path: path_only.to_string(),
-                        method: method.clone(),
-                        authorization: headers.get("authorization").cloned(),
-                    };
-                    match filter.filter(&request) {
-                        Ok(_) => handler
-                            .handle(&method, &path, &body)
-                            .unwrap_or_else(|err| HttpResponse::text(500, err.to_string())),
-                        Err(_) => HttpResponse::text(401, "unauthorized"),
-                    }
+        while connections.try_join_next().is_some() {}
+        let mut slot = None;
+        if let Some(limit) = connection_limit.as_ref().filter(|_| !shedding) {
+            slot = tokio::select! {
+                slot = limit.acquire() => slot,
+                _ = shutdown.wait() => break,
+            };
+            shedding = slot.is_none();
+        }
+        let (socket, peer) = tokio::select! {
+            accepted = listeners.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // Usually transient (EMFILE, ECONNABORTED); keep serving.
+                    warn!(error = %err, "accept failed");
+                    tokio::time::sleep(listener::ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            },
+            _ = shutdown.wait() => break,
+        };
+        if let Some(limit) = connection_limit.as_ref().filter(|_| shedding) {
+            slot = limit.try_acquire();
+            if slot.is_none() {
+                if let Some(shed) = limit.shed(socket, acceptor.is_tls()) {
+                    connections.spawn(shed);
+                }
+                continue;
+            }
+            shedding = false;
+        }
+        let app = Arc::clone(&app);
+        let shutdown = shutdown.clone();
+        let config = Arc::clone(&config);
+        let acceptor = Arc::clone(&acceptor);
+        connections.spawn(async move {
+            let _slot = slot;
+            let stream = match acceptor.accept(socket).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!(error = %err, peer = ?peer, "dropping connection");
+                    return;
                 }
-                Err(err) => HttpResponse::text(400, err.to_string()),
             };
-            let _ = write_http_response(&mut socket, response).await;
+            server::serve_connection(stream, peer, shutdown, config, |mut request| {
+                let app = Arc::clone(&app);
+                async move {
+                    app.handle(&mut request)
+                        .await
+                        .unwrap_or_else(|err| app.error_response(&request, &err))
+                }
+            })
+            .await;
         });
     }
+    drop(listeners);
+    drain_connections(connections, server_config.shutdown_grace_period()).await;
+    Ok(())
 }
 
-async fn read_http_request(
-    socket: &mut tokio::net::TcpStream,
-) -> Result<(String, String, HashMap<String, String>, Vec<u8>), EmberError> {
-    let mut buffer = Vec::new();
-    let mut temp = [0u8; 1024];
-    let header_end;
-    loop {
-        let read = socket
-            .read(&mut temp)
-            .await
-            .map_err(|err| EmberError::msg(format!("read failed: {err}")))?;
-        if read == 0 {
-            return Err(EmberError::msg("connection closed"));
-        }
-        buffer.extend_from_slice(&temp[..read]);
-        if let Some(pos) = find_header_end(&buffer) {
-            header_end = pos;
-            break;
-        }
-        if buffer.len() > 64 * 1024 {
-            return Err(EmberError::msg("request headers too large"));
-        }
+/// Wait for open connections to finish, aborting whatever is left after `grace`.
+async fn drain_connections(mut connections: JoinSet<()>, grace: Duration) {
+    if connections.is_empty() {
+        return;
     }
+    info!(connections = connections.len(), "draining connections");
+    let drained = tokio::time::timeout(grace, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            connections = connections.len(),
+            "shutdown grace period elapsed, closing remaining connections"
+        );
+        connections.shutdown().await;
+    }
+}
 
-    let header_bytes = &buffer[..header_end];
-    let header_str = String::from_utf8_lossy(header_bytes);
-    let mut lines = header_str.lines();
-    let request_line = lines
-        .next()
-        .ok_or_else(|| EmberError::msg("missing request line"))?;
-    let mut parts = request_line.split_whitespace();
-    let method = parts
-        .next()
-        .ok_or_else(|| EmberError::msg("missing method"))?
-        .to_string();
-    let path = parts
-        .next()
-        .ok_or_else(|| EmberError::msg("missing path"))?
-        .to_string();
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener as StdTcpListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
-    let mut headers = HashMap::new();
-    let mut content_length = 0usize;
-    for line in lines {
-        if let Some((name, value)) = line.split_once(':') {
-            let name = name.trim().to_ascii_lowercase();
-            let value = value.trim().to_string();
-            if name == "content-length" {
-                content_length = value.parse::<usize>().unwrap_or(0);
-            }
-            headers.insert(name, value);
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    /// Answers `GET /slow` after a pause, long enough to shut down mid-request.
+    struct Slow;
+
+    impl ember_ext_runtime::ControllerMetadata for Slow {
+        fn routes() -> &'static [Route] {
+            &[Route {
+                method: "GET",
+                path: "/slow",
+                etag: false,
+                cache_control: None,
+            }]
         }
     }
 
-    let mut body = buffer[header_end + 4..].to_vec();
-    while body.len() < content_length {
-        let read = socket
-            .read(&mut temp)
-            .await
-            .map_err(|err| EmberError::msg(format!("read failed: {err}")))?;
-        if read == 0 {
-            break;
+    impl HttpHandler for Slow {
+        fn handle<'a>(&'a self, _request: &'a Request) -> HandlerFuture<'a> {
+            Box::pin(async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                Ok(HttpResponse::text(200, "done"))
+            })
         }
-        body.extend_from_slice(&temp[..read]);
     }
-    Ok((method, path, headers, body))
-}
 
-fn find_header_end(buffer: &[u8]) -> Option<usize> {
-    buffer
-        .windows(4)
-        .position(|window| window == b"\r\n\r\n")
-}
+    fn free_local_addr() -> String {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
 
-async fn write_http_response(
-    socket: &mut tokio::net::TcpStream,
-    response: HttpResponse,
-) -> Result<(), EmberError> {
-    let status_text = match response.status {
-        200 => "OK",
-        201 => "Created",
-        204 => "No Content",
-        400 => "Bad Request",
-        404 => "Not Found",
-        500 => "Internal Server Error",
-        _ => "OK",
-    };
-    let header = format!(
-        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nContent-Type: {}\r\n\r\n",
-        response.status,
-        status_text,
-        response.body.len(),
-        response.content_type
-    );
-    socket
-        .write_all(header.as_bytes())
-        .await
-        .map_err(|err| EmberError::msg(format!("write failed: {err}")))?;
-    if !response.body.is_empty() {
-        socket
-            .write_all(&response.body)
+    #[tokio::test]
+    async fn shutdown_drains_in_flight_requests_then_runs_hooks() {
+        let addr = free_local_addr();
+        let mut app = App::new();
+        app.register_controller(Slow).unwrap();
+        let shutdown = ShutdownHandle::new();
+        let hook_ran = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&hook_ran);
+        shutdown.on_shutdown(move || async move { flag.store(true, Ordering::SeqCst) });
+
+        let server = {
+            let (addr, shutdown) = (addr.clone(), shutdown.clone());
+            tokio::spawn(async move {
+                let served = run_basic_http(&[addr], app, &ServerConfig::default(), &shutdown).await;
+                shutdown.run_hooks().await;
+                served
+            })
+        };
+        let mut client = loop {
+            match TcpStream::connect(&addr).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        client
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
             .await
-            .map_err(|err| EmberError::msg(format!("write failed: {err}")))?;
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        shutdown.shutdown();
+        assert!(shutdown.is_shutdown());
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("done"), "{response}");
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server stops after draining")
+            .unwrap()
+            .unwrap();
+        assert!(hook_ran.load(Ordering::SeqCst));
+        assert!(TcpStream::connect(&addr).await.is_err(), "listener is closed after shutdown");
     }
-    Ok(())
 }
@@ -0,0 +1,364 @@
+#![forbid(unsafe_code)]
+
+//! Listen endpoints for the built-in server: TCP, Unix domain sockets and
+//! sockets inherited through systemd socket activation.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::future::poll_fn;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use ember_ext_exceptions::EmberError;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+use tracing::error;
+
+use crate::tls::IoStream;
+use crate::ServerConfig;
+
+/// An accepted connection and its peer address; Unix sockets have none.
+pub(crate) type Accepted = (Box<dyn IoStream>, Option<SocketAddr>);
+
+/// Pending connections queued by the kernel per listener.
+const BACKLOG: i32 = 1024;
+
+/// Pause after a failed accept, so running out of descriptors does not spin the loop.
+pub(crate) const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A bound socket accepting connections.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: tokio::net::UnixListener,
+        /// Removes the socket file when the listener is dropped.
+        file: Option<unix::SocketFile>,
+    },
+}
+
+impl Listener {
+    /// The bound TCP address, if this is a TCP listener.
+    pub(crate) fn tcp_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Self::Unix { .. } => None,
+        }
+    }
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<Accepted>> {
+        match self {
+            Self::Tcp(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(socket, peer)| (Box::new(socket) as Box<dyn IoStream>, Some(peer))),
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => listener
+                .poll_accept(cx)
+                .map_ok(|(socket, _)| (Box::new(socket) as Box<dyn IoStream>, None)),
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => f.write_str("tcp"),
+            },
+            #[cfg(unix)]
+            Self::Unix { file: Some(file), .. } => write!(f, "unix:{}", file.path().display()),
+            #[cfg(unix)]
+            Self::Unix { listener, file: None } => {
+                let addr = listener.local_addr().ok();
+                match addr.as_ref().and_then(|addr| addr.as_pathname()) {
+                    Some(path) => write!(f, "unix:{}", path.display()),
+                    None => f.write_str("unix"),
+                }
+            }
+        }
+    }
+}
+
+/// Accepts from whichever of several listeners has a connection ready.
+pub(crate) struct Listeners {
+    listeners: Vec<Listener>,
+    next: AtomicUsize,
+}
+
+impl Listeners {
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Listener> {
+        self.listeners.iter()
+    }
+
+    /// Wait for the next connection on any listener. Listeners are polled
+    /// round-robin so a busy one cannot starve the others.
+    pub(crate) async fn accept(&self) -> io::Result<Accepted> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        poll_fn(|cx| {
+            let count = self.listeners.len();
+            for offset in 0..count {
+                if let Poll::Ready(accepted) = self.listeners[(start + offset) % count].poll_accept(cx) {
+                    return Poll::Ready(accepted);
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// Bind every endpoint in `endpoints`.
+///
+/// An endpoint is `host:port` (every resolved address is bound, so
+/// `localhost:8080` listens on both `127.0.0.1` and `::1`), `unix:/path` for a
+/// Unix domain socket, or `systemd` for the sockets passed in through
+/// `LISTEN_FDS`. IPv6 sockets accept IPv4 too unless an IPv4 address is
+/// bound on the same port.
+pub(crate) fn bind(endpoints: &[String], config: &ServerConfig) -> Result<Listeners, EmberError> {
+    let mut tcp = Vec::new();
+    for endpoint in endpoints.iter().map(|endpoint| endpoint.trim()) {
+        if endpoint == "systemd" || endpoint.starts_with("unix:") {
+            continue;
+        }
+        let resolved: Vec<SocketAddr> = endpoint
+            .to_socket_addrs()
+            .map_err(|err| EmberError::msg(format!("invalid listen address `{endpoint}`: {err}")))?
+            .collect();
+        if resolved.is_empty() {
+            return Err(EmberError::msg(format!("listen address `{endpoint}` resolved to no sockets")));
+        }
+        for addr in resolved {
+            if !tcp.iter().any(|(bound, _)| *bound == addr) {
+                tcp.push((addr, endpoint));
+            }
+        }
+    }
+    let ipv4_ports: HashSet<u16> = tcp
+        .iter()
+        .filter(|(addr, _)| addr.is_ipv4())
+        .map(|(addr, _)| addr.port())
+        .collect();
+
+    let mut listeners = Vec::new();
+    for (addr, endpoint) in &tcp {
+        let only_v6 = addr.is_ipv6() && ipv4_ports.contains(&addr.port());
+        let listener = bind_tcp(*addr, only_v6).map_err(|err| {
+            error!(error = %err, listen = %endpoint, addr = %addr, "failed to bind listen address");
+            EmberError::msg(format!("failed to bind listen address {addr}: {err}"))
+        })?;
+        listeners.push(listener);
+    }
+    for endpoint in endpoints.iter().map(|endpoint| endpoint.trim()) {
+        if endpoint == "systemd" {
+            listeners.extend(systemd::listeners()?);
+        } else if let Some(path) = endpoint.strip_prefix("unix:") {
+            listeners.push(bind_unix(path, config.unix_socket_mode.as_deref())?);
+        }
+    }
+    if listeners.is_empty() {
+        return Err(EmberError::msg("no listen addresses configured"));
+    }
+    Ok(Listeners {
+        listeners,
+        next: AtomicUsize::new(0),
+    })
+}
+
+fn bind_tcp(addr: SocketAddr, only_v6: bool) -> io::Result<Listener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into()).map(Listener::Tcp)
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str, mode: Option<&str>) -> Result<Listener, EmberError> {
+    unix::bind(std::path::Path::new(path), mode)
+        .map_err(|err| EmberError::msg(format!("failed to bind unix socket {path}: {err}")))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(path: &str, _mode: Option<&str>) -> Result<Listener, EmberError> {
+    Err(EmberError::msg(format!("unix socket {path} is not supported on this platform")))
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::fs::{self, Permissions};
+    use std::io;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixStream;
+    use std::path::{Path, PathBuf};
+
+    use tokio::net::UnixListener;
+    use tracing::warn;
+
+    use super::Listener;
+
+    /// A socket file created by the server, removed again when the listener closes.
+    pub(crate) struct SocketFile(PathBuf);
+
+    impl SocketFile {
+        pub(super) fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for SocketFile {
+        fn drop(&mut self) {
+            if let Err(err) = fs::remove_file(&self.0) {
+                warn!(error = %err, path = %self.0.display(), "failed to remove unix socket");
+            }
+        }
+    }
+
+    /// Bind `path`, replacing a socket file left behind by a previous run and
+    /// applying the octal permission `mode`, e.g. `660`.
+    ///
+    /// The socket is bound inside a fresh `0700` directory next to `path` and
+    /// renamed into place once its mode is set, so it is never reachable with
+    /// the permissions the umask would have given it.
+    pub(super) fn bind(path: &Path, mode: Option<&str>) -> io::Result<Listener> {
+        let mode = mode
+            .map(|mode| {
+                u32::from_str_radix(mode.trim().trim_start_matches("0o"), 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid socket mode `{mode}`")))
+            })
+            .transpose()?;
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "path exists and is not a socket"));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "another process is listening"));
+            }
+            fs::remove_file(path)?;
+        }
+        let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty());
+        let staging = tempfile::Builder::new()
+            .prefix(".sock")
+            .tempdir_in(parent.unwrap_or(Path::new(".")))?;
+        let staged = staging.path().join("s");
+        let listener = UnixListener::bind(&staged)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(&staged, Permissions::from_mode(mode))?;
+        }
+        fs::rename(&staged, path)?;
+        Ok(Listener::Unix {
+            listener,
+            file: Some(SocketFile(path.to_path_buf())),
+        })
+    }
+}
+
+#[cfg(unix)]
+mod systemd {
+    use std::io;
+
+    use ember_ext_exceptions::EmberError;
+    use socket2::{Socket, Type};
+    use tokio::net::TcpListener;
+
+    use super::Listener;
+
+    /// Take over the sockets systemd passed to this process.
+    ///
+    /// The descriptors are marked close-on-exec so child processes do not
+    /// inherit them. `LISTEN_FDS` and `LISTEN_PID` are read but left set, as
+    /// changing the environment is unsound once other threads run.
+    pub(super) fn listeners() -> Result<Vec<Listener>, EmberError> {
+        let failed = |err: io::Error| EmberError::msg(format!("failed to take over systemd sockets: {err}"));
+        ember_ext_listenfd::take()
+            .map_err(failed)?
+            .into_iter()
+            .map(|fd| listener(Socket::from(fd)))
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(failed)
+    }
+
+    fn listener(socket: Socket) -> io::Result<Listener> {
+        if socket.r#type()? != Type::STREAM {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "only stream sockets can be served"));
+        }
+        socket.set_nonblocking(true)?;
+        if socket.local_addr()?.is_unix() {
+            let listener = std::os::unix::net::UnixListener::from(socket);
+            // systemd owns the socket file, so it is left in place on shutdown.
+            return tokio::net::UnixListener::from_std(listener).map(|listener| Listener::Unix { listener, file: None });
+        }
+        TcpListener::from_std(socket.into()).map(Listener::Tcp)
+    }
+}
+
+#[cfg(not(unix))]
+mod systemd {
+    use ember_ext_exceptions::EmberError;
+
+    use super::Listener;
+
+    pub(super) fn listeners() -> Result<Vec<Listener>, EmberError> {
+        Err(EmberError::msg("systemd socket activation is only supported on Unix"))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unix_sockets_are_created_with_their_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.sock");
+        let config = ServerConfig {
+            unix_socket_mode: Some("600".to_string()),
+            ..ServerConfig::default()
+        };
+        let endpoint = format!("unix:{}", path.display());
+        let listeners = bind(std::slice::from_ref(&endpoint), &config).unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert_eq!(listeners.iter().next().unwrap().to_string(), endpoint);
+
+        let (accepted, connected) = tokio::join!(listeners.accept(), tokio::net::UnixStream::connect(&path));
+        assert!(accepted.is_ok() && connected.is_ok());
+
+        drop(listeners);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn unix_socket_modes_must_be_octal() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig {
+            unix_socket_mode: Some("rw".to_string()),
+            ..ServerConfig::default()
+        };
+        let endpoint = format!("unix:{}", dir.path().join("app.sock").display());
+        assert!(bind(&[endpoint], &config).is_err());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn systemd_needs_sockets_for_this_process() {
+        let err = bind(&["systemd".to_string()], &ServerConfig::default()).err().unwrap();
+        assert!(err.to_string().contains("LISTEN_PID is not this process"));
+    }
+}
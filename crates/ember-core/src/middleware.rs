@@ -0,0 +1,80 @@
+#![forbid(unsafe_code)]
+
+//! Built-in middleware.
+
+use ember_ext_auth::{SecurityFilter, SecurityRequest};
+use ember_ext_exceptions::EmberError;
+use ember_ext_http::{route_index, HttpResponse, Middleware, MiddlewareFuture, Request, Route};
+use tracing::debug;
+
+/// Authenticates requests with a [`SecurityFilter`].
+///
+/// On success the resulting `SecurityContext` is stored in the request
+/// extensions; on failure the request is answered with a `401` problem, or
+/// with the status of an [`EmberError::status`] error, and the handler never
+/// runs.
+#[derive(Debug, Clone)]
+pub struct SecurityMiddleware<F> {
+    filter: F,
+}
+
+impl<F> SecurityMiddleware<F> {
+    /// Wrap a security filter.
+    pub fn new(filter: F) -> Self {
+        Self { filter }
+    }
+}
+
+impl<F> Middleware for SecurityMiddleware<F>
+where
+    F: SecurityFilter + Send + Sync,
+{
+    fn before<'a>(&'a self, request: &'a mut Request) -> MiddlewareFuture<'a, Option<HttpResponse>> {
+        let security_request = SecurityRequest {
+            path: request.path.clone(),
+            method: request.method.clone(),
+            authorization: request.header("authorization").map(str::to_string),
+        };
+        let outcome = match self.filter.filter(&security_request) {
+            Ok(context) => {
+                request.extensions.insert(context);
+                None
+            }
+            Err(err @ EmberError::Message { .. }) => {
+                debug!(error = %err, path = %request.path, "rejecting unauthenticated request");
+                Some(HttpResponse::problem(401, "authentication required"))
+            }
+            Err(err) => {
+                debug!(error = %err, path = %request.path, "rejecting request");
+                Some(HttpResponse::from_error(&err, false))
+            }
+        };
+        Box::pin(async move { Ok(outcome) })
+    }
+}
+
+/// Add a field name to `Vary` unless it is already listed.
+pub(crate) fn add_vary(response: &mut HttpResponse, field: &str) {
+    let listed = response
+        .headers
+        .get_all("vary")
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case(field)
+        });
+    if !listed {
+        response.headers.append("Vary", field);
+    }
+}
+
+/// The route that serves `method` and `path`, chosen the way the dispatcher
+/// chooses it.
+pub(crate) fn find_route<'r>(routes: &'r [Route], method: &str, path: &str) -> Option<&'r Route> {
+    route_index(routes, method, path).map(|index| &routes[index])
+}
+
+/// The template of the route that serves `method` and `path`.
+pub(crate) fn route_template(routes: &[Route], method: &str, path: &str) -> Option<&'static str> {
+    find_route(routes, method, path).map(|route| route.path)
+}
@@ -0,0 +1,689 @@
+#![forbid(unsafe_code)]
+
+//! `multipart/form-data` request bodies.
+
+use std::borrow::Cow;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ember_ext_exceptions::EmberError;
+use ember_ext_http::{Headers, HttpResponse, Request, percent_decode};
+use serde::de::DeserializeOwned;
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+
+use crate::body::{decode_form, media_type};
+use crate::server_config::{MultipartConfig, ServerConfig};
+
+/// Largest header block accepted for a single part.
+const MAX_PART_HEADER_BYTES: usize = 8 * 1024;
+
+/// Bytes read from the body at a time while parsing.
+const READ_CHUNK_BYTES: usize = 64 * 1024;
+
+/// A parsed `multipart/form-data` body.
+///
+/// Parts are checked against the server's `ember.server.multipart` limits as
+/// the body is read. File parts above the memory threshold are written to
+/// temporary files while they arrive; the files are deleted when the part is
+/// dropped, unless [`Part::persist`] moves them.
+#[derive(Debug)]
+pub struct Multipart {
+    parts: Vec<Part>,
+}
+
+impl Multipart {
+    /// Parse the request body, answering `415` for other content types, `413`
+    /// when a limit is exceeded and `400` for malformed bodies.
+    pub async fn from_request(request: &Request) -> Result<Self, HttpResponse> {
+        let (boundary, config) = boundary_and_config(request)?;
+        Self::parse(request.body.as_slice(), &boundary, &config).await
+    }
+
+    /// Parse a body delimited by `boundary`, reading it from `body` in chunks.
+    ///
+    /// A preamble before the first boundary and an epilogue after the closing
+    /// one are ignored; reading stops at the closing boundary.
+    pub async fn parse<R>(mut body: R, boundary: &str, config: &MultipartConfig) -> Result<Self, HttpResponse>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut parser = Parser::new(boundary, config);
+        let mut chunk = vec![0; READ_CHUNK_BYTES];
+        let mut eof = false;
+        let mut parts = Vec::new();
+        let mut current: Option<Incoming> = None;
+        loop {
+            match parser.next(eof)? {
+                Some(Event::Headers(block)) => {
+                    current = Some(Incoming {
+                        part: Part::from_headers(&block)?,
+                        contents: Contents::Memory(Vec::new()),
+                    });
+                }
+                Some(Event::Content(bytes)) => {
+                    if let Some(incoming) = &mut current {
+                        incoming.append(&bytes, config).await.map_err(store_failed)?;
+                    }
+                }
+                Some(Event::PartEnd) => {
+                    if let Some(incoming) = current.take() {
+                        parts.push(incoming.finish().await.map_err(store_failed)?);
+                    }
+                }
+                None if parser.finished() => break,
+                None => {
+                    let read = body
+                        .read(&mut chunk)
+                        .await
+                        .map_err(|_| malformed("failed to read the body"))?;
+                    eof = read == 0;
+                    parser.push(&chunk[..read]);
+                }
+            }
+        }
+        Ok(Self { parts })
+    }
+
+    /// Every part, in the order they were sent.
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+
+    /// The first part with the given field name.
+    pub fn part(&self, name: &str) -> Option<&Part> {
+        self.parts.iter().find(|part| part.name == name)
+    }
+
+    /// Parts that carry a file name.
+    pub fn files(&self) -> impl Iterator<Item = &Part> {
+        self.parts.iter().filter(|part| part.file_name.is_some())
+    }
+
+    /// Take ownership of the parts, e.g. to persist uploaded files.
+    pub fn into_parts(self) -> Vec<Part> {
+        self.parts
+    }
+
+    /// Deserialize the non-file fields into `T`, the same way a urlencoded form would be.
+    pub fn fields<T: DeserializeOwned>(&self) -> Result<T, HttpResponse> {
+        let mut pairs = Vec::new();
+        for part in self.parts.iter().filter(|part| part.file_name.is_none()) {
+            let value = part
+                .text()
+                .map_err(|_| malformed(&format!("field `{}` is not valid UTF-8", part.name)))?;
+            pairs.push((part.name.as_str(), value));
+        }
+        let encoded = serde_urlencoded::to_string(&pairs)
+            .map_err(|err| HttpResponse::problem(400, format!("invalid form body: {err}")))?;
+        decode_form(encoded.as_bytes())
+    }
+}
+
+/// Decode the text fields of a `multipart/form-data` request into `T`,
+/// skipping the contents of file parts rather than storing them.
+pub(crate) fn decode_fields<T: DeserializeOwned>(request: &Request) -> Result<T, HttpResponse> {
+    let (boundary, config) = boundary_and_config(request)?;
+    let mut parser = Parser::new(&boundary, &config);
+    parser.push(&request.body);
+    let mut parts = Vec::new();
+    let mut current = None;
+    while let Some(event) = parser.next(true)? {
+        match event {
+            Event::Headers(block) => {
+                current = Some(Part::from_headers(&block)?).filter(|part| part.file_name.is_none());
+            }
+            Event::Content(bytes) => {
+                if let Some(Part {
+                    data: PartData::Memory(buffered),
+                    ..
+                }) = &mut current
+                {
+                    buffered.extend_from_slice(&bytes);
+                }
+            }
+            Event::PartEnd => parts.extend(current.take()),
+        }
+    }
+    Multipart { parts }.fields()
+}
+
+/// The boundary of a multipart request and the limits that apply to it.
+fn boundary_and_config(request: &Request) -> Result<(String, MultipartConfig), HttpResponse> {
+    if media_type(request).as_deref() != Some("multipart/form-data") {
+        return Err(HttpResponse::problem(415, "unsupported Content-Type; expected multipart/form-data"));
+    }
+    let boundary = request
+        .header("content-type")
+        .and_then(|content_type| parameter(content_type, "boundary"))
+        .filter(|boundary| (1..=70).contains(&boundary.len()))
+        .ok_or_else(|| HttpResponse::problem(400, "missing or invalid multipart boundary"))?;
+    let config = match request.extensions.get::<Arc<ServerConfig>>() {
+        Some(config) => config.multipart.clone(),
+        None => MultipartConfig::default(),
+    };
+    Ok((boundary, config))
+}
+
+/// Where a part's contents are kept.
+#[derive(Debug)]
+enum PartData {
+    Memory(Vec<u8>),
+    File { file: NamedTempFile, len: u64 },
+}
+
+/// A single field or file of a [`Multipart`] body.
+#[derive(Debug)]
+pub struct Part {
+    name: String,
+    file_name: Option<String>,
+    content_type: Option<String>,
+    headers: Headers,
+    data: PartData,
+}
+
+impl Part {
+    /// A part with the given header block and no contents yet.
+    fn from_headers(header_block: &[u8]) -> Result<Self, HttpResponse> {
+        let header_block = std::str::from_utf8(header_block).map_err(|_| malformed("part headers are not UTF-8"))?;
+        let mut headers = Headers::new();
+        for line in header_block.split("\r\n").filter(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':').ok_or_else(|| malformed("invalid part header"))?;
+            headers.append(name.trim(), value.trim());
+        }
+        let disposition = headers
+            .get("content-disposition")
+            .ok_or_else(|| malformed("part without Content-Disposition"))?;
+        if !disposition.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("form-data") {
+            return Err(malformed("part is not form-data"));
+        }
+        let name = parameter(disposition, "name").ok_or_else(|| malformed("part without a name"))?;
+        let file_name = match parameter(disposition, "filename*") {
+            Some(extended) => Some(decode_extended(&extended)),
+            None => parameter(disposition, "filename"),
+        };
+        let content_type = headers.get("content-type").map(str::to_string);
+        Ok(Self {
+            name,
+            file_name,
+            content_type,
+            headers,
+            data: PartData::Memory(Vec::new()),
+        })
+    }
+
+    /// The form field name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The client's file name, for file parts.
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    /// The part's `Content-Type`, if sent.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// All headers sent with the part.
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Size of the contents in bytes.
+    pub fn len(&self) -> u64 {
+        match &self.data {
+            PartData::Memory(bytes) => bytes.len() as u64,
+            PartData::File { len, .. } => *len,
+        }
+    }
+
+    /// Return `true` if the part has no contents.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The temporary file holding the contents, if the part was written to disk.
+    pub fn path(&self) -> Option<&Path> {
+        match &self.data {
+            PartData::Memory(_) => None,
+            PartData::File { file, .. } => Some(file.path()),
+        }
+    }
+
+    /// The contents, read back from disk if necessary.
+    pub fn bytes(&self) -> Result<Cow<'_, [u8]>, EmberError> {
+        match &self.data {
+            PartData::Memory(bytes) => Ok(Cow::Borrowed(bytes)),
+            PartData::File { file, .. } => std::fs::read(file.path())
+                .map(Cow::Owned)
+                .map_err(|err| EmberError::msg(format!("failed to read upload: {err}"))),
+        }
+    }
+
+    /// The contents as UTF-8 text.
+    pub fn text(&self) -> Result<String, EmberError> {
+        String::from_utf8(self.bytes()?.into_owned())
+            .map_err(|_| EmberError::msg(format!("part `{}` is not valid UTF-8", self.name)))
+    }
+
+    /// Move the contents to `path`, keeping a temporary file instead of deleting it.
+    pub fn persist(self, path: impl AsRef<Path>) -> Result<(), EmberError> {
+        let path = path.as_ref();
+        let result = match self.data {
+            PartData::Memory(bytes) => std::fs::write(path, bytes),
+            PartData::File { file, .. } => match file.persist(path) {
+                Ok(_) => Ok(()),
+                Err(err) => std::fs::copy(err.file.path(), path).map(|_| ()),
+            },
+        };
+        result.map_err(|err| EmberError::msg(format!("failed to save upload to {}: {err}", path.display())))
+    }
+}
+
+/// Where the contents of a part being read go.
+enum Contents {
+    Memory(Vec<u8>),
+    File {
+        file: NamedTempFile,
+        writer: tokio::fs::File,
+        len: u64,
+    },
+}
+
+/// A part whose contents are still arriving.
+struct Incoming {
+    part: Part,
+    contents: Contents,
+}
+
+impl Incoming {
+    /// Add the next piece of contents, moving a file part to disk once it
+    /// outgrows the memory threshold.
+    async fn append(&mut self, bytes: &[u8], config: &MultipartConfig) -> io::Result<()> {
+        if let Contents::Memory(buffered) = &mut self.contents
+            && self.part.file_name.is_some()
+            && buffered.len() + bytes.len() > config.memory_threshold_bytes
+        {
+            let buffered = std::mem::take(buffered);
+            let (file, mut writer) = temp_file(config.temp_dir.clone()).await?;
+            writer.write_all(&buffered).await?;
+            self.contents = Contents::File {
+                file,
+                writer,
+                len: buffered.len() as u64,
+            };
+        }
+        match &mut self.contents {
+            Contents::Memory(buffered) => buffered.extend_from_slice(bytes),
+            Contents::File { writer, len, .. } => {
+                writer.write_all(bytes).await?;
+                *len += bytes.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    async fn finish(self) -> io::Result<Part> {
+        let mut part = self.part;
+        part.data = match self.contents {
+            Contents::Memory(bytes) => PartData::Memory(bytes),
+            Contents::File { file, mut writer, len } => {
+                writer.flush().await?;
+                PartData::File { file, len }
+            }
+        };
+        Ok(part)
+    }
+}
+
+/// Create a temporary file in `dir`, with a handle for writing to it asynchronously.
+async fn temp_file(dir: Option<PathBuf>) -> io::Result<(NamedTempFile, tokio::fs::File)> {
+    let (file, writer) = tokio::task::spawn_blocking(move || {
+        let file = NamedTempFile::new_in(dir.unwrap_or_else(std::env::temp_dir))?;
+        let writer = file.as_file().try_clone()?;
+        Ok::<_, io::Error>((file, writer))
+    })
+    .await
+    .map_err(io::Error::other)??;
+    Ok((file, tokio::fs::File::from_std(writer)))
+}
+
+fn store_failed(err: io::Error) -> HttpResponse {
+    debug!(error = %err, "failed to buffer upload to a temporary file");
+    HttpResponse::problem(500, "failed to store upload")
+}
+
+/// What the parser found next in the body.
+#[derive(Debug, PartialEq, Eq)]
+enum Event {
+    /// A part starts with this header block.
+    Headers(Vec<u8>),
+    /// The next piece of the current part's contents.
+    Content(Vec<u8>),
+    /// The current part is complete.
+    PartEnd,
+}
+
+/// Where the parser is within the body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Preamble,
+    Boundary,
+    Headers,
+    Content,
+    Done,
+}
+
+/// Incremental `multipart/form-data` parser, fed the body a chunk at a time.
+///
+/// Only input that may still hold part of a delimiter is kept between chunks,
+/// so memory use is bounded by the chunk size and the header limit.
+struct Parser {
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    stage: Stage,
+    parts: usize,
+    part_bytes: usize,
+    max_parts: usize,
+    max_part_bytes: usize,
+}
+
+impl Parser {
+    fn new(boundary: &str, config: &MultipartConfig) -> Self {
+        Self {
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            // The opening boundary may start the body, without a line break before it.
+            buf: b"\r\n".to_vec(),
+            stage: Stage::Preamble,
+            parts: 0,
+            part_bytes: 0,
+            max_parts: config.max_parts,
+            max_part_bytes: config.max_part_bytes,
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Return `true` once the closing boundary has been read.
+    fn finished(&self) -> bool {
+        self.stage == Stage::Done
+    }
+
+    /// The next event in the input pushed so far; `None` when more input is
+    /// needed or the body is finished. `eof` says no more input will come.
+    fn next(&mut self, eof: bool) -> Result<Option<Event>, HttpResponse> {
+        loop {
+            match self.stage {
+                Stage::Preamble => match find(&self.buf, &self.delimiter, 0) {
+                    Some(at) => {
+                        self.buf.drain(..at + self.delimiter.len());
+                        self.stage = Stage::Boundary;
+                    }
+                    None if eof => return Err(malformed("missing opening boundary")),
+                    None => {
+                        let keep = self.delimiter.len() - 1;
+                        self.buf.drain(..self.buf.len().saturating_sub(keep));
+                        return Ok(None);
+                    }
+                },
+                Stage::Boundary => {
+                    if self.buf.starts_with(b"--") {
+                        self.buf.clear();
+                        self.stage = Stage::Done;
+                        return Ok(None);
+                    }
+                    let padding = self.buf.iter().take_while(|byte| matches!(byte, b' ' | b'\t')).count();
+                    self.buf.drain(..padding);
+                    if self.buf.starts_with(b"\r\n") {
+                        self.buf.drain(..2);
+                        if self.parts == self.max_parts {
+                            return Err(HttpResponse::problem(
+                                413,
+                                format!("multipart body has more than {} parts", self.max_parts),
+                            ));
+                        }
+                        self.parts += 1;
+                        self.part_bytes = 0;
+                        self.stage = Stage::Headers;
+                    } else if !matches!(self.buf.as_slice(), [] | [b'\r'] | [b'-']) {
+                        return Err(malformed("expected a line break after the boundary"));
+                    } else if eof {
+                        return Err(malformed("missing closing boundary"));
+                    } else {
+                        return Ok(None);
+                    }
+                }
+                Stage::Headers => {
+                    let (block, consumed) = if self.buf.starts_with(b"\r\n") {
+                        (Vec::new(), 2)
+                    } else if let Some(end) = find(&self.buf, b"\r\n\r\n", 0) {
+                        (self.buf[..end].to_vec(), end + 4)
+                    } else if self.buf.len() > MAX_PART_HEADER_BYTES + 3 {
+                        return Err(malformed("part headers too large"));
+                    } else if eof {
+                        return Err(malformed("unterminated part headers"));
+                    } else {
+                        return Ok(None);
+                    };
+                    if block.len() > MAX_PART_HEADER_BYTES {
+                        return Err(malformed("part headers too large"));
+                    }
+                    self.buf.drain(..consumed);
+                    self.stage = Stage::Content;
+                    return Ok(Some(Event::Headers(block)));
+                }
+                Stage::Content => {
+                    let content_end = match find(&self.buf, &self.delimiter, 0) {
+                        Some(0) => {
+                            self.buf.drain(..self.delimiter.len());
+                            self.stage = Stage::Boundary;
+                            return Ok(Some(Event::PartEnd));
+                        }
+                        Some(at) => at,
+                        None if eof => return Err(malformed("unterminated part")),
+                        None => self.buf.len().saturating_sub(self.delimiter.len() - 1),
+                    };
+                    if content_end == 0 {
+                        return Ok(None);
+                    }
+                    self.part_bytes += content_end;
+                    if self.part_bytes > self.max_part_bytes {
+                        return Err(HttpResponse::problem(
+                            413,
+                            format!("multipart part exceeds the {} byte limit", self.max_part_bytes),
+                        ));
+                    }
+                    return Ok(Some(Event::Content(self.buf.drain(..content_end).collect())));
+                }
+                Stage::Done => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Decode an RFC 8187 extended value such as `UTF-8''na%C3%AFve.txt`.
+fn decode_extended(value: &str) -> String {
+    let encoded = value.splitn(3, '\'').nth(2).unwrap_or(value);
+    percent_decode(encoded, false)
+}
+
+/// Read a parameter from a header value like `form-data; name="file"; filename="a.txt"`.
+fn parameter(header: &str, wanted: &str) -> Option<String> {
+    let mut rest = header.split_once(';')?.1;
+    loop {
+        rest = rest.trim_start();
+        let eq = rest.find('=')?;
+        let name = rest[..eq].trim();
+        rest = &rest[eq + 1..];
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((index, ch)) = chars.next() {
+                match ch {
+                    '\\' => value.extend(chars.next().map(|(_, escaped)| escaped)),
+                    '"' => {
+                        end = index + 1;
+                        break;
+                    }
+                    other => value.push(other),
+                }
+            }
+            rest = &quoted[end..];
+            value
+        } else {
+            let end = rest.find(';').unwrap_or(rest.len());
+            let value = rest[..end].trim().to_string();
+            rest = &rest[end..];
+            value
+        };
+        if name.eq_ignore_ascii_case(wanted) {
+            return Some(value);
+        }
+        rest = rest.trim_start().strip_prefix(';')?;
+    }
+}
+
+/// Find `needle` in `haystack` at or after `from`.
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    let first = *needle.first()?;
+    let mut pos = from;
+    while pos + needle.len() <= haystack.len() {
+        let offset = haystack[pos..].iter().position(|byte| *byte == first)?;
+        pos += offset;
+        if haystack[pos..].starts_with(needle) {
+            return Some(pos);
+        }
+        pos += 1;
+    }
+    None
+}
+
+fn malformed(reason: &str) -> HttpResponse {
+    HttpResponse::problem(400, format!("malformed multipart body: {reason}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"This is the preamble.\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        Dune\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"cover\"; filename*=UTF-8''na%C3%AFve.txt\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        line one\r\nline two\r\n\
+        --XyZ--\r\n\
+        This is the epilogue, --XyZ\r\n";
+
+    async fn parse(body: &[u8], config: &MultipartConfig) -> Result<Multipart, HttpResponse> {
+        Multipart::parse(body, "XyZ", config).await
+    }
+
+    #[tokio::test]
+    async fn skips_preamble_and_epilogue() {
+        let multipart = parse(BODY, &MultipartConfig::default()).await.unwrap();
+        assert_eq!(multipart.parts().len(), 2);
+        assert_eq!(multipart.part("title").unwrap().text().unwrap(), "Dune");
+        let cover = multipart.part("cover").unwrap();
+        assert_eq!(cover.file_name(), Some("naïve.txt"));
+        assert_eq!(cover.content_type(), Some("text/plain"));
+        assert_eq!(cover.text().unwrap(), "line one\r\nline two");
+        assert!(cover.path().is_none());
+    }
+
+    #[tokio::test]
+    async fn spills_large_files_while_reading() {
+        let config = MultipartConfig {
+            memory_threshold_bytes: 4,
+            ..MultipartConfig::default()
+        };
+        let multipart = parse(BODY, &config).await.unwrap();
+        let cover = multipart.part("cover").unwrap();
+        let path = cover.path().unwrap().to_path_buf();
+        assert_eq!(std::fs::read(&path).unwrap(), b"line one\r\nline two");
+        assert_eq!(cover.len(), 18);
+        assert!(multipart.part("title").unwrap().path().is_none());
+        drop(multipart);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn finds_delimiters_split_across_chunks() {
+        let mut parser = Parser::new("XyZ", &MultipartConfig::default());
+        let mut events = Vec::new();
+        for byte in BODY {
+            parser.push(std::slice::from_ref(byte));
+            while let Some(event) = parser.next(false).unwrap() {
+                events.push(event);
+            }
+        }
+        assert!(parser.finished());
+        let content: Vec<u8> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Content(bytes) => Some(bytes.as_slice()),
+                _ => None,
+            })
+            .flatten()
+            .copied()
+            .collect();
+        assert_eq!(content, b"Duneline one\r\nline two");
+        assert_eq!(events.iter().filter(|event| **event == Event::PartEnd).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_missing_final_boundary() {
+        let unterminated = b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue";
+        assert_eq!(parse(unterminated, &MultipartConfig::default()).await.unwrap_err().status, 400);
+        let unclosed = b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue\r\n--XyZ";
+        assert_eq!(parse(unclosed, &MultipartConfig::default()).await.unwrap_err().status, 400);
+        assert_eq!(parse(b"no boundary here", &MultipartConfig::default()).await.unwrap_err().status, 400);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversize_parts_and_too_many_parts() {
+        let small = MultipartConfig {
+            max_part_bytes: 10,
+            ..MultipartConfig::default()
+        };
+        assert_eq!(parse(BODY, &small).await.unwrap_err().status, 413);
+        let few = MultipartConfig {
+            max_parts: 1,
+            ..MultipartConfig::default()
+        };
+        assert_eq!(parse(BODY, &few).await.unwrap_err().status, 413);
+    }
+
+    #[test]
+    fn decodes_fields_without_storing_files() {
+        #[derive(serde::Deserialize)]
+        struct Book {
+            title: String,
+        }
+        let mut request = Request::new("POST", "/books");
+        request.headers.insert("Content-Type", "multipart/form-data; boundary=XyZ");
+        request.body = BODY.to_vec();
+        let book: Book = decode_fields(&request).unwrap();
+        assert_eq!(book.title, "Dune");
+    }
+
+    #[test]
+    fn reads_quoted_and_extended_parameters() {
+        let disposition = r#"form-data; name="a \"b\""; filename="plain.txt"; filename*=UTF-8''%E2%82%AC.txt"#;
+        assert_eq!(parameter(disposition, "name").as_deref(), Some("a \"b\""));
+        assert_eq!(parameter(disposition, "filename").as_deref(), Some("plain.txt"));
+        assert_eq!(decode_extended(&parameter(disposition, "filename*").unwrap()), "€.txt");
+    }
+}
@@ -0,0 +1,440 @@
+#![forbid(unsafe_code)]
+
+//! Per-client rate limiting.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ember_ext_auth::SecurityContext;
+use ember_ext_exceptions::EmberError;
+use ember_ext_http::{HandlerFuture, HttpResponse, Middleware, Next, Request, Route};
+use tracing::{debug, warn};
+
+use crate::middleware::route_template;
+use crate::server_config::{RateLimitAlgorithm, RateLimitConfig, RateLimitKey};
+
+/// Sweep idle entries from the in-memory store every this many calls.
+const SWEEP_INTERVAL: u64 = 1024;
+
+/// The limit a key is checked against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitPolicy {
+    /// How requests are counted.
+    pub algorithm: RateLimitAlgorithm,
+    /// Requests allowed per window.
+    pub limit: u64,
+    /// Length of the window.
+    pub window: Duration,
+}
+
+/// The outcome of counting one request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitDecision {
+    /// Whether the request may proceed.
+    pub allowed: bool,
+    /// Requests allowed per window.
+    pub limit: u64,
+    /// Requests left right now.
+    pub remaining: u64,
+    /// Time until the full quota is available again.
+    pub reset_after: Duration,
+    /// For rejected requests, time until the next one would be allowed.
+    pub retry_after: Option<Duration>,
+}
+
+/// Future returned by [`RateLimitStore::acquire`].
+pub type RateLimitFuture<'a> = Pin<Box<dyn Future<Output = Result<RateLimitDecision, EmberError>> + Send + 'a>>;
+
+/// Backend that counts requests per key.
+///
+/// The default is [`InMemoryRateLimitStore`], which limits each instance on
+/// its own; implement this over a shared store such as Redis to enforce one
+/// limit across replicas. `acquire` must count and decide atomically.
+pub trait RateLimitStore: Send + Sync {
+    /// Count one request for `key` and decide whether it is allowed.
+    fn acquire<'a>(&'a self, key: &'a str, policy: &'a RateLimitPolicy) -> RateLimitFuture<'a>;
+}
+
+/// Per-key counters kept by [`InMemoryRateLimitStore`].
+#[derive(Debug)]
+enum Counter {
+    Bucket { tokens: f64, updated: Instant },
+    Window { start: Instant, current: u64, previous: u64 },
+}
+
+#[derive(Debug)]
+struct Entry {
+    counter: Counter,
+    expires: Instant,
+}
+
+/// A [`RateLimitStore`] held in process memory.
+///
+/// Keys idle for longer than their window are dropped periodically.
+#[derive(Debug, Default)]
+pub struct InMemoryRateLimitStore {
+    entries: Mutex<HashMap<String, Entry>>,
+    calls: AtomicU64,
+}
+
+impl InMemoryRateLimitStore {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn decide(&self, key: &str, policy: &RateLimitPolicy, now: Instant) -> RateLimitDecision {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if self.calls.fetch_add(1, Ordering::Relaxed) % SWEEP_INTERVAL == SWEEP_INTERVAL - 1 {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        let entry = entries.entry(key.to_string()).or_insert_with(|| Entry {
+            counter: Counter::new(policy.algorithm, now),
+            expires: now,
+        });
+        if !entry.counter.uses(policy.algorithm) {
+            entry.counter = Counter::new(policy.algorithm, now);
+        }
+        let window = policy.window.max(Duration::from_millis(1));
+        entry.expires = now + window * 2;
+        match &mut entry.counter {
+            Counter::Bucket { tokens, updated } => token_bucket(tokens, updated, policy.limit, window, now),
+            Counter::Window { start, current, previous } => {
+                sliding_window(start, current, previous, policy.limit, window, now)
+            }
+        }
+    }
+}
+
+impl RateLimitStore for InMemoryRateLimitStore {
+    fn acquire<'a>(&'a self, key: &'a str, policy: &'a RateLimitPolicy) -> RateLimitFuture<'a> {
+        let decision = self.decide(key, policy, Instant::now());
+        Box::pin(async move { Ok(decision) })
+    }
+}
+
+impl Counter {
+    fn new(algorithm: RateLimitAlgorithm, now: Instant) -> Self {
+        match algorithm {
+            RateLimitAlgorithm::TokenBucket => Self::Bucket {
+                tokens: f64::INFINITY,
+                updated: now,
+            },
+            RateLimitAlgorithm::SlidingWindow => Self::Window {
+                start: now,
+                current: 0,
+                previous: 0,
+            },
+        }
+    }
+
+    fn uses(&self, algorithm: RateLimitAlgorithm) -> bool {
+        matches!(
+            (self, algorithm),
+            (Self::Bucket { .. }, RateLimitAlgorithm::TokenBucket)
+                | (Self::Window { .. }, RateLimitAlgorithm::SlidingWindow)
+        )
+    }
+}
+
+/// `limit` tokens refilled at `limit / window` per second, one spent per request.
+fn token_bucket(
+    tokens: &mut f64,
+    updated: &mut Instant,
+    limit: u64,
+    window: Duration,
+    now: Instant,
+) -> RateLimitDecision {
+    let capacity = limit as f64;
+    let rate = capacity / window.as_secs_f64();
+    *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * rate).min(capacity);
+    *updated = now;
+    let allowed = *tokens >= 1.0;
+    if allowed {
+        *tokens -= 1.0;
+    }
+    RateLimitDecision {
+        allowed,
+        limit,
+        remaining: tokens.floor() as u64,
+        reset_after: Duration::from_secs_f64((capacity - *tokens) / rate),
+        retry_after: (!allowed).then(|| Duration::from_secs_f64((1.0 - *tokens) / rate)),
+    }
+}
+
+/// Requests in the current window plus the previous window's count weighted
+/// by how much of it still falls inside the sliding window.
+fn sliding_window(
+    start: &mut Instant,
+    current: &mut u64,
+    previous: &mut u64,
+    limit: u64,
+    window: Duration,
+    now: Instant,
+) -> RateLimitDecision {
+    let elapsed = now.duration_since(*start);
+    if elapsed >= window * 2 {
+        *previous = 0;
+        *current = 0;
+        *start = now;
+    } else if elapsed >= window {
+        *previous = *current;
+        *current = 0;
+        *start += window;
+    }
+    let window_secs = window.as_secs_f64();
+    let into_window = now.duration_since(*start).as_secs_f64();
+    let left_in_window = window_secs - into_window;
+    let estimate = *previous as f64 * (1.0 - into_window / window_secs) + *current as f64;
+    let allowed = estimate + 1.0 <= limit as f64;
+    let retry_after = if allowed {
+        *current += 1;
+        None
+    } else if *current < limit {
+        // The previous window's share decays at `previous / window` per second.
+        let excess = estimate + 1.0 - limit as f64;
+        Some((excess * window_secs / *previous as f64).min(left_in_window))
+    } else {
+        // Wait for this window to become the previous one and decay far enough.
+        let share = limit.saturating_sub(1) as f64 / *current as f64;
+        Some(left_in_window + window_secs * (1.0 - share))
+    };
+    let remaining = if allowed { (limit as f64 - estimate - 1.0).max(0.0) as u64 } else { 0 };
+    // Requests in this window weigh on the next one, so the quota is only
+    // fully back once it has passed too.
+    let reset_after = match (*current, *previous) {
+        (0, 0) => 0.0,
+        (0, _) => left_in_window,
+        _ => left_in_window + window_secs,
+    };
+    RateLimitDecision {
+        allowed,
+        limit,
+        remaining,
+        reset_after: Duration::from_secs_f64(reset_after),
+        retry_after: retry_after.map(Duration::from_secs_f64),
+    }
+}
+
+/// Which rules a [`RateLimitMiddleware`] applies, split by where the chain
+/// knows enough to key them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitStage {
+    /// Every rule.
+    #[default]
+    All,
+    /// Rules keyed by IP, API key or route. Register these ahead of
+    /// authentication, so requests it rejects are counted too.
+    BeforeAuth,
+    /// Rules keyed by subject, which need `SecurityMiddleware` to have run.
+    AfterAuth,
+}
+
+impl RateLimitStage {
+    fn applies_to(self, key: RateLimitKey) -> bool {
+        match self {
+            Self::All => true,
+            Self::BeforeAuth => key != RateLimitKey::Subject,
+            Self::AfterAuth => key == RateLimitKey::Subject,
+        }
+    }
+}
+
+/// Answers `429 Too Many Requests` once a client exceeds its limit.
+///
+/// Every limited response carries `RateLimit-Limit`, `RateLimit-Remaining`,
+/// `RateLimit-Reset` and `RateLimit-Policy`; rejected ones add `Retry-After`.
+/// Limiting by subject needs the middleware after `SecurityMiddleware`, while
+/// the other keys belong in front of it; [`RateLimitMiddleware::for_stage`]
+/// splits the rules between two instances sharing one store. If the store
+/// fails, the request is let through and the error logged.
+pub struct RateLimitMiddleware {
+    config: RateLimitConfig,
+    routes: Vec<Route>,
+    store: Arc<dyn RateLimitStore>,
+    stage: RateLimitStage,
+}
+
+impl fmt::Debug for RateLimitMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimitMiddleware")
+            .field("config", &self.config)
+            .field("stage", &self.stage)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RateLimitMiddleware {
+    /// Build the middleware for an app serving `routes`, counting in memory.
+    pub fn new(config: RateLimitConfig, routes: &[Route]) -> Self {
+        Self {
+            config,
+            routes: routes.to_vec(),
+            store: Arc::new(InMemoryRateLimitStore::new()),
+            stage: RateLimitStage::All,
+        }
+    }
+
+    /// Apply only the rules of `stage`; the others pass requests through.
+    pub fn for_stage(mut self, stage: RateLimitStage) -> Self {
+        self.stage = stage;
+        self
+    }
+
+    /// Count requests in `store` instead of in memory.
+    pub fn with_store(mut self, store: Arc<dyn RateLimitStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// The policy and key for a request, or `None` if it is not limited.
+    fn classify(&self, request: &Request) -> Option<(RateLimitPolicy, String)> {
+        let route = route_template(&self.routes, &request.method, &request.raw_path);
+        let rule = self.config.routes.iter().enumerate().find(|(_, rule)| {
+            (route == Some(rule.path.as_str()) || request.path == rule.path)
+                && rule
+                    .method
+                    .as_deref()
+                    .is_none_or(|method| method.eq_ignore_ascii_case(&request.method))
+        });
+        let (scope, policy, key) = match rule {
+            Some((index, rule)) => (
+                format!("rule{index}"),
+                RateLimitPolicy {
+                    algorithm: rule.algorithm.unwrap_or(self.config.algorithm),
+                    limit: rule.limit,
+                    window: rule.window(),
+                },
+                rule.key.unwrap_or(self.config.key),
+            ),
+            None => (
+                "default".to_string(),
+                RateLimitPolicy {
+                    algorithm: self.config.algorithm,
+                    limit: self.config.limit,
+                    window: self.config.window(),
+                },
+                self.config.key,
+            ),
+        };
+        if policy.limit == 0 || !self.stage.applies_to(key) {
+            return None;
+        }
+        let peer = || match request.remote_addr {
+            Some(addr) => format!("ip:{}", addr.ip()),
+            None => "ip:unknown".to_string(),
+        };
+        let client = match key {
+            RateLimitKey::Ip => peer(),
+            RateLimitKey::Subject => match request.extensions.get::<SecurityContext>() {
+                Some(context) => format!("subject:{}", context.subject),
+                None => peer(),
+            },
+            RateLimitKey::ApiKey => match request.header(&self.config.api_key_header) {
+                Some(api_key) => format!("api_key:{api_key}"),
+                None => peer(),
+            },
+            RateLimitKey::Route => format!(
+                "route:{} {}",
+                request.method,
+                route.unwrap_or(request.path.as_str())
+            ),
+        };
+        Some((policy, format!("{scope}|{client}")))
+    }
+}
+
+impl Middleware for RateLimitMiddleware {
+    fn around<'a>(&'a self, request: &'a mut Request, next: Next<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let Some((policy, key)) = self.classify(request) else {
+                return next.run(request).await;
+            };
+            let decision = match self.store.acquire(&key, &policy).await {
+                Ok(decision) => decision,
+                Err(err) => {
+                    warn!(error = %err, "rate limit store failed; allowing request");
+                    return next.run(request).await;
+                }
+            };
+            if !decision.allowed {
+                debug!(key = %key, path = %request.path, "rate limit exceeded");
+                let retry_after = decision.retry_after.unwrap_or(decision.reset_after);
+                let response = HttpResponse::problem(429, "rate limit exceeded")
+                    .with_header("Retry-After", whole_seconds(retry_after).to_string());
+                return Ok(with_rate_limit_headers(response, &policy, &decision));
+            }
+            let response = next.run(request).await?;
+            Ok(with_rate_limit_headers(response, &policy, &decision))
+        })
+    }
+}
+
+fn with_rate_limit_headers(
+    response: HttpResponse,
+    policy: &RateLimitPolicy,
+    decision: &RateLimitDecision,
+) -> HttpResponse {
+    response
+        .with_header("RateLimit-Limit", decision.limit.to_string())
+        .with_header("RateLimit-Remaining", decision.remaining.to_string())
+        .with_header("RateLimit-Reset", whole_seconds(decision.reset_after).to_string())
+        .with_header(
+            "RateLimit-Policy",
+            format!("{};w={}", policy.limit, whole_seconds(policy.window)),
+        )
+}
+
+/// Round up to whole seconds, as the headers require.
+fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_config::RouteRateLimit;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            key: RateLimitKey::ApiKey,
+            routes: vec![RouteRateLimit {
+                path: "/me".to_string(),
+                key: Some(RateLimitKey::Subject),
+                limit: 5,
+                ..RouteRateLimit::default()
+            }],
+            ..RateLimitConfig::default()
+        }
+    }
+
+    fn request(path: &str) -> Request {
+        let mut request = Request::new("GET", path);
+        request.headers.insert("X-Api-Key", "k1");
+        request.extensions.insert(SecurityContext::new("alice"));
+        request
+    }
+
+    #[test]
+    fn stages_split_rules_by_key() {
+        let before = RateLimitMiddleware::new(config(), &[]).for_stage(RateLimitStage::BeforeAuth);
+        let after = RateLimitMiddleware::new(config(), &[]).for_stage(RateLimitStage::AfterAuth);
+        let all = RateLimitMiddleware::new(config(), &[]);
+
+        let (_, key) = before.classify(&request("/books")).unwrap();
+        assert_eq!(key, "default|api_key:k1");
+        assert!(after.classify(&request("/books")).is_none());
+
+        assert!(before.classify(&request("/me")).is_none());
+        let (policy, key) = after.classify(&request("/me")).unwrap();
+        assert_eq!((policy.limit, key.as_str()), (5, "rule0|subject:alice"));
+
+        assert!(all.classify(&request("/books")).is_some());
+        assert!(all.classify(&request("/me")).is_some());
+    }
+}
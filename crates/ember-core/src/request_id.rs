@@ -0,0 +1,121 @@
+#![forbid(unsafe_code)]
+
+//! Request ids for correlating clients, logs and downstream calls.
+
+use std::fmt;
+
+use ember_ext_http::{HandlerFuture, HttpResponse, Middleware, Next, Request};
+use tracing::{info_span, Instrument};
+
+use crate::server_config::RequestIdConfig;
+
+/// Longest client-supplied id that is reused.
+const MAX_INCOMING_LEN: usize = 128;
+
+/// The id of the request being handled, stored in its extensions.
+///
+/// ```ignore
+/// let id = RequestId::from_request(request).map(RequestId::as_str);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Generate a random (UUID v4) id.
+    pub fn new() -> Self {
+        Self(uuid::Uuid::new_v4().to_string())
+    }
+
+    /// The id assigned to `request`, if request ids are enabled.
+    pub fn from_request(request: &Request) -> Option<&Self> {
+        request.extensions.get::<Self>()
+    }
+
+    /// The id as sent in the response header.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The id as a URI, used for `ProblemDetails.instance`.
+    pub fn instance_uri(&self) -> String {
+        format!("urn:ember:request:{}", self.0)
+    }
+
+    /// Accept a client-supplied id only if it is short and made of URI-safe characters.
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let well_formed = !value.is_empty()
+            && value.len() <= MAX_INCOMING_LEN
+            && value
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || b"-_.:+/=@".contains(&byte));
+        well_formed.then(|| Self(value.to_string()))
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Assigns each request a [`RequestId`].
+///
+/// A well-formed id from the configured header is reused when
+/// `trust_incoming` is set; otherwise a new one is generated. The rest of the
+/// chain runs inside a `request` span carrying the id, the id is echoed in the
+/// response, and `application/problem+json` bodies get it as their `instance`.
+#[derive(Debug, Clone)]
+pub struct RequestIdMiddleware {
+    config: RequestIdConfig,
+}
+
+impl RequestIdMiddleware {
+    /// Build the middleware from `ember.server.request_id`.
+    pub fn new(config: RequestIdConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl Middleware for RequestIdMiddleware {
+    fn around<'a>(&'a self, request: &'a mut Request, next: Next<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let incoming = if self.config.trust_incoming {
+                request.header(&self.config.header).and_then(RequestId::parse)
+            } else {
+                None
+            };
+            let id = incoming.unwrap_or_default();
+            request.extensions.insert(id.clone());
+            let span = info_span!("request", request_id = %id);
+            let mut response = next.run(request).instrument(span).await?;
+            set_problem_instance(&mut response, &id);
+            response.headers.insert(self.config.header.clone(), id.0);
+            Ok(response)
+        })
+    }
+}
+
+/// Point the `instance` member of a problem details body at the request id.
+fn set_problem_instance(response: &mut HttpResponse, id: &RequestId) {
+    let is_problem = response.content_type.as_deref().is_some_and(|content_type| {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        essence.eq_ignore_ascii_case("application/problem+json")
+    });
+    if !is_problem || response.stream.is_some() {
+        return;
+    }
+    let Ok(serde_json::Value::Object(mut problem)) = serde_json::from_slice(&response.body) else {
+        return;
+    };
+    problem.insert("instance".to_string(), id.instance_uri().into());
+    if let Ok(body) = serde_json::to_vec(&problem) {
+        response.body = body;
+    }
+}
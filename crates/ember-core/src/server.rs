@@ -0,0 +1,925 @@
+#![forbid(unsafe_code)]
+
+//! HTTP/1.1 connection handling for the built-in server.
+
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use ember_ext_exceptions::EmberError;
+use ember_ext_http::{BodyStream, Headers, Request, Upgraded};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, warn};
+
+use crate::{HttpResponse, ServerConfig, ShutdownHandle};
+
+/// A request read from a connection.
+pub(crate) struct RawRequest {
+    /// The parsed request.
+    pub request: Request,
+    /// Whether the client allows the connection to be reused.
+    pub keep_alive: bool,
+    /// Whether the client spoke HTTP/1.0.
+    pub http10: bool,
+}
+
+/// HTTP version of the request, stored in its extensions.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HttpVersion(pub(crate) &'static str);
+
+/// A request that could not be read, with the status to answer it with.
+#[derive(Debug)]
+pub(crate) struct ReadError {
+    /// HTTP status code for the error response.
+    pub status: u16,
+    /// Human-readable reason.
+    pub message: String,
+}
+
+impl ReadError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(400, message)
+    }
+
+    fn io(action: &str, err: std::io::Error) -> Self {
+        Self::bad_request(format!("{action} failed: {err}"))
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// A client connection with bytes buffered ahead of the current request.
+///
+/// Pipelined requests arrive back to back, so anything read past the end of
+/// one request stays in `buffer` and is parsed as the start of the next.
+pub(crate) struct Connection<S> {
+    stream: S,
+    buffer: Vec<u8>,
+    shutdown: ShutdownHandle,
+    config: Arc<ServerConfig>,
+}
+
+impl<S> Connection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Wrap an accepted stream.
+    pub fn new(stream: S, shutdown: ShutdownHandle, config: Arc<ServerConfig>) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            shutdown,
+            config,
+        }
+    }
+
+    /// Give up the stream along with any bytes read past the last request.
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.stream, self.buffer)
+    }
+
+    /// Read more bytes into the buffer, returning the number read.
+    async fn fill(&mut self) -> Result<usize, ReadError> {
+        let mut temp = [0u8; 4096];
+        let read = self
+            .stream
+            .read(&mut temp)
+            .await
+            .map_err(|err| ReadError::io("read", err))?;
+        self.buffer.extend_from_slice(&temp[..read]);
+        Ok(read)
+    }
+
+    /// Read more bytes, treating end of stream as an error.
+    async fn fill_more(&mut self) -> Result<(), ReadError> {
+        if self.fill().await? == 0 {
+            return Err(ReadError::bad_request("connection closed"));
+        }
+        Ok(())
+    }
+
+    /// Read the next request.
+    ///
+    /// Returns `Ok(None)` when the client closed the connection, stayed idle
+    /// past the keep-alive timeout, or the server began shutting down before
+    /// another request arrived.
+    pub async fn read_request(&mut self) -> Result<Option<RawRequest>, ReadError> {
+        if self.buffer.is_empty() {
+            let shutdown = self.shutdown.clone();
+            let idle_timeout = self.config.idle_timeout();
+            let idle = tokio::select! {
+                idle = tokio::time::timeout(idle_timeout, self.fill()) => idle,
+                _ = shutdown.wait() => return Ok(None),
+            };
+            match idle {
+                Ok(Ok(0)) => return Ok(None),
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => return Err(err),
+                Err(_) => {
+                    debug!("closing idle connection");
+                    return Ok(None);
+                }
+            }
+        }
+
+        let header_end = tokio::time::timeout(self.config.header_read_timeout(), self.read_head())
+            .await
+            .map_err(|_| ReadError::new(408, "timed out reading request headers"))??;
+
+        let header_str = String::from_utf8_lossy(&self.buffer[..header_end]).into_owned();
+        // Only CRLF ends a line; a bare CR or LF is refused by the line parsers
+        // rather than treated as a line break a proxy may not have seen.
+        let mut lines = header_str.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+        let (method, target, http10) = parse_request_line(request_line)?;
+
+        let mut headers = Headers::new();
+        let mut content_lengths = Vec::new();
+        let mut transfer_encodings = Vec::new();
+        for line in lines {
+            let (name, value) = parse_header_line(line)?;
+            match name.as_str() {
+                "content-length" => content_lengths.push(value.clone()),
+                "transfer-encoding" => transfer_encodings.push(value.clone()),
+                _ => {}
+            }
+            headers.append(name, value);
+        }
+
+        let body_start = header_end + 4;
+        let framing = body_framing(&content_lengths, &transfer_encodings, http10)?;
+        if let BodyFraming::Length(length) = framing
+            && length > self.config.max_body_bytes
+        {
+            return Err(ReadError::new(
+                413,
+                format!("request body of {length} bytes exceeds the {} byte limit", self.config.max_body_bytes),
+            ));
+        }
+        let expects_continue = headers
+            .get("expect")
+            .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"));
+        if expects_continue && !matches!(framing, BodyFraming::Length(0)) && self.buffer.len() == body_start {
+            self.stream
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await
+                .map_err(|err| ReadError::io("write", err))?;
+        }
+        let (body, trailers, body_end) =
+            tokio::time::timeout(self.config.body_read_timeout(), self.read_body(framing, body_start))
+                .await
+                .map_err(|_| ReadError::new(408, "timed out reading request body"))??;
+        self.buffer.drain(..body_end);
+        let mut trailer_fields = Headers::new();
+        for (name, value) in trailers {
+            trailer_fields.append(name, value);
+        }
+
+        let keep_alive = wants_keep_alive(headers.get("connection"), http10);
+        let request = Request::new(method, &target)
+            .with_headers(headers)
+            .with_trailers(trailer_fields)
+            .with_body(body);
+        Ok(Some(RawRequest {
+            request,
+            keep_alive,
+            http10,
+        }))
+    }
+
+    /// Read until the end of the header block, returning its offset.
+    async fn read_head(&mut self) -> Result<usize, ReadError> {
+        loop {
+            if let Some(pos) = find_header_end(&self.buffer) {
+                if pos > self.config.max_header_bytes {
+                    break;
+                }
+                return Ok(pos);
+            }
+            if self.buffer.len() > self.config.max_header_bytes {
+                break;
+            }
+            self.fill_more().await?;
+        }
+        Err(ReadError::new(
+            431,
+            format!("request headers exceed the {} byte limit", self.config.max_header_bytes),
+        ))
+    }
+
+    /// Read a body with the given framing starting at `start`.
+    ///
+    /// Returns the body, any trailer fields and the buffer offset just past it.
+    async fn read_body(
+        &mut self,
+        framing: BodyFraming,
+        start: usize,
+    ) -> Result<(Vec<u8>, Vec<(String, String)>, usize), ReadError> {
+        match framing {
+            BodyFraming::Length(length) => {
+                while self.buffer.len() < start + length {
+                    self.fill_more().await?;
+                }
+                Ok((self.buffer[start..start + length].to_vec(), Vec::new(), start + length))
+            }
+            BodyFraming::Chunked => self.read_chunked_body(start).await,
+        }
+    }
+
+    /// Decode a `Transfer-Encoding: chunked` body starting at `start`.
+    ///
+    /// Returns the decoded body, any trailer fields and the buffer offset just
+    /// past the final CRLF.
+    async fn read_chunked_body(
+        &mut self,
+        start: usize,
+    ) -> Result<(Vec<u8>, Vec<(String, String)>, usize), ReadError> {
+        let mut body = Vec::new();
+        let mut pos = start;
+        loop {
+            let (line, next) = self.read_line(pos).await?;
+            let size = parse_chunk_size(&line)?;
+            pos = next;
+            if size == 0 {
+                break;
+            }
+            if body.len().saturating_add(size) > self.config.max_body_bytes {
+                return Err(ReadError::new(
+                    413,
+                    format!("request body exceeds the {} byte limit", self.config.max_body_bytes),
+                ));
+            }
+            let chunk_end = pos + size + 2;
+            while self.buffer.len() < chunk_end {
+                self.fill_more().await?;
+            }
+            if &self.buffer[chunk_end - 2..chunk_end] != b"\r\n" {
+                return Err(ReadError::bad_request("chunk data not terminated by CRLF"));
+            }
+            body.extend_from_slice(&self.buffer[pos..chunk_end - 2]);
+            pos = chunk_end;
+        }
+
+        let mut trailers = Vec::new();
+        let trailer_start = pos;
+        loop {
+            let (line, next) = self.read_line(pos).await?;
+            pos = next;
+            if line.is_empty() {
+                break;
+            }
+            if pos - trailer_start > self.config.max_header_bytes {
+                return Err(ReadError::new(431, "request trailers too large"));
+            }
+            trailers.push(parse_header_line(&line)?);
+        }
+        Ok((body, trailers, pos))
+    }
+
+    /// Read a CRLF-terminated line starting at `pos`.
+    ///
+    /// Returns the line without its terminator and the offset of the next line.
+    async fn read_line(&mut self, pos: usize) -> Result<(String, usize), ReadError> {
+        loop {
+            if let Some(offset) = self.buffer[pos..]
+                .windows(2)
+                .position(|window| window == b"\r\n")
+            {
+                let line = std::str::from_utf8(&self.buffer[pos..pos + offset])
+                    .map_err(|_| ReadError::bad_request("invalid chunked encoding"))?
+                    .to_string();
+                return Ok((line, pos + offset + 2));
+            }
+            if self.buffer.len() - pos > self.config.max_header_bytes {
+                return Err(ReadError::bad_request("chunk line too long"));
+            }
+            self.fill_more().await?;
+        }
+    }
+
+    /// Write a response, announcing whether the connection stays open.
+    ///
+    /// Responses to `HEAD` keep their `Content-Length` but send no body.
+    /// Streaming bodies of known length are sent with `Content-Length`; others
+    /// use chunked encoding, or are sent until the connection closes for
+    /// HTTP/1.0 clients. Once shutdown starts a stream may run for the grace
+    /// period; one still going then fails, so the client sees a truncated body
+    /// rather than a complete one. Each write must finish within the write
+    /// timeout.
+    pub async fn write_response(
+        &mut self,
+        mut response: HttpResponse,
+        keep_alive: bool,
+        http10: bool,
+        head_request: bool,
+    ) -> Result<(), WriteError> {
+        let streaming = response.stream.take().filter(|_| allows_content_length(response.status));
+        let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, response.reason_phrase());
+        if let Some(content_type) = response.content_type.as_deref() {
+            push_header(&mut head, "Content-Type", content_type);
+        }
+        let sized = streaming.as_ref().and_then(BodyStream::content_length);
+        if let Some(len) = sized {
+            head.push_str(&format!("Content-Length: {len}\r\n"));
+        } else if streaming.is_some() {
+            if !http10 {
+                head.push_str("Transfer-Encoding: chunked\r\n");
+            }
+        } else if allows_content_length(response.status) {
+            head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+        }
+        for (name, value) in response.headers.iter() {
+            let overridden = name.eq_ignore_ascii_case("content-type") && response.content_type.is_some();
+            if is_framing_header(name) || overridden {
+                continue;
+            }
+            push_header(&mut head, name, value);
+        }
+        match (keep_alive, http10) {
+            _ if response.status == 101 => head.push_str("Connection: Upgrade\r\n"),
+            (false, _) => head.push_str("Connection: close\r\n"),
+            (true, true) => head.push_str("Connection: keep-alive\r\n"),
+            (true, false) => {}
+        }
+        head.push_str("\r\n");
+        let mut out = head.into_bytes();
+        if !head_request && streaming.is_none() && allows_content_length(response.status) {
+            out.extend_from_slice(&response.body);
+        }
+        self.write_timed(&out).await?;
+
+        let Some(mut body) = streaming.filter(|_| !head_request) else {
+            return Ok(());
+        };
+        let shutdown = self.shutdown.clone();
+        let grace = self.config.shutdown_grace_period();
+        let drained = async move {
+            shutdown.wait().await;
+            tokio::time::sleep(grace).await;
+        };
+        tokio::pin!(drained);
+        let mut remaining = sized;
+        loop {
+            let chunk = tokio::select! {
+                chunk = body.next_chunk() => chunk,
+                _ = &mut drained => {
+                    return Err(WriteError::Body(EmberError::msg("shutdown grace period ended the stream")));
+                }
+            };
+            match (chunk, remaining.as_mut()) {
+                (Some(Ok(chunk)), _) if chunk.is_empty() => {}
+                (Some(Ok(chunk)), Some(remaining)) => {
+                    *remaining = remaining
+                        .checked_sub(chunk.len() as u64)
+                        .ok_or_else(|| WriteError::Body(EmberError::msg("stream ran past its Content-Length")))?;
+                    self.write_timed(&chunk).await?;
+                }
+                (None, Some(0)) => return Ok(()),
+                (None, Some(_)) => {
+                    return Err(WriteError::Body(EmberError::msg("stream ended before its Content-Length")));
+                }
+                (Some(Ok(chunk)), None) if http10 => self.write_timed(&chunk).await?,
+                (Some(Ok(chunk)), None) => {
+                    let mut framed = format!("{:x}\r\n", chunk.len()).into_bytes();
+                    framed.extend_from_slice(&chunk);
+                    framed.extend_from_slice(b"\r\n");
+                    self.write_timed(&framed).await?;
+                }
+                (Some(Err(err)), _) => return Err(WriteError::Body(err)),
+                (None, None) if http10 => return Ok(()),
+                (None, None) => return self.write_timed(b"0\r\n\r\n").await,
+            }
+        }
+    }
+
+    /// Write and flush `bytes` within the write timeout.
+    async fn write_timed(&mut self, bytes: &[u8]) -> Result<(), WriteError> {
+        let write = async {
+            self.stream.write_all(bytes).await?;
+            self.stream.flush().await
+        };
+        match tokio::time::timeout(self.config.write_timeout(), write).await {
+            Ok(result) => result.map_err(WriteError::Io),
+            Err(_) => Err(WriteError::TimedOut),
+        }
+    }
+}
+
+/// Why a response could not be written in full.
+#[derive(Debug)]
+pub(crate) enum WriteError {
+    /// The peer stopped reading or the socket failed.
+    Io(std::io::Error),
+    /// A write did not finish within the write timeout.
+    TimedOut,
+    /// The streaming body reported an error part-way through.
+    Body(EmberError),
+}
+
+/// Serve requests on a connection until either side closes it.
+///
+/// Requests are answered strictly in the order they arrive, which keeps
+/// pipelined responses in sync with the requests that produced them. Once
+/// shutdown starts, the request in flight is answered with `Connection: close`.
+/// Requests that break a configured limit are answered with the matching
+/// status and the connection is closed. A `101 Switching Protocols` response
+/// carrying an upgrade hands the connection over once it is written.
+///
+/// Handlers find the [`ShutdownHandle`] and the `Arc<ServerConfig>` in the
+/// request extensions.
+pub(crate) async fn serve_connection<S, F, Fut>(
+    stream: S,
+    remote_addr: Option<SocketAddr>,
+    shutdown: ShutdownHandle,
+    config: Arc<ServerConfig>,
+    handle: F,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = HttpResponse>,
+{
+    let mut connection = Connection::new(stream, shutdown.clone(), Arc::clone(&config));
+    loop {
+        let (mut response, keep_alive, http10, head_request) = match connection.read_request().await {
+            Ok(Some(raw)) => {
+                let mut request = raw.request;
+                request.remote_addr = remote_addr;
+                request.extensions.insert(shutdown.clone());
+                request.extensions.insert(Arc::clone(&config));
+                request
+                    .extensions
+                    .insert(HttpVersion(if raw.http10 { "HTTP/1.0" } else { "HTTP/1.1" }));
+                let head_request = request.method == "HEAD";
+                let response = handle(request).await;
+                let keep_alive = raw.keep_alive
+                    && !wants_close(&response.headers)
+                    && (!raw.http10 || response.stream.as_ref().is_none_or(|stream| stream.content_length().is_some()))
+                    && !shutdown.is_shutdown();
+                (response, keep_alive, raw.http10, head_request)
+            }
+            Ok(None) => return,
+            Err(err) => {
+                warn!(
+                    status = err.status,
+                    error = %err,
+                    peer = ?remote_addr,
+                    "rejecting request"
+                );
+                (HttpResponse::problem(err.status, err.message), false, false, false)
+            }
+        };
+        let upgrade = if response.status == 101 {
+            response.upgrade.take()
+        } else {
+            None
+        };
+        let keep_alive = keep_alive && response.status != 101;
+        match connection.write_response(response, keep_alive, http10, head_request).await {
+            Ok(()) => {
+                if let Some(upgrade) = upgrade {
+                    let (stream, buffered) = connection.into_parts();
+                    upgrade.run(Upgraded::new(stream, buffered)).await;
+                    return;
+                }
+                if !keep_alive {
+                    return;
+                }
+            }
+            Err(WriteError::Io(err)) => {
+                debug!(error = %err, peer = ?remote_addr, "client went away while writing response");
+                return;
+            }
+            Err(WriteError::TimedOut) => {
+                warn!(peer = ?remote_addr, "timed out writing response");
+                return;
+            }
+            Err(WriteError::Body(err)) => {
+                warn!(error = %err, peer = ?remote_addr, "response stream failed");
+                return;
+            }
+        }
+    }
+}
+
+/// How the length of a request body is determined.
+enum BodyFraming {
+    /// Exactly this many bytes follow the headers.
+    Length(usize),
+    /// The body uses the chunked transfer coding.
+    Chunked,
+}
+
+/// Work out body framing from the `Content-Length` and `Transfer-Encoding` headers.
+///
+/// Anything ambiguous is rejected rather than guessed at, since a proxy in
+/// front of us may have guessed differently and that disagreement is what
+/// request smuggling relies on.
+fn body_framing(
+    content_lengths: &[String],
+    transfer_encodings: &[String],
+    http10: bool,
+) -> Result<BodyFraming, ReadError> {
+    if !transfer_encodings.is_empty() {
+        if !content_lengths.is_empty() {
+            return Err(ReadError::bad_request(
+                "both Transfer-Encoding and Content-Length are present",
+            ));
+        }
+        if http10 {
+            return Err(ReadError::bad_request("Transfer-Encoding is not allowed in HTTP/1.0"));
+        }
+        let codings: Vec<&str> = transfer_encodings
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|coding| !coding.is_empty())
+            .collect();
+        return match codings.as_slice() {
+            [coding] if coding.eq_ignore_ascii_case("chunked") => Ok(BodyFraming::Chunked),
+            _ => Err(ReadError::bad_request(format!(
+                "unsupported Transfer-Encoding: {}",
+                codings.join(", ")
+            ))),
+        };
+    }
+
+    let mut length = None;
+    for value in content_lengths.iter().flat_map(|value| value.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(ReadError::bad_request("invalid Content-Length"));
+        }
+        let parsed = value
+            .parse::<usize>()
+            .map_err(|_| ReadError::bad_request("invalid Content-Length"))?;
+        match length {
+            Some(existing) if existing != parsed => {
+                return Err(ReadError::bad_request("conflicting Content-Length values"));
+            }
+            _ => length = Some(parsed),
+        }
+    }
+    Ok(BodyFraming::Length(length.unwrap_or(0)))
+}
+
+/// Parse a `chunk-size [; chunk-ext]` line.
+fn parse_chunk_size(line: &str) -> Result<usize, ReadError> {
+    let size = line.split(';').next().unwrap_or_default().trim();
+    if size.is_empty() || size.len() > 16 || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(ReadError::bad_request("invalid chunk size"));
+    }
+    usize::from_str_radix(size, 16).map_err(|_| ReadError::bad_request("invalid chunk size"))
+}
+
+/// Split a request line into its method and target, and whether the version
+/// is HTTP/1.0.
+///
+/// Anything but `METHOD SP target SP HTTP/1.x` is refused with `400`, and a
+/// well-formed line naming another version with `505`.
+fn parse_request_line(line: &str) -> Result<(String, String, bool), ReadError> {
+    if line.contains(['\r', '\n']) {
+        return Err(ReadError::bad_request("bare line break in request line"));
+    }
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ReadError::bad_request("malformed request line"));
+    };
+    if method.is_empty() || target.is_empty() {
+        return Err(ReadError::bad_request("malformed request line"));
+    }
+    let http10 = match version {
+        "HTTP/1.1" => false,
+        "HTTP/1.0" => true,
+        _ => {
+            let numbered = version.strip_prefix("HTTP/").is_some_and(|number| {
+                matches!(number.as_bytes(), [major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit())
+            });
+            if numbered {
+                return Err(ReadError::new(505, format!("{version} is not supported")));
+            }
+            return Err(ReadError::bad_request("invalid HTTP version"));
+        }
+    };
+    Ok((method.to_string(), target.to_string(), http10))
+}
+
+/// Split a header line into a lower-cased name and trimmed value.
+fn parse_header_line(line: &str) -> Result<(String, String), ReadError> {
+    if line.contains(['\r', '\n']) {
+        return Err(ReadError::bad_request("bare line break in header"));
+    }
+    if line.starts_with([' ', '\t']) {
+        return Err(ReadError::bad_request("obsolete header line folding is not supported"));
+    }
+    let (name, value) = line
+        .split_once(':')
+        .ok_or_else(|| ReadError::bad_request("malformed header line"))?;
+    if name.is_empty() || name.bytes().any(|byte| byte.is_ascii_whitespace() || byte.is_ascii_control()) {
+        return Err(ReadError::bad_request("invalid header name"));
+    }
+    Ok((name.to_ascii_lowercase(), value.trim().to_string()))
+}
+
+/// Decide whether a connection persists after the current request.
+///
+/// HTTP/1.1 connections persist unless the client sends `Connection: close`;
+/// HTTP/1.0 connections close unless the client sends `Connection: keep-alive`.
+fn wants_keep_alive(connection: Option<&str>, http10: bool) -> bool {
+    let has_token = |token: &str| {
+        connection
+            .map(|value| value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    };
+    if http10 {
+        has_token("keep-alive")
+    } else {
+        !has_token("close")
+    }
+}
+
+/// Return `true` if a handler asked for the connection to be closed.
+fn wants_close(headers: &Headers) -> bool {
+    headers
+        .get_all("connection")
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("close"))
+}
+
+/// Responses with these statuses must not carry a body or `Content-Length`.
+fn allows_content_length(status: u16) -> bool {
+    !(100..200).contains(&status) && status != 204 && status != 304
+}
+
+/// Headers the server sets itself from the response framing.
+fn is_framing_header(name: &str) -> bool {
+    ["content-length", "transfer-encoding", "connection"]
+        .iter()
+        .any(|framing| name.eq_ignore_ascii_case(framing))
+}
+
+/// Append a header line, dropping values that would split the response.
+fn push_header(head: &mut String, name: &str, value: &str) {
+    if name.is_empty() || [name, value].iter().any(|part| part.contains(['\r', '\n'])) {
+        tracing::warn!(header = name, "dropping response header containing a line break");
+        return;
+    }
+    head.push_str(name);
+    head.push_str(": ");
+    head.push_str(value);
+    head.push_str("\r\n");
+}
+
+fn find_header_end(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use ember_ext_http::Middleware;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    /// Read one request from `raw`, sent by a client that then hangs up.
+    async fn read(raw: &[u8]) -> Result<RawRequest, ReadError> {
+        let (server, mut client) = tokio::io::duplex(64 * 1024);
+        client.write_all(raw).await.unwrap();
+        drop(client);
+        let mut connection = Connection::new(server, ShutdownHandle::new(), Arc::new(ServerConfig::default()));
+        connection.read_request().await.map(|raw| raw.expect("a request"))
+    }
+
+    async fn status(raw: &[u8]) -> u16 {
+        read(raw).await.err().map_or(0, |err| err.status)
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn framing_refuses_ambiguous_lengths() {
+        let framing = |lengths: &[&str], encodings: &[&str]| body_framing(&strings(lengths), &strings(encodings), false);
+        assert!(framing(&["5"], &["chunked"]).is_err());
+        assert!(framing(&["5", "6"], &[]).is_err());
+        assert!(framing(&["5, 6"], &[]).is_err());
+        assert!(framing(&["+5"], &[]).is_err());
+        assert!(framing(&[""], &[]).is_err());
+        assert!(framing(&[], &["gzip, chunked"]).is_err());
+        assert!(body_framing(&[], &strings(&["chunked"]), true).is_err());
+        assert!(matches!(framing(&["5", "5"], &[]), Ok(BodyFraming::Length(5))));
+        assert!(matches!(framing(&["5, 5"], &[]), Ok(BodyFraming::Length(5))));
+        assert!(matches!(framing(&[], &["Chunked"]), Ok(BodyFraming::Chunked)));
+        assert!(matches!(framing(&[], &[]), Ok(BodyFraming::Length(0))));
+    }
+
+    #[test]
+    fn chunk_sizes_are_bounded_hex() {
+        assert_eq!(parse_chunk_size("1a").unwrap(), 26);
+        assert_eq!(parse_chunk_size("A; name=value").unwrap(), 10);
+        assert!(parse_chunk_size("").is_err());
+        assert!(parse_chunk_size("-1").is_err());
+        assert!(parse_chunk_size("0x10").is_err());
+        assert!(parse_chunk_size("10000000000000000").is_err());
+    }
+
+    #[test]
+    fn request_lines_need_a_supported_version() {
+        let (method, target, http10) = parse_request_line("GET /books?q=1 HTTP/1.0").unwrap();
+        assert_eq!((method.as_str(), target.as_str(), http10), ("GET", "/books?q=1", true));
+        assert!(!parse_request_line("GET / HTTP/1.1").unwrap().2);
+        assert_eq!(parse_request_line("GET /").unwrap_err().status, 400);
+        assert_eq!(parse_request_line("GET / HTTP/2.0").unwrap_err().status, 505);
+        assert_eq!(parse_request_line("GET / http/1.1").unwrap_err().status, 400);
+        assert_eq!(parse_request_line("GET  / HTTP/1.1").unwrap_err().status, 400);
+        assert_eq!(parse_request_line("GET / HTTP/1.1 extra").unwrap_err().status, 400);
+    }
+
+    #[tokio::test]
+    async fn reads_chunked_bodies_with_trailers() {
+        let request = read(
+            b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\n\
+              X-Checksum: abc\r\nContent-Type: text/evil\r\n\r\n",
+        )
+        .await
+        .unwrap()
+        .request;
+        assert_eq!(request.body, b"hello world");
+        assert_eq!(request.trailer("x-checksum"), Some("abc"));
+        assert_eq!(request.header("x-checksum"), None);
+        assert_eq!(request.header("content-type"), None);
+    }
+
+    /// Accepts any request that carries an `Authorization` header.
+    struct AnyToken;
+
+    impl ember_ext_auth::SecurityFilter for AnyToken {
+        fn filter(
+            &self,
+            request: &ember_ext_auth::SecurityRequest,
+        ) -> Result<ember_ext_auth::SecurityContext, EmberError> {
+            match &request.authorization {
+                Some(_) => Ok(ember_ext_auth::SecurityContext::new("client")),
+                None => Err(EmberError::msg("missing credentials")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn trailers_cannot_add_credentials() {
+        let mut request = read(
+            b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n\
+              0\r\nX-Api-Key: stolen\r\nAuthorization: Bearer stolen\r\nX-Forwarded-For: 127.0.0.1\r\n\r\n",
+        )
+        .await
+        .unwrap()
+        .request;
+        assert_eq!(request.header("x-api-key"), None);
+        assert_eq!(request.headers.get_all("x-forwarded-for").collect::<Vec<_>>(), ["10.0.0.1"]);
+        assert_eq!(request.trailer("x-api-key"), Some("stolen"));
+
+        let security = crate::SecurityMiddleware::new(AnyToken);
+        let refused = security.before(&mut request).await.unwrap().expect("refused");
+        assert_eq!(refused.status, 401);
+    }
+
+    #[tokio::test]
+    async fn rejects_smuggling_shapes() {
+        let te_and_cl = b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert_eq!(status(te_and_cl).await, 400);
+        let conflicting = b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!";
+        assert_eq!(status(conflicting).await, 400);
+        let huge_chunk = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n";
+        assert_eq!(status(huge_chunk).await, 413);
+        let long_chunk = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n00000000000000005\r\nhello\r\n0\r\n\r\n";
+        assert_eq!(status(long_chunk).await, 400);
+        let bare_lf = b"GET / HTTP/1.1\r\nHost: a\nX-Injected: 1\r\n\r\n";
+        assert_eq!(status(bare_lf).await, 400);
+        let bare_lf_trailer = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nA: b\nC: d\r\n\r\n";
+        assert_eq!(status(bare_lf_trailer).await, 400);
+    }
+
+    /// A stream yielding `chunks` in order.
+    struct Chunks(std::collections::VecDeque<&'static [u8]>);
+
+    impl futures_core::Stream for Chunks {
+        type Item = Result<Vec<u8>, EmberError>;
+
+        fn poll_next(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Self::Item>> {
+            std::task::Poll::Ready(self.get_mut().0.pop_front().map(|chunk| Ok(chunk.to_vec())))
+        }
+    }
+
+    /// Write `stream` as a response body and return what the client received.
+    async fn write_stream(stream: BodyStream) -> (Result<(), WriteError>, String) {
+        write_stream_with(stream, ShutdownHandle::new(), ServerConfig::default()).await
+    }
+
+    async fn write_stream_with(
+        stream: BodyStream,
+        shutdown: ShutdownHandle,
+        config: ServerConfig,
+    ) -> (Result<(), WriteError>, String) {
+        let (server, mut client) = tokio::io::duplex(64 * 1024);
+        let mut connection = Connection::new(server, shutdown, Arc::new(config));
+        let mut response = HttpResponse::empty(200);
+        response.stream = Some(stream);
+        let result = connection.write_response(response, true, false, false).await;
+        drop(connection);
+        let mut out = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut client, &mut out).await.unwrap();
+        (result, out)
+    }
+
+    #[tokio::test]
+    async fn sized_streams_are_sent_with_content_length() {
+        let sized = BodyStream::new(Chunks([&b"hello"[..], b" world"].into())).with_content_length(11);
+        let (result, out) = write_stream(sized).await;
+        assert!(result.is_ok());
+        assert!(out.contains("Content-Length: 11\r\n"));
+        assert!(!out.contains("Transfer-Encoding"));
+        assert!(out.ends_with("\r\n\r\nhello world"));
+
+        let unsized_stream = BodyStream::new(Chunks([&b"hello"[..]].into()));
+        let (result, out) = write_stream(unsized_stream).await;
+        assert!(result.is_ok());
+        assert!(out.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(out.ends_with("5\r\nhello\r\n0\r\n\r\n"));
+
+        let short = BodyStream::new(Chunks([&b"hello"[..]].into())).with_content_length(11);
+        assert!(matches!(write_stream(short).await.0, Err(WriteError::Body(_))));
+        let long = BodyStream::new(Chunks([&b"hello world!"[..]].into())).with_content_length(11);
+        assert!(matches!(write_stream(long).await.0, Err(WriteError::Body(_))));
+    }
+
+    /// A stream yielding `first`, then nothing until it is dropped.
+    fn stalled(first: &'static [u8]) -> BodyStream {
+        BodyStream::new(Stalled(Chunks([first].into())))
+    }
+
+    struct Stalled(Chunks);
+
+    impl futures_core::Stream for Stalled {
+        type Item = Result<Vec<u8>, EmberError>;
+
+        fn poll_next(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Self::Item>> {
+            match std::pin::Pin::new(&mut self.get_mut().0).poll_next(cx) {
+                std::task::Poll::Ready(Some(chunk)) => std::task::Poll::Ready(Some(chunk)),
+                _ => std::task::Poll::Pending,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_truncates_streams_without_a_terminator() {
+        let shutdown = ShutdownHandle::new();
+        shutdown.shutdown();
+        let config = ServerConfig {
+            shutdown_grace_period_seconds: 0,
+            ..ServerConfig::default()
+        };
+        let (result, out) = write_stream_with(stalled(b"hello"), shutdown, config).await;
+        assert!(matches!(result, Err(WriteError::Body(_))));
+        assert!(out.ends_with("5\r\nhello\r\n"));
+        assert!(!out.contains("0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_streams_finish_within_the_grace_period() {
+        let shutdown = ShutdownHandle::new();
+        shutdown.shutdown();
+        let stream = BodyStream::new(Chunks([&b"hello"[..], b" world"].into()));
+        let (result, out) = write_stream_with(stream, shutdown, ServerConfig::default()).await;
+        assert!(result.is_ok());
+        assert!(out.ends_with("5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_without_a_supported_version() {
+        assert_eq!(status(b"GET /\r\n\r\n").await, 400);
+        assert_eq!(status(b"GET / HTTP/3.0\r\n\r\n").await, 505);
+        assert!(read(b"GET / HTTP/1.0\r\n\r\n").await.unwrap().http10);
+    }
+}
@@ -0,0 +1,501 @@
+#![forbid(unsafe_code)]
+
+//! Built-in HTTP server settings.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ember_ext_exceptions::EmberError;
+use serde::{Deserialize, Serialize};
+
+/// Settings for the built-in HTTP server, usually read from `ember.server`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Endpoints to listen on, replacing the service's single listen address
+    /// when non-empty: `host:port` (every resolved address, so IPv4 and IPv6),
+    /// `unix:/path/to.sock`, or `systemd` for socket-activated descriptors.
+    pub listen: Vec<String>,
+    /// Octal permissions for Unix socket files, e.g. `"660"`; the umask applies when unset.
+    pub unix_socket_mode: Option<String>,
+    /// Seconds to let in-flight connections finish after a shutdown signal.
+    pub shutdown_grace_period_seconds: u64,
+    /// Largest request line plus headers accepted; larger requests get 431.
+    pub max_header_bytes: usize,
+    /// Largest request body accepted; larger bodies get 413.
+    pub max_body_bytes: usize,
+    /// Seconds allowed to receive the request line and headers; slower clients get 408.
+    pub header_read_timeout_seconds: u64,
+    /// Seconds allowed to receive the request body; slower clients get 408.
+    pub body_read_timeout_seconds: u64,
+    /// Seconds allowed to write a response before the connection is dropped.
+    pub write_timeout_seconds: u64,
+    /// Seconds a kept-alive connection may sit idle between requests.
+    pub idle_timeout_seconds: u64,
+    /// Seconds an upgraded connection, such as a WebSocket, may go without
+    /// receiving anything before it is closed.
+    pub upgrade_idle_timeout_seconds: u64,
+    /// Serve HTTPS instead of plain HTTP; requires the `tls` feature.
+    pub tls: Option<TlsConfig>,
+    /// Response compression and request decompression.
+    pub compression: CompressionConfig,
+    /// Cross-origin resource sharing; disabled when absent.
+    pub cors: Option<CorsConfig>,
+    /// Limits for `multipart/form-data` request bodies.
+    pub multipart: MultipartConfig,
+    /// Per-request access logging.
+    pub access_log: AccessLogConfig,
+    /// Request id assignment and propagation.
+    pub request_id: RequestIdConfig,
+    /// Per-client request rate limits; disabled when absent.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Connection and in-flight request limits with load shedding.
+    pub concurrency: ConcurrencyConfig,
+    /// Strong `ETag`s and conditional requests for every `GET` route.
+    pub etag: EtagConfig,
+}
+
+impl ServerConfig {
+    /// Grace period for draining connections on shutdown.
+    pub fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_period_seconds)
+    }
+
+    /// Time allowed to read the request head.
+    pub fn header_read_timeout(&self) -> Duration {
+        Duration::from_secs(self.header_read_timeout_seconds)
+    }
+
+    /// Time allowed to read the request body.
+    pub fn body_read_timeout(&self) -> Duration {
+        Duration::from_secs(self.body_read_timeout_seconds)
+    }
+
+    /// Time allowed to write a response.
+    pub fn write_timeout(&self) -> Duration {
+        Duration::from_secs(self.write_timeout_seconds)
+    }
+
+    /// Time an idle keep-alive connection stays open.
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_seconds)
+    }
+
+    /// Time an upgraded connection stays open without receiving anything.
+    pub fn upgrade_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.upgrade_idle_timeout_seconds)
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: Vec::new(),
+            unix_socket_mode: None,
+            shutdown_grace_period_seconds: 30,
+            max_header_bytes: 64 * 1024,
+            max_body_bytes: 2 * 1024 * 1024,
+            header_read_timeout_seconds: 10,
+            body_read_timeout_seconds: 30,
+            write_timeout_seconds: 30,
+            idle_timeout_seconds: 60,
+            upgrade_idle_timeout_seconds: 300,
+            tls: None,
+            compression: CompressionConfig::default(),
+            cors: None,
+            multipart: MultipartConfig::default(),
+            access_log: AccessLogConfig::default(),
+            request_id: RequestIdConfig::default(),
+            rate_limit: None,
+            concurrency: ConcurrencyConfig::default(),
+            etag: EtagConfig::default(),
+        }
+    }
+}
+
+/// HTTPS settings, read from `ember.server.tls`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM file holding the certificate chain, leaf first.
+    pub cert_path: PathBuf,
+    /// PEM file holding the private key (PKCS#8, PKCS#1 or SEC1).
+    pub key_path: PathBuf,
+    /// Seconds between checks for changed certificate files; `0` disables reloading.
+    pub reload_interval_seconds: u64,
+    /// Optional plain HTTP address that redirects every request to HTTPS.
+    pub redirect_listen: Option<String>,
+}
+
+impl TlsConfig {
+    /// Interval between certificate reload checks, if reloading is enabled.
+    pub fn reload_interval(&self) -> Option<Duration> {
+        (self.reload_interval_seconds > 0).then(|| Duration::from_secs(self.reload_interval_seconds))
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert_path: PathBuf::new(),
+            key_path: PathBuf::new(),
+            reload_interval_seconds: 10,
+            redirect_listen: None,
+        }
+    }
+}
+
+/// Compression settings, read from `ember.server.compression`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// Compress responses for clients that send `Accept-Encoding`.
+    pub enabled: bool,
+    /// Responses with smaller bodies are sent as-is.
+    pub min_size_bytes: usize,
+    /// Content types eligible for compression; an entry ending in `/` matches
+    /// every subtype, e.g. `text/`.
+    pub content_types: Vec<String>,
+    /// Codings to offer, most preferred first: any of `br`, `gzip`, `deflate`.
+    pub encodings: Vec<String>,
+    /// Decode `Content-Encoding: gzip` and `deflate` request bodies.
+    pub decompress_requests: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_size_bytes: 1024,
+            content_types: vec![
+                "text/".to_string(),
+                "application/json".to_string(),
+                "application/problem+json".to_string(),
+                "application/javascript".to_string(),
+                "application/xml".to_string(),
+                "image/svg+xml".to_string(),
+            ],
+            encodings: vec!["br".to_string(), "gzip".to_string(), "deflate".to_string()],
+            decompress_requests: true,
+        }
+    }
+}
+
+/// CORS settings, read from `ember.server.cors`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins allowed to call the service, e.g. `https://app.example.com`.
+    /// `*` matches any origin and may also be used inside a pattern such as
+    /// `https://*.example.com`.
+    pub allowed_origins: Vec<String>,
+    /// Methods allowed cross-origin; empty allows every method a route declares.
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed cross-origin; `*` allows whatever the client asks for.
+    pub allowed_headers: Vec<String>,
+    /// Response headers exposed to browser scripts.
+    pub exposed_headers: Vec<String>,
+    /// Allow cookies and `Authorization` on cross-origin requests. Cannot be
+    /// combined with a `*` origin.
+    pub allow_credentials: bool,
+    /// Seconds a browser may cache a preflight response.
+    pub max_age_seconds: Option<u64>,
+}
+
+impl CorsConfig {
+    /// Refuse settings that would let any site make credentialed requests:
+    /// `allow_credentials` with an origin pattern of only `*`.
+    pub fn validate(&self) -> Result<(), EmberError> {
+        let any_origin = self
+            .allowed_origins
+            .iter()
+            .any(|pattern| !pattern.is_empty() && pattern.chars().all(|ch| ch == '*'));
+        if any_origin && self.allow_credentials {
+            return Err(EmberError::msg(
+                "ember.server.cors: allow_credentials cannot be combined with the `*` origin; list the allowed origins",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: Vec::new(),
+            allowed_headers: vec!["*".to_string()],
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age_seconds: Some(600),
+        }
+    }
+}
+
+/// Multipart upload limits, read from `ember.server.multipart`.
+///
+/// The whole request body is still bounded by `max_body_bytes`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MultipartConfig {
+    /// Most parts accepted in one request; more get 413.
+    pub max_parts: usize,
+    /// Largest single part accepted; larger parts get 413.
+    pub max_part_bytes: usize,
+    /// File parts larger than this are written to a temporary file instead of kept in memory.
+    pub memory_threshold_bytes: usize,
+    /// Directory for temporary files; the system temp directory when absent.
+    pub temp_dir: Option<PathBuf>,
+}
+
+impl Default for MultipartConfig {
+    fn default() -> Self {
+        Self {
+            max_parts: 100,
+            max_part_bytes: 1024 * 1024,
+            memory_threshold_bytes: 64 * 1024,
+            temp_dir: None,
+        }
+    }
+}
+
+/// Access log settings, read from `ember.server.access_log`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    /// Log one line per request through `tracing` under the `ember::access` target.
+    pub enabled: bool,
+    /// Line layout: `structured` fields, or Apache `common` / `combined` text.
+    pub format: AccessLogFormat,
+    /// Fraction of requests to log, from `0.0` to `1.0`; `5xx` responses are always logged.
+    pub sample_rate: f64,
+    /// Paths that are never logged; an entry ending in `*` matches every path with that prefix.
+    pub exclude_paths: Vec<String>,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            format: AccessLogFormat::Structured,
+            sample_rate: 1.0,
+            exclude_paths: Vec::new(),
+        }
+    }
+}
+
+/// Layout of access log lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Separate `tracing` fields, suited to JSON log output.
+    #[default]
+    Structured,
+    /// NCSA Common Log Format.
+    Common,
+    /// Combined Log Format: Common plus referer and user agent.
+    Combined,
+}
+
+/// Request id settings, read from `ember.server.request_id`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestIdConfig {
+    /// Give every request an id, echoed in the response and recorded on its span.
+    pub enabled: bool,
+    /// Header the id is read from and echoed in.
+    pub header: String,
+    /// Reuse a well-formed id sent by the client instead of generating one.
+    pub trust_incoming: bool,
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            header: "X-Request-Id".to_string(),
+            trust_incoming: true,
+        }
+    }
+}
+
+/// ETag settings, read from `ember.server.etag`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EtagConfig {
+    /// Tag every `GET` route, not just those declared with `etag`.
+    pub enabled: bool,
+}
+
+/// Rate limiting settings, read from `ember.server.rate_limit`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Algorithm used for routes without their own.
+    pub algorithm: RateLimitAlgorithm,
+    /// What identifies a client for routes without their own key.
+    pub key: RateLimitKey,
+    /// Header holding the client's API key when `key` is `api_key`.
+    pub api_key_header: String,
+    /// Requests allowed per window for routes without a rule; `0` leaves them unlimited.
+    pub limit: u64,
+    /// Length of the window in seconds.
+    pub window_seconds: u64,
+    /// Per-route overrides; the first matching rule wins.
+    pub routes: Vec<RouteRateLimit>,
+}
+
+impl RateLimitConfig {
+    /// Length of the default window.
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_seconds)
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: RateLimitAlgorithm::TokenBucket,
+            key: RateLimitKey::Ip,
+            api_key_header: "X-Api-Key".to_string(),
+            limit: 100,
+            window_seconds: 60,
+            routes: Vec::new(),
+        }
+    }
+}
+
+/// A rate limit for one route, inside `ember.server.rate_limit.routes`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RouteRateLimit {
+    /// Route template such as `/books/{id}`, or a concrete path.
+    pub path: String,
+    /// Method the rule applies to; every method when absent.
+    pub method: Option<String>,
+    /// Requests allowed per window; `0` exempts the route.
+    pub limit: u64,
+    /// Length of the window in seconds.
+    pub window_seconds: u64,
+    /// Overrides the default algorithm.
+    pub algorithm: Option<RateLimitAlgorithm>,
+    /// Overrides the default key.
+    pub key: Option<RateLimitKey>,
+}
+
+impl RouteRateLimit {
+    /// Length of this rule's window.
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_seconds)
+    }
+}
+
+impl Default for RouteRateLimit {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            method: None,
+            limit: 0,
+            window_seconds: 60,
+            algorithm: None,
+            key: None,
+        }
+    }
+}
+
+/// How requests are counted against a limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAlgorithm {
+    /// A bucket of `limit` tokens refilled evenly over the window; allows short bursts.
+    #[default]
+    TokenBucket,
+    /// Requests in the last window, weighting the previous window by how much of it still overlaps.
+    SlidingWindow,
+}
+
+/// What a rate limit is counted per.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// The client's IP address.
+    #[default]
+    Ip,
+    /// The authenticated `SecurityContext` subject, or the IP for anonymous requests.
+    Subject,
+    /// The API key header, or the IP when it is missing.
+    ApiKey,
+    /// The route itself, shared by every client.
+    Route,
+}
+
+/// Concurrency limits, read from `ember.server.concurrency`.
+///
+/// Connections and requests over a limit wait up to `queue_timeout_ms` for a
+/// slot and are then shed with `503 Service Unavailable` and `Retry-After`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConcurrencyConfig {
+    /// Most connections served at once; unlimited when unset.
+    pub max_connections: Option<usize>,
+    /// Most requests handled at once; unlimited when unset unless `adaptive` is set.
+    pub max_in_flight_requests: Option<usize>,
+    /// Milliseconds a connection or request may wait for a slot.
+    pub queue_timeout_ms: u64,
+    /// Seconds sent in `Retry-After` when load is shed.
+    pub retry_after_seconds: u64,
+    /// Adjust the in-flight limit from observed latency, up to `max_in_flight_requests`.
+    pub adaptive: Option<AdaptiveConcurrencyConfig>,
+}
+
+impl ConcurrencyConfig {
+    /// Time a connection or request may wait for a slot.
+    pub fn queue_timeout(&self) -> Duration {
+        Duration::from_millis(self.queue_timeout_ms)
+    }
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            max_in_flight_requests: None,
+            queue_timeout_ms: 500,
+            retry_after_seconds: 1,
+            adaptive: None,
+        }
+    }
+}
+
+/// Latency-based in-flight limit, read from `ember.server.concurrency.adaptive`.
+///
+/// The limit grows by about one for every `limit` requests that finish within
+/// `target_latency_ms` while the server is busy, and shrinks by a tenth, at
+/// most once per target latency, when requests take longer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdaptiveConcurrencyConfig {
+    /// The limit never drops below this.
+    pub min_limit: usize,
+    /// The limit to start from.
+    pub initial_limit: usize,
+    /// Handler latency, in milliseconds, above which the limit shrinks.
+    pub target_latency_ms: u64,
+}
+
+impl AdaptiveConcurrencyConfig {
+    /// Latency above which the limit shrinks.
+    pub fn target_latency(&self) -> Duration {
+        Duration::from_millis(self.target_latency_ms)
+    }
+}
+
+impl Default for AdaptiveConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            min_limit: 4,
+            initial_limit: 20,
+            target_latency_ms: 100,
+        }
+    }
+}
@@ -0,0 +1,133 @@
+#![forbid(unsafe_code)]
+
+//! Graceful shutdown coordination.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+
+type ShutdownHook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+/// A cloneable handle that stops a running Ember service.
+///
+/// The runtime triggers it on SIGTERM/SIGINT; tests and embedding code can
+/// trigger it directly with [`ShutdownHandle::shutdown`].
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownState>,
+}
+
+struct ShutdownState {
+    signal: watch::Sender<bool>,
+    hooks: Mutex<Vec<ShutdownHook>>,
+}
+
+impl ShutdownHandle {
+    /// Create a handle that has not been triggered yet.
+    pub fn new() -> Self {
+        let (signal, _) = watch::channel(false);
+        Self {
+            inner: Arc::new(ShutdownState {
+                signal,
+                hooks: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Ask the service to stop accepting connections and drain.
+    pub fn shutdown(&self) {
+        self.inner.signal.send_replace(true);
+    }
+
+    /// Return `true` once shutdown has been requested.
+    pub fn is_shutdown(&self) -> bool {
+        *self.inner.signal.borrow()
+    }
+
+    /// Wait until shutdown is requested.
+    pub async fn wait(&self) {
+        let mut receiver = self.inner.signal.subscribe();
+        let _ = receiver.wait_for(|requested| *requested).await;
+    }
+
+    /// Register a hook that runs after connections have drained.
+    ///
+    /// Hooks run once, in registration order.
+    pub fn on_shutdown<F, Fut>(&self, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut hooks = self.inner.hooks.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        hooks.push(Box::new(move || Box::pin(hook())));
+    }
+
+    /// Run and clear the registered hooks.
+    pub(crate) async fn run_hooks(&self) {
+        let hooks = {
+            let mut hooks = self.inner.hooks.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            std::mem::take(&mut *hooks)
+        };
+        for hook in hooks {
+            hook().await;
+        }
+    }
+
+    /// Trigger shutdown when the process receives SIGTERM or SIGINT.
+    pub(crate) fn listen_for_signals(&self) {
+        let handle = self.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            tracing::info!("shutdown signal received");
+            handle.shutdown();
+        });
+    }
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ShutdownHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShutdownHandle")
+            .field("shutdown", &self.is_shutdown())
+            .finish()
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = ctrl_c() => {}
+            }
+        }
+        Err(err) => {
+            tracing::warn!(error = %err, "failed to install SIGTERM handler");
+            ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    ctrl_c().await;
+}
+
+/// Wait for Ctrl-C, or forever if the handler cannot be installed.
+async fn ctrl_c() {
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        tracing::warn!(error = %err, "failed to install Ctrl-C handler");
+        std::future::pending::<()>().await;
+    }
+}
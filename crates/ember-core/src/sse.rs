@@ -0,0 +1,253 @@
+#![forbid(unsafe_code)]
+
+//! Server-Sent Events.
+
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use ember_ext_exceptions::EmberError;
+use ember_ext_http::{BodyStream, HttpResponse, IntoResponse, Request};
+use futures_core::Stream;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+/// Heartbeat interval used unless [`Sse::with_heartbeat`] changes it.
+const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// A single server-sent event.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+impl SseEvent {
+    /// An event carrying `data`; multi-line data is split across `data:` fields.
+    pub fn data(data: impl Into<String>) -> Self {
+        Self {
+            data: data.into(),
+            ..Self::default()
+        }
+    }
+
+    /// An event whose data is `value` encoded as JSON.
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> Result<Self, EmberError> {
+        serde_json::to_string(value)
+            .map(Self::data)
+            .map_err(|err| EmberError::msg(format!("encode failed: {err}")))
+    }
+
+    /// A comment line, ignored by clients but useful to keep proxies from timing out.
+    pub fn comment(text: impl Into<String>) -> Self {
+        Self {
+            comment: Some(text.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Set the event id, which the client sends back as `Last-Event-ID` on reconnect.
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Set the event type; clients dispatch it to listeners for that name.
+    pub fn with_event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// Tell the client how long to wait before reconnecting.
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Encode the event in the `text/event-stream` format.
+    fn encode(&self) -> Vec<u8> {
+        let mut out = String::new();
+        if let Some(comment) = &self.comment {
+            for line in split_lines(comment) {
+                out.push_str(&format!(": {line}\n"));
+            }
+        }
+        if let Some(event) = &self.event {
+            out.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id {
+            out.push_str(&format!("id: {}\n", single_line(id).replace('\0', "")));
+        }
+        if let Some(retry) = self.retry {
+            out.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        let bare = self.comment.is_none() && self.event.is_none() && self.id.is_none() && self.retry.is_none();
+        if !self.data.is_empty() || bare {
+            for line in split_lines(&self.data) {
+                out.push_str(&format!("data: {line}\n"));
+            }
+        }
+        out.push('\n');
+        out.into_bytes()
+    }
+}
+
+/// Split on any of the line endings the event-stream format recognises.
+fn split_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.split("\r\n").flat_map(|line| line.split(['\r', '\n']))
+}
+
+/// Strip line breaks from a field that must fit on one line.
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
+
+/// A `text/event-stream` response that controllers can return from `#[get]` routes.
+///
+/// Events are written as soon as the stream yields them. When no event has
+/// been sent for the heartbeat interval (15 seconds by default) a comment line
+/// is sent instead, so idle connections survive proxies and a disconnected
+/// client is noticed on the next write.
+pub struct Sse {
+    events: Pin<Box<dyn Stream<Item = SseEvent> + Send>>,
+    heartbeat: Duration,
+    retry: Option<Duration>,
+}
+
+impl Sse {
+    /// Stream the events produced by `events`.
+    pub fn new<S>(events: S) -> Self
+    where
+        S: Stream<Item = SseEvent> + Send + 'static,
+    {
+        Self {
+            events: Box::pin(events),
+            heartbeat: DEFAULT_HEARTBEAT,
+            retry: None,
+        }
+    }
+
+    /// Create a stream fed through the returned sender, buffering up to `capacity` events.
+    ///
+    /// The stream ends when every sender is dropped; sending fails once the
+    /// client has gone away.
+    pub fn channel(capacity: usize) -> (SseSender, Self) {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        (SseSender { sender }, Self::new(ChannelEvents { receiver }))
+    }
+
+    /// Send a comment after this long without events; `Duration::ZERO` disables heartbeats.
+    pub fn with_heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = interval;
+        self
+    }
+
+    /// Send a `retry:` hint before the first event.
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+impl fmt::Debug for Sse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sse")
+            .field("heartbeat", &self.heartbeat)
+            .field("retry", &self.retry)
+            .finish_non_exhaustive()
+    }
+}
+
+impl IntoResponse for Sse {
+    fn into_response(self, _request: &Request, _status: u16) -> Result<HttpResponse, EmberError> {
+        let stream = EventStream {
+            preamble: self.retry.map(|retry| SseEvent::default().with_retry(retry).encode()),
+            events: self.events,
+            heartbeat: (!self.heartbeat.is_zero()).then_some(self.heartbeat),
+            ticker: None,
+        };
+        Ok(HttpResponse::streaming(200, "text/event-stream", BodyStream::new(stream))
+            .with_header("Cache-Control", "no-cache")
+            .with_header("X-Accel-Buffering", "no"))
+    }
+}
+
+/// Sends events into an [`Sse`] created with [`Sse::channel`].
+#[derive(Debug, Clone)]
+pub struct SseSender {
+    sender: mpsc::Sender<SseEvent>,
+}
+
+impl SseSender {
+    /// Queue an event, waiting if the buffer is full.
+    pub async fn send(&self, event: SseEvent) -> Result<(), EmberError> {
+        self.sender
+            .send(event)
+            .await
+            .map_err(|_| EmberError::msg("event stream closed"))
+    }
+
+    /// Return `true` once the client has disconnected or the response was dropped.
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+/// Events received from an [`SseSender`].
+struct ChannelEvents {
+    receiver: mpsc::Receiver<SseEvent>,
+}
+
+impl Stream for ChannelEvents {
+    type Item = SseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<SseEvent>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+/// Encodes events and interleaves heartbeats.
+struct EventStream {
+    preamble: Option<Vec<u8>>,
+    events: Pin<Box<dyn Stream<Item = SseEvent> + Send>>,
+    heartbeat: Option<Duration>,
+    ticker: Option<Interval>,
+}
+
+impl Stream for EventStream {
+    type Item = Result<Vec<u8>, EmberError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(preamble) = this.preamble.take() {
+            return Poll::Ready(Some(Ok(preamble)));
+        }
+        match this.events.as_mut().poll_next(cx) {
+            Poll::Ready(Some(event)) => {
+                if let Some(ticker) = &mut this.ticker {
+                    ticker.reset();
+                }
+                return Poll::Ready(Some(Ok(event.encode())));
+            }
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => {}
+        }
+        let Some(period) = this.heartbeat else {
+            return Poll::Pending;
+        };
+        let ticker = this.ticker.get_or_insert_with(|| {
+            let mut ticker = tokio::time::interval_at(Instant::now() + period, period);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticker
+        });
+        match ticker.poll_tick(cx) {
+            Poll::Ready(_) => Poll::Ready(Some(Ok(b": keep-alive\n\n".to_vec()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,556 @@
+#![forbid(unsafe_code)]
+
+//! Static file serving from a directory or from embedded assets.
+
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ember_ext_exceptions::EmberError;
+use ember_ext_http::{BodyStream, HttpResponse, Middleware, MiddlewareFuture, Request};
+use futures_core::Stream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, ReadBuf};
+
+/// Files up to this size are read in one go; larger ones are streamed.
+const BUFFERED_FILE_BYTES: u64 = 64 * 1024;
+
+/// Bytes read from a file per streamed chunk.
+const FILE_CHUNK_BYTES: usize = 64 * 1024;
+
+/// A file compiled into the binary, usually with `include_bytes!`.
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddedFile {
+    /// Path relative to the mount prefix, e.g. `index.html` or `assets/app.js`.
+    pub path: &'static str,
+    /// File contents.
+    pub contents: &'static [u8],
+}
+
+/// Where a [`StaticFiles`] mount reads from.
+#[derive(Debug, Clone)]
+enum Source {
+    Directory(PathBuf),
+    Embedded(Vec<(EmbeddedFile, String)>),
+}
+
+/// Serves files under a URL prefix.
+///
+/// `GET` and `HEAD` requests under the prefix are answered directly with
+/// `ETag`, `Last-Modified` (for directory files), conditional `304`s and single
+/// byte ranges; other requests continue down the middleware chain. Files over
+/// 64 KiB are streamed from disk rather than read into memory. Paths
+/// containing `..`, backslashes or hidden segments are refused, and files
+/// reached through symlinks must still resolve inside the root directory.
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    prefix: String,
+    source: Source,
+    index_fallback: bool,
+    cache_control: Option<String>,
+}
+
+impl StaticFiles {
+    /// Serve files from `root` at `prefix`, e.g. `StaticFiles::dir("/docs", "./public")`.
+    pub fn dir(prefix: impl Into<String>, root: impl Into<PathBuf>) -> Self {
+        Self::new(prefix.into(), Source::Directory(root.into()))
+    }
+
+    /// Serve files compiled into the binary at `prefix`.
+    pub fn embedded(prefix: impl Into<String>, files: &'static [EmbeddedFile]) -> Self {
+        let files = files
+            .iter()
+            .map(|file| (*file, format!("\"{:016x}\"", fnv1a(file.contents))))
+            .collect();
+        Self::new(prefix.into(), Source::Embedded(files))
+    }
+
+    fn new(prefix: String, source: Source) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            source,
+            index_fallback: false,
+            cache_control: None,
+        }
+    }
+
+    /// Serve `index.html` for unknown paths without a file extension, so a
+    /// single-page app can handle its own client-side routes.
+    pub fn with_index_fallback(mut self, enabled: bool) -> Self {
+        self.index_fallback = enabled;
+        self
+    }
+
+    /// Send this `Cache-Control` value with every file.
+    pub fn with_cache_control(mut self, value: impl Into<String>) -> Self {
+        self.cache_control = Some(value.into());
+        self
+    }
+
+    /// The part of `path` below the prefix, or `None` if it is outside the mount.
+    fn relative<'p>(&self, path: &'p str) -> Option<&'p str> {
+        let rest = path.strip_prefix(self.prefix.as_str())?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest.trim_start_matches('/'))
+        } else {
+            None
+        }
+    }
+
+    async fn serve(&self, request: &Request, relative: &str) -> Result<HttpResponse, EmberError> {
+        let Some(segments) = safe_segments(relative) else {
+            return Ok(HttpResponse::problem(404, format!("no file at {}", request.path)));
+        };
+        let mut asset = self.find(&segments).await?;
+        let wants_page = segments.last().is_none_or(|last| !last.contains('.'));
+        if asset.is_none() && self.index_fallback && wants_page {
+            asset = self.find(&["index.html"]).await?;
+        }
+        match asset {
+            Some(asset) => self.respond(request, asset).await,
+            None => Ok(HttpResponse::problem(404, format!("no file at {}", request.path))),
+        }
+    }
+
+    async fn find(&self, segments: &[&str]) -> Result<Option<Asset>, EmberError> {
+        match &self.source {
+            Source::Embedded(files) => {
+                let wanted = segments.join("/");
+                let index = if wanted.is_empty() {
+                    "index.html".to_string()
+                } else {
+                    format!("{wanted}/index.html")
+                };
+                Ok(files
+                    .iter()
+                    .find(|(file, _)| file.path.trim_start_matches('/') == wanted)
+                    .or_else(|| files.iter().find(|(file, _)| file.path.trim_start_matches('/') == index))
+                    .map(|(file, etag)| Asset {
+                        len: file.contents.len() as u64,
+                        modified: None,
+                        etag: etag.clone(),
+                        content_type: content_type_for(file.path),
+                        data: AssetData::Embedded(file.contents),
+                    }))
+            }
+            Source::Directory(root) => find_file(root, segments)
+                .await
+                .map_err(|err| EmberError::msg(format!("static file lookup failed: {err}"))),
+        }
+    }
+
+    async fn respond(&self, request: &Request, asset: Asset) -> Result<HttpResponse, EmberError> {
+        let mut response = HttpResponse::empty(200)
+            .with_header("Accept-Ranges", "bytes")
+            .with_header("ETag", asset.etag.clone());
+        if let Some(modified) = asset.modified {
+            response.headers.insert("Last-Modified", httpdate::fmt_http_date(modified));
+        }
+        if let Some(cache_control) = &self.cache_control {
+            response.headers.insert("Cache-Control", cache_control.clone());
+        }
+        if not_modified(request, &asset) {
+            response.status = 304;
+            return Ok(response);
+        }
+
+        let range = match request.header("range") {
+            Some(range) if if_range_matches(request, &asset) => parse_range(range, asset.len),
+            _ => ByteRange::Full,
+        };
+        let (start, len) = match range {
+            ByteRange::Full => (0, asset.len),
+            ByteRange::Partial(start, end) => {
+                response.status = 206;
+                response
+                    .headers
+                    .insert("Content-Range", format!("bytes {start}-{end}/{}", asset.len));
+                (start, end - start + 1)
+            }
+            ByteRange::Unsatisfiable => {
+                let detail = format!("range not satisfiable for {} of {} bytes", request.path, asset.len);
+                return Ok(HttpResponse::problem(416, detail)
+                    .with_header("Accept-Ranges", "bytes")
+                    .with_header("Content-Range", format!("bytes */{}", asset.len)));
+            }
+        };
+        asset
+            .send(&mut response, start, len)
+            .await
+            .map_err(|err| EmberError::msg(format!("static file read failed: {err}")))?;
+        response.content_type = Some(asset.content_type.to_string());
+        Ok(response)
+    }
+}
+
+impl Middleware for StaticFiles {
+    fn before<'a>(&'a self, request: &'a mut Request) -> MiddlewareFuture<'a, Option<HttpResponse>> {
+        Box::pin(async move {
+            if !matches!(request.method.as_str(), "GET" | "HEAD") {
+                return Ok(None);
+            }
+            match self.relative(&request.path) {
+                Some(relative) => self.serve(request, relative).await.map(Some),
+                None => Ok(None),
+            }
+        })
+    }
+}
+
+/// A file located for a request.
+struct Asset {
+    len: u64,
+    modified: Option<SystemTime>,
+    etag: String,
+    content_type: &'static str,
+    data: AssetData,
+}
+
+enum AssetData {
+    File(PathBuf),
+    Embedded(&'static [u8]),
+}
+
+impl Asset {
+    /// Set `len` bytes from `start` as the response body, streaming large files.
+    async fn send(&self, response: &mut HttpResponse, start: u64, len: u64) -> io::Result<()> {
+        match &self.data {
+            AssetData::Embedded(contents) => response.body = contents[start as usize..(start + len) as usize].to_vec(),
+            AssetData::File(path) => {
+                let mut file = tokio::fs::File::open(path).await?;
+                file.seek(SeekFrom::Start(start)).await?;
+                if len <= BUFFERED_FILE_BYTES {
+                    let mut body = Vec::with_capacity(len as usize);
+                    file.take(len).read_to_end(&mut body).await?;
+                    response.body = body;
+                } else {
+                    let chunks = FileChunks {
+                        file,
+                        remaining: len,
+                        buffer: Vec::new(),
+                    };
+                    response.stream = Some(BodyStream::new(chunks).with_content_length(len));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The next `remaining` bytes of a file, as a stream of chunks.
+struct FileChunks {
+    file: tokio::fs::File,
+    remaining: u64,
+    buffer: Vec<u8>,
+}
+
+impl Stream for FileChunks {
+    type Item = Result<Vec<u8>, EmberError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.remaining == 0 {
+            return Poll::Ready(None);
+        }
+        let wanted = this.remaining.min(FILE_CHUNK_BYTES as u64) as usize;
+        this.buffer.resize(wanted, 0);
+        let mut buf = ReadBuf::new(&mut this.buffer);
+        match Pin::new(&mut this.file).poll_read(cx, &mut buf) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Err(err)) => {
+                this.remaining = 0;
+                Poll::Ready(Some(Err(EmberError::msg(format!("static file read failed: {err}")))))
+            }
+            Poll::Ready(Ok(())) if buf.filled().is_empty() => {
+                this.remaining = 0;
+                Poll::Ready(Some(Err(EmberError::msg("static file shrank while being sent"))))
+            }
+            Poll::Ready(Ok(())) => {
+                let read = buf.filled().len();
+                this.remaining -= read as u64;
+                let mut chunk = std::mem::take(&mut this.buffer);
+                chunk.truncate(read);
+                Poll::Ready(Some(Ok(chunk)))
+            }
+        }
+    }
+}
+
+/// Look up a file below `root`, falling back to `index.html` for directories.
+async fn find_file(root: &Path, segments: &[&str]) -> io::Result<Option<Asset>> {
+    let mut path = root.to_path_buf();
+    path.extend(segments);
+    let mut metadata = match tokio::fs::metadata(&path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if metadata.is_dir() {
+        path.push("index.html");
+        metadata = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+    }
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    let root = tokio::fs::canonicalize(root).await?;
+    if !tokio::fs::canonicalize(&path).await?.starts_with(&root) {
+        return Ok(None);
+    }
+
+    let modified = metadata.modified().ok();
+    let stamp = modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    Ok(Some(Asset {
+        len: metadata.len(),
+        modified,
+        etag: format!("\"{stamp:x}-{:x}\"", metadata.len()),
+        content_type: content_type_for(&path.to_string_lossy()),
+        data: AssetData::File(path),
+    }))
+}
+
+/// Split a decoded relative path into segments, refusing anything that could
+/// escape the mount or expose hidden files.
+fn safe_segments(relative: &str) -> Option<Vec<&str>> {
+    let mut segments = Vec::new();
+    for segment in relative.split('/') {
+        if segment.is_empty() || segment == "." {
+            continue;
+        }
+        let hidden = segment.starts_with('.') && segment != ".well-known";
+        if hidden || segment.contains(['\\', '\0', ':']) {
+            return None;
+        }
+        segments.push(segment);
+    }
+    Some(segments)
+}
+
+/// Return `true` if the client's cached copy is still current.
+fn not_modified(request: &Request, asset: &Asset) -> bool {
+    if let Some(if_none_match) = request.header("if-none-match") {
+        return if_none_match.split(',').map(str::trim).any(|tag| {
+            tag == "*" || tag.trim_start_matches("W/") == asset.etag.trim_start_matches("W/")
+        });
+    }
+    match (request.header("if-modified-since"), asset.modified) {
+        (Some(since), Some(modified)) => match httpdate::parse_http_date(since) {
+            Ok(since) => unix_seconds(modified) <= unix_seconds(since),
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+/// Return `true` if a `Range` request may be honoured given its `If-Range`.
+fn if_range_matches(request: &Request, asset: &Asset) -> bool {
+    let Some(if_range) = request.header("if-range").map(str::trim) else {
+        return true;
+    };
+    if if_range.starts_with('"') {
+        return if_range == asset.etag;
+    }
+    if if_range.starts_with("W/") {
+        return false;
+    }
+    match (httpdate::parse_http_date(if_range), asset.modified) {
+        (Ok(date), Some(modified)) => unix_seconds(date) == unix_seconds(modified),
+        _ => false,
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// The outcome of interpreting a `Range` header.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// Serve the whole file.
+    Full,
+    /// Serve bytes `start..=end`.
+    Partial(u64, u64),
+    /// The range lies outside the file.
+    Unsatisfiable,
+}
+
+/// Parse a single `bytes=` range; anything else serves the whole file.
+fn parse_range(header: &str, len: u64) -> ByteRange {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    match (start.trim(), end.trim()) {
+        ("", "") => ByteRange::Full,
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial(len.saturating_sub(suffix), len - 1),
+            Err(_) => ByteRange::Full,
+        },
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else {
+                return ByteRange::Full;
+            };
+            if start >= len {
+                return ByteRange::Unsatisfiable;
+            }
+            let end = if end.is_empty() {
+                len - 1
+            } else {
+                match end.parse::<u64>() {
+                    Ok(end) if end >= start => end.min(len - 1),
+                    _ => return ByteRange::Full,
+                }
+            };
+            ByteRange::Partial(start, end)
+        }
+    }
+}
+
+/// Guess a content type from a file name's extension.
+fn content_type_for(path: &str) -> &'static str {
+    let extension = path
+        .rsplit('/')
+        .next()
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "txt" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// 64-bit FNV-1a, used for stable ETags of embedded files and response bodies.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), ByteRange::Partial(0, 9));
+        assert_eq!(parse_range("bytes=90-", 100), ByteRange::Partial(90, 99));
+        assert_eq!(parse_range("bytes=90-200", 100), ByteRange::Partial(90, 99));
+        assert_eq!(parse_range("bytes=-10", 100), ByteRange::Partial(90, 99));
+        assert_eq!(parse_range("bytes=-200", 100), ByteRange::Partial(0, 99));
+        assert_eq!(parse_range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-5", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_ranges_it_does_not_serve() {
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), ByteRange::Full);
+        assert_eq!(parse_range("items=0-1", 100), ByteRange::Full);
+        assert_eq!(parse_range("bytes=9-5", 100), ByteRange::Full);
+        assert_eq!(parse_range("bytes=x-5", 100), ByteRange::Full);
+        assert_eq!(parse_range("bytes=-", 100), ByteRange::Full);
+    }
+
+    async fn get(files: &StaticFiles, path: &str, range: Option<&str>) -> HttpResponse {
+        let mut request = Request::new("GET", path);
+        if let Some(range) = range {
+            request.headers.insert("Range", range);
+        }
+        files.before(&mut request).await.unwrap().expect("served")
+    }
+
+    async fn collect(mut stream: BodyStream) -> Vec<u8> {
+        let mut body = Vec::new();
+        while let Some(chunk) = stream.next_chunk().await {
+            body.extend(chunk.unwrap());
+        }
+        body
+    }
+
+    #[tokio::test]
+    async fn streams_large_files_and_seeks_for_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents: Vec<u8> = (0..200_000u32).map(|index| (index % 251) as u8).collect();
+        std::fs::write(dir.path().join("big.bin"), &contents).unwrap();
+        std::fs::write(dir.path().join("small.txt"), "small").unwrap();
+        let files = StaticFiles::dir("/files", dir.path());
+
+        let mut full = get(&files, "/files/big.bin", None).await;
+        let stream = full.stream.take().expect("streamed");
+        assert_eq!(stream.content_length(), Some(200_000));
+        assert!(full.body.is_empty());
+        assert_eq!(collect(stream).await, contents);
+
+        let mut partial = get(&files, "/files/big.bin", Some("bytes=70000-")).await;
+        assert_eq!(partial.status, 206);
+        assert_eq!(partial.headers.get("content-range"), Some("bytes 70000-199999/200000"));
+        let stream = partial.stream.take().expect("streamed");
+        assert_eq!(stream.content_length(), Some(130_000));
+        assert_eq!(collect(stream).await, contents[70_000..]);
+
+        let short = get(&files, "/files/big.bin", Some("bytes=10-19")).await;
+        assert!(short.stream.is_none());
+        assert_eq!(short.body, contents[10..20]);
+
+        let small = get(&files, "/files/small.txt", None).await;
+        assert!(small.stream.is_none());
+        assert_eq!(small.body, b"small");
+    }
+
+    #[tokio::test]
+    async fn unsatisfiable_ranges_are_problems() {
+        static FILES: &[EmbeddedFile] = &[EmbeddedFile {
+            path: "a.txt",
+            contents: b"hello",
+        }];
+        let files = StaticFiles::embedded("/", FILES);
+        let response = get(&files, "/a.txt", Some("bytes=10-")).await;
+        assert_eq!(response.status, 416);
+        assert_eq!(response.content_type.as_deref(), Some("application/problem+json"));
+        assert_eq!(response.headers.get("content-range"), Some("bytes */5"));
+    }
+}
@@ -0,0 +1,355 @@
+#![forbid(unsafe_code)]
+
+//! HTTPS termination and HTTP-to-HTTPS redirects for the built-in server.
+
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ember_ext_exceptions::EmberError;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tracing::{debug, error, info, warn};
+
+use crate::listener::ACCEPT_ERROR_BACKOFF;
+use crate::{server, HttpResponse, ServerConfig, ShutdownHandle};
+
+/// A byte stream the server can speak HTTP over, plain or encrypted.
+pub(crate) trait IoStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> IoStream for T {}
+
+/// Turns accepted connections into HTTP streams, terminating TLS when configured.
+pub(crate) struct StreamAcceptor {
+    #[cfg(feature = "tls")]
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    handshake_timeout: Duration,
+}
+
+impl StreamAcceptor {
+    /// Build an acceptor from `ember.server`, starting certificate reloading if enabled.
+    pub(crate) fn from_config(config: &ServerConfig, shutdown: &ShutdownHandle) -> Result<Self, EmberError> {
+        #[cfg(feature = "tls")]
+        let tls = match &config.tls {
+            Some(tls) => Some(rustls_support::acceptor(tls, shutdown)?),
+            None => None,
+        };
+        #[cfg(not(feature = "tls"))]
+        {
+            let _ = shutdown;
+            if config.tls.is_some() {
+                return Err(EmberError::msg(
+                    "ember.server.tls is set but ember-core was built without the `tls` feature",
+                ));
+            }
+        }
+        Ok(Self {
+            #[cfg(feature = "tls")]
+            tls,
+            handshake_timeout: config.header_read_timeout(),
+        })
+    }
+
+    /// Return `true` if connections are served over HTTPS.
+    pub(crate) fn is_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        {
+            self.tls.is_some()
+        }
+        #[cfg(not(feature = "tls"))]
+        {
+            false
+        }
+    }
+
+    /// Complete the TLS handshake, if any, within the header read timeout.
+    pub(crate) async fn accept(&self, socket: Box<dyn IoStream>) -> Result<Box<dyn IoStream>, EmberError> {
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = &self.tls {
+            let stream = tokio::time::timeout(self.handshake_timeout, acceptor.accept(socket))
+                .await
+                .map_err(|_| EmberError::msg("TLS handshake timed out"))?
+                .map_err(|err| EmberError::msg(format!("TLS handshake failed: {err}")))?;
+            return Ok(Box::new(stream));
+        }
+        let _ = self.handshake_timeout;
+        Ok(socket)
+    }
+}
+
+/// Start the plain HTTP listener that redirects to HTTPS, if one is configured.
+///
+/// Binding happens before this returns so that a bad address fails startup;
+/// the listener itself stops when shutdown begins.
+pub(crate) async fn start_redirect_listener(
+    config: &ServerConfig,
+    https_port: u16,
+    shutdown: &ShutdownHandle,
+) -> Result<(), EmberError> {
+    let Some(listen) = config.tls.as_ref().and_then(|tls| tls.redirect_listen.as_deref()) else {
+        return Ok(());
+    };
+    let addr = listen
+        .to_socket_addrs()
+        .map_err(|err| EmberError::msg(format!("invalid redirect listen address: {err}")))?
+        .next()
+        .ok_or_else(|| EmberError::msg("redirect listen address resolved to no sockets"))?;
+    let listener = TcpListener::bind(addr).await.map_err(|err| {
+        error!(error = %err, listen = %listen, "failed to bind redirect listen address");
+        EmberError::msg(format!("failed to bind redirect listen address: {err}"))
+    })?;
+    info!(listen = %listen, "redirecting HTTP to HTTPS");
+
+    let config = Arc::new(config.clone());
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        loop {
+            let (socket, peer) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!(error = %err, "redirect accept failed");
+                        tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                        continue;
+                    }
+                },
+                _ = shutdown.wait() => break,
+            };
+            let config = Arc::clone(&config);
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                server::serve_connection(socket, Some(peer), shutdown, config, |request| async move {
+                    match request.header("host") {
+                        Some(host) => HttpResponse::redirect(308, https_location(host, https_port, &request)),
+                        None => HttpResponse::problem(400, "missing Host header"),
+                    }
+                })
+                .await;
+            });
+        }
+        debug!("redirect listener stopped");
+    });
+    Ok(())
+}
+
+/// The `https://` URL for a request that arrived over plain HTTP.
+fn https_location(host: &str, https_port: u16, request: &crate::Request) -> String {
+    let hostname = match host.strip_prefix('[') {
+        Some(rest) => rest.split_once(']').map(|(ip, _)| format!("[{ip}]")).unwrap_or_else(|| host.to_string()),
+        None => host.split(':').next().unwrap_or(host).to_string(),
+    };
+    let mut location = format!("https://{hostname}");
+    if https_port != 443 {
+        location.push_str(&format!(":{https_port}"));
+    }
+    location.push_str(&encode_path(&request.path));
+    if let Some(query) = &request.raw_query {
+        location.push('?');
+        location.push_str(query);
+    }
+    location
+}
+
+/// Percent-encode a decoded path so it can be placed back into a URL.
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' | b'@' | b'!'
+            | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(feature = "tls")]
+mod rustls_support {
+    use std::fs;
+    use std::path::Path;
+    use std::sync::{Arc, RwLock};
+    use std::time::SystemTime;
+
+    use ember_ext_exceptions::EmberError;
+    use rustls::crypto::CryptoProvider;
+    use rustls::server::{ClientHello, ResolvesServerCert};
+    use rustls::sign::CertifiedKey;
+    use tracing::{info, warn};
+
+    use crate::server_config::TlsConfig;
+    use crate::ShutdownHandle;
+
+    /// Serves whichever certificate was loaded most recently.
+    #[derive(Debug)]
+    struct ReloadingResolver {
+        current: RwLock<Arc<CertifiedKey>>,
+    }
+
+    impl ReloadingResolver {
+        fn current(&self) -> Arc<CertifiedKey> {
+            Arc::clone(&self.current.read().unwrap_or_else(|poisoned| poisoned.into_inner()))
+        }
+
+        /// Load the files again, keeping the current certificate if they are
+        /// unreadable or the key does not belong to the certificate.
+        fn reload(&self, tls: &TlsConfig, provider: &CryptoProvider) -> Result<(), EmberError> {
+            let key = load_certified_key(tls, provider)?;
+            *self.current.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(key);
+            Ok(())
+        }
+    }
+
+    impl ResolvesServerCert for ReloadingResolver {
+        fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+            Some(self.current())
+        }
+    }
+
+    /// Build a TLS acceptor for `tls`, reloading its files as they change.
+    pub(super) fn acceptor(
+        tls: &TlsConfig,
+        shutdown: &ShutdownHandle,
+    ) -> Result<tokio_rustls::TlsAcceptor, EmberError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let resolver = Arc::new(ReloadingResolver {
+            current: RwLock::new(Arc::new(load_certified_key(tls, &provider)?)),
+        });
+        let mut config = rustls::ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|err| EmberError::msg(format!("invalid TLS configuration: {err}")))?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        if let Some(interval) = tls.reload_interval() {
+            let tls = tls.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                let mut last_modified = modified_times(&tls);
+                let mut ticker = tokio::time::interval(interval);
+                ticker.tick().await;
+                loop {
+                    tokio::select! {
+                        _ = ticker.tick() => {}
+                        _ = shutdown.wait() => break,
+                    }
+                    let modified = modified_times(&tls);
+                    if modified == last_modified {
+                        continue;
+                    }
+                    // A pair caught mid-rotation fails to load and is retried
+                    // on the next tick, since `last_modified` stays behind.
+                    match resolver.reload(&tls, &provider) {
+                        Ok(()) => {
+                            last_modified = modified;
+                            info!(cert = %tls.cert_path.display(), "reloaded TLS certificate");
+                        }
+                        Err(err) => {
+                            warn!(error = %err, "failed to reload TLS certificate; keeping the current one");
+                        }
+                    }
+                }
+            });
+        }
+        Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+    }
+
+    fn modified_times(tls: &TlsConfig) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        (modified(&tls.cert_path), modified(&tls.key_path))
+    }
+
+    fn load_certified_key(tls: &TlsConfig, provider: &CryptoProvider) -> Result<CertifiedKey, EmberError> {
+        let cert_pem = read_file(&tls.cert_path, "certificate")?;
+        let certs = rustls_pemfile::certs(&mut cert_pem.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| EmberError::msg(format!("invalid certificate PEM: {err}")))?;
+        if certs.is_empty() {
+            return Err(EmberError::msg(format!(
+                "no certificates found in {}",
+                tls.cert_path.display()
+            )));
+        }
+        let key_pem = read_file(&tls.key_path, "private key")?;
+        let key = rustls_pemfile::private_key(&mut key_pem.as_slice())
+            .map_err(|err| EmberError::msg(format!("invalid private key PEM: {err}")))?
+            .ok_or_else(|| EmberError::msg(format!("no private key found in {}", tls.key_path.display())))?;
+        let signing_key = provider
+            .key_provider
+            .load_private_key(key)
+            .map_err(|err| EmberError::msg(format!("unsupported private key: {err}")))?;
+        let certified = CertifiedKey::new(certs, signing_key);
+        certified.keys_match().map_err(|err| {
+            EmberError::msg(format!(
+                "private key {} does not match certificate {}: {err}",
+                tls.key_path.display(),
+                tls.cert_path.display()
+            ))
+        })?;
+        Ok(certified)
+    }
+
+    fn read_file(path: &Path, what: &str) -> Result<Vec<u8>, EmberError> {
+        fs::read(path).map_err(|err| EmberError::msg(format!("failed to read {what} {}: {err}", path.display())))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::path::PathBuf;
+
+        use super::*;
+
+        /// A self-signed certificate and its key, as PEM.
+        fn self_signed(name: &str) -> (String, String) {
+            let certified = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+            (certified.cert.pem(), certified.key_pair.serialize_pem())
+        }
+
+        fn write_pair(dir: &Path, cert: &str, key: &str) -> TlsConfig {
+            let (cert_path, key_path): (PathBuf, PathBuf) = (dir.join("cert.pem"), dir.join("key.pem"));
+            fs::write(&cert_path, cert).unwrap();
+            fs::write(&key_path, key).unwrap();
+            TlsConfig {
+                cert_path,
+                key_path,
+                ..TlsConfig::default()
+            }
+        }
+
+        #[test]
+        fn loads_a_matching_pair_and_refuses_a_mismatched_one() {
+            let dir = tempfile::tempdir().unwrap();
+            let provider = rustls::crypto::ring::default_provider();
+            let (cert, key) = self_signed("a.example");
+            let (_, other_key) = self_signed("b.example");
+
+            let tls = write_pair(dir.path(), &cert, &key);
+            assert!(load_certified_key(&tls, &provider).is_ok());
+            let tls = write_pair(dir.path(), &cert, &other_key);
+            let err = load_certified_key(&tls, &provider).unwrap_err();
+            assert!(err.to_string().contains("does not match"), "{err}");
+        }
+
+        #[test]
+        fn reload_keeps_the_current_pair_until_both_files_match() {
+            let dir = tempfile::tempdir().unwrap();
+            let provider = rustls::crypto::ring::default_provider();
+            let (old_cert, old_key) = self_signed("old.example");
+            let (new_cert, new_key) = self_signed("new.example");
+            let tls = write_pair(dir.path(), &old_cert, &old_key);
+            let resolver = ReloadingResolver {
+                current: RwLock::new(Arc::new(load_certified_key(&tls, &provider).unwrap())),
+            };
+            let served = resolver.current().cert.clone();
+
+            // The certificate was replaced before its key.
+            write_pair(dir.path(), &new_cert, &old_key);
+            assert!(resolver.reload(&tls, &provider).is_err());
+            assert_eq!(resolver.current().cert, served);
+
+            write_pair(dir.path(), &new_cert, &new_key);
+            resolver.reload(&tls, &provider).unwrap();
+            assert_ne!(resolver.current().cert, served);
+        }
+    }
+}
@@ -0,0 +1,808 @@
+#![forbid(unsafe_code)]
+
+//! WebSocket connections (RFC 6455).
+
+use std::fmt;
+use std::future::{Future, pending};
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ember_ext_auth::SecurityContext;
+use ember_ext_exceptions::EmberError;
+use ember_ext_http::{HttpResponse, Request, Upgrade, Upgraded};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::ShutdownHandle;
+use crate::server_config::ServerConfig;
+
+/// Appended to the client's key to derive `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message accepted unless the route sets `max_message_bytes`.
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// Bytes requested from the connection per read.
+const READ_CHUNK_BYTES: usize = 8 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// A message sent or received over a [`WebSocket`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketMessage {
+    /// A UTF-8 text message.
+    Text(String),
+    /// A binary message.
+    Binary(Vec<u8>),
+    /// A ping; received pings are answered with a pong automatically.
+    Ping(Vec<u8>),
+    /// A pong, either answering our ping or sent unsolicited as a heartbeat.
+    Pong(Vec<u8>),
+    /// The closing handshake, with the peer's status code and reason if it gave one.
+    Close(Option<CloseFrame>),
+}
+
+impl WebSocketMessage {
+    /// A text message.
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::Text(text.into())
+    }
+
+    /// A binary message.
+    pub fn binary(bytes: impl Into<Vec<u8>>) -> Self {
+        Self::Binary(bytes.into())
+    }
+
+    /// A text message holding `value` encoded as JSON.
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> Result<Self, EmberError> {
+        serde_json::to_string(value)
+            .map(Self::Text)
+            .map_err(|err| EmberError::msg(format!("encode failed: {err}")))
+    }
+
+    /// Decode a text or binary message as JSON.
+    pub fn parse_json<T: DeserializeOwned>(&self) -> Result<T, EmberError> {
+        let bytes = match self {
+            Self::Text(text) => text.as_bytes(),
+            Self::Binary(bytes) => bytes,
+            _ => return Err(EmberError::msg("expected a text or binary message")),
+        };
+        serde_json::from_slice(bytes).map_err(|err| EmberError::msg(format!("invalid JSON: {err}")))
+    }
+}
+
+/// The status code and reason carried by a close frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    /// Close status code, e.g. `1000` for a normal closure.
+    pub code: u16,
+    /// Human-readable reason; at most 123 bytes are sent.
+    pub reason: String,
+}
+
+/// Return values a `#[websocket]` handler may have.
+///
+/// An `Err` is logged and the connection is closed with status `1011`.
+pub trait WebSocketOutcome {
+    /// Convert into a result.
+    fn into_result(self) -> Result<(), EmberError>;
+}
+
+impl WebSocketOutcome for () {
+    fn into_result(self) -> Result<(), EmberError> {
+        Ok(())
+    }
+}
+
+impl WebSocketOutcome for Result<(), EmberError> {
+    fn into_result(self) -> Result<(), EmberError> {
+        self
+    }
+}
+
+/// A validated WebSocket handshake, ready to be accepted.
+///
+/// Created from the upgrade request after middleware has run, so the
+/// `SecurityContext` established by `SecurityMiddleware` is carried over to
+/// the [`WebSocket`].
+#[derive(Debug)]
+pub struct WebSocketUpgrade {
+    accept: String,
+    max_message_bytes: usize,
+    timeouts: Timeouts,
+    security_context: Option<SecurityContext>,
+    shutdown: Option<ShutdownHandle>,
+}
+
+/// How long an upgraded connection may wait on its peer.
+#[derive(Debug, Clone, Copy)]
+struct Timeouts {
+    idle: Duration,
+    write: Duration,
+}
+
+impl WebSocketUpgrade {
+    /// Validate the handshake headers of an upgrade request.
+    ///
+    /// Requests that are not WebSocket upgrades, or that ask for another
+    /// protocol version, get `426 Upgrade Required`; malformed keys get `400`.
+    pub fn from_request(request: &Request) -> Result<Self, HttpResponse> {
+        let upgrade = has_token(request, "upgrade", "websocket") && has_token(request, "connection", "upgrade");
+        if request.method != "GET" || !upgrade {
+            return Err(HttpResponse::problem(426, "WebSocket upgrade required").with_header("Upgrade", "websocket"));
+        }
+        if request.header("sec-websocket-version").map(str::trim) != Some("13") {
+            return Err(HttpResponse::problem(426, "unsupported WebSocket version")
+                .with_header("Sec-WebSocket-Version", "13"));
+        }
+        let key = request.header("sec-websocket-key").map(str::trim).unwrap_or_default();
+        if BASE64.decode(key).map(|nonce| nonce.len()) != Ok(16) {
+            return Err(HttpResponse::problem(400, "invalid Sec-WebSocket-Key"));
+        }
+        let mut digest = Sha1::new();
+        digest.update(key.as_bytes());
+        digest.update(ACCEPT_GUID.as_bytes());
+        let config = request.extensions.get::<Arc<ServerConfig>>();
+        let defaults = ServerConfig::default();
+        let config = config.map_or(&defaults, |config| &**config);
+        Ok(Self {
+            accept: BASE64.encode(digest.finalize()),
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            timeouts: Timeouts {
+                idle: config.upgrade_idle_timeout(),
+                write: config.write_timeout(),
+            },
+            security_context: request.extensions.get::<SecurityContext>().cloned(),
+            shutdown: request.extensions.get::<ShutdownHandle>().cloned(),
+        })
+    }
+
+    /// Refuse messages larger than this, closing with status `1009`.
+    pub fn with_max_message_bytes(mut self, max_message_bytes: usize) -> Self {
+        self.max_message_bytes = max_message_bytes;
+        self
+    }
+
+    /// Close the connection with `1001` when nothing arrives from the client
+    /// for this long; `ember.server.upgrade_idle_timeout_seconds` by default.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.timeouts.idle = idle_timeout;
+        self
+    }
+
+    /// Accept the handshake and run `handler` on the connection once the
+    /// `101 Switching Protocols` response has been sent.
+    ///
+    /// When the handler returns, the connection is closed with `1000`, or
+    /// with `1011` if it returned an error.
+    pub fn on_upgrade<F, Fut>(self, handler: F) -> HttpResponse
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: WebSocketOutcome,
+    {
+        let Self {
+            accept,
+            max_message_bytes,
+            timeouts,
+            security_context,
+            shutdown,
+        } = self;
+        let upgrade = Upgrade::new(move |upgraded| async move {
+            let socket = WebSocket::new(upgraded, max_message_bytes, timeouts, security_context, shutdown);
+            let sender = socket.sender.clone();
+            match handler(socket).await.into_result() {
+                Ok(()) => sender.finish(1000, "").await,
+                Err(err) => {
+                    warn!(error = %err, "WebSocket handler failed");
+                    sender.finish(1011, "internal error").await;
+                }
+            }
+        });
+        HttpResponse::switching_protocols("websocket", upgrade).with_header("Sec-WebSocket-Accept", accept)
+    }
+}
+
+/// Return `true` if the comma-separated header `name` lists `token`.
+fn has_token(request: &Request, name: &str, token: &str) -> bool {
+    request
+        .headers
+        .get_all(name)
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// An open WebSocket connection.
+///
+/// Use [`recv`](Self::recv) and [`send`](Self::send) directly, or
+/// [`split`](Self::split) the socket to read and write from separate tasks.
+pub struct WebSocket {
+    receiver: WebSocketReceiver,
+    sender: WebSocketSender,
+    security_context: Option<SecurityContext>,
+}
+
+impl WebSocket {
+    fn new(
+        upgraded: Upgraded,
+        max_message_bytes: usize,
+        timeouts: Timeouts,
+        security_context: Option<SecurityContext>,
+        shutdown: Option<ShutdownHandle>,
+    ) -> Self {
+        let (reader, writer) = tokio::io::split(upgraded);
+        let sender = WebSocketSender {
+            writer: Arc::new(Mutex::new(FrameWriter {
+                io: writer,
+                close_sent: false,
+                write_timeout: timeouts.write,
+            })),
+        };
+        let receiver = WebSocketReceiver {
+            reader,
+            buffer: Vec::new(),
+            sender: sender.clone(),
+            max_message_bytes,
+            idle_timeout: timeouts.idle,
+            fragments: None,
+            closed: false,
+            shutdown,
+        };
+        Self {
+            receiver,
+            sender,
+            security_context,
+        }
+    }
+
+    /// Wait for the next message; `None` once the connection is closed.
+    ///
+    /// Cancel-safe, like [`WebSocketReceiver::recv`].
+    pub async fn recv(&mut self) -> Option<Result<WebSocketMessage, EmberError>> {
+        self.receiver.recv().await
+    }
+
+    /// Send a message.
+    pub async fn send(&self, message: WebSocketMessage) -> Result<(), EmberError> {
+        self.sender.send(message).await
+    }
+
+    /// Start the closing handshake.
+    pub async fn close(&self, code: u16, reason: &str) -> Result<(), EmberError> {
+        self.sender.close(code, reason).await
+    }
+
+    /// The identity `SecurityMiddleware` authenticated the upgrade request as.
+    pub fn security_context(&self) -> Option<&SecurityContext> {
+        self.security_context.as_ref()
+    }
+
+    /// Separate the sending and receiving halves.
+    pub fn split(self) -> (WebSocketSender, WebSocketReceiver) {
+        (self.sender, self.receiver)
+    }
+}
+
+impl fmt::Debug for WebSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocket")
+            .field("security_context", &self.security_context)
+            .finish_non_exhaustive()
+    }
+}
+
+struct FrameWriter {
+    io: WriteHalf<Upgraded>,
+    close_sent: bool,
+    write_timeout: Duration,
+}
+
+impl FrameWriter {
+    /// Write one frame, giving up on the connection if it fails or the peer
+    /// stops reading for longer than the write timeout.
+    async fn write(&mut self, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+        let written = tokio::time::timeout(self.write_timeout, write_frame(&mut self.io, opcode, payload))
+            .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+        if written.is_err() {
+            self.close_sent = true;
+        }
+        written
+    }
+}
+
+/// The sending half of a [`WebSocket`]; clones share the connection.
+#[derive(Clone)]
+pub struct WebSocketSender {
+    writer: Arc<Mutex<FrameWriter>>,
+}
+
+impl WebSocketSender {
+    /// Send a message.
+    ///
+    /// Nothing more can be sent after a [`WebSocketMessage::Close`].
+    pub async fn send(&self, message: WebSocketMessage) -> Result<(), EmberError> {
+        let mut writer = self.writer.lock().await;
+        if writer.close_sent {
+            return Err(EmberError::msg("WebSocket is closed"));
+        }
+        let (opcode, payload) = match message {
+            WebSocketMessage::Text(text) => (OPCODE_TEXT, text.into_bytes()),
+            WebSocketMessage::Binary(bytes) => (OPCODE_BINARY, bytes),
+            WebSocketMessage::Ping(bytes) | WebSocketMessage::Pong(bytes) if bytes.len() > 125 => {
+                return Err(EmberError::msg("control frame payload exceeds 125 bytes"));
+            }
+            WebSocketMessage::Ping(bytes) => (OPCODE_PING, bytes),
+            WebSocketMessage::Pong(bytes) => (OPCODE_PONG, bytes),
+            WebSocketMessage::Close(frame) => {
+                writer.close_sent = true;
+                (OPCODE_CLOSE, close_payload(frame.as_ref()))
+            }
+        };
+        writer
+            .write(opcode, &payload)
+            .await
+            .map_err(|err| EmberError::msg(format!("write failed: {err}")))
+    }
+
+    /// Start the closing handshake.
+    pub async fn close(&self, code: u16, reason: &str) -> Result<(), EmberError> {
+        self.send(WebSocketMessage::Close(Some(CloseFrame {
+            code,
+            reason: reason.to_string(),
+        })))
+        .await
+    }
+
+    /// Close the connection, sending a close frame first if none was sent yet.
+    async fn finish(&self, code: u16, reason: &str) {
+        let mut writer = self.writer.lock().await;
+        if !writer.close_sent {
+            writer.close_sent = true;
+            let payload = close_payload(Some(&CloseFrame {
+                code,
+                reason: reason.to_string(),
+            }));
+            let _ = writer.write(OPCODE_CLOSE, &payload).await;
+        }
+        let write_timeout = writer.write_timeout;
+        let _ = tokio::time::timeout(write_timeout, writer.io.shutdown()).await;
+    }
+
+    /// Answer the peer's close frame unless we already sent one, then close.
+    async fn acknowledge_close(&self, frame: Option<&CloseFrame>) {
+        let code = frame.map_or(1000, |frame| frame.code);
+        self.finish(code, "").await;
+    }
+}
+
+impl fmt::Debug for WebSocketSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocketSender").finish_non_exhaustive()
+    }
+}
+
+/// The receiving half of a [`WebSocket`].
+///
+/// Pings are answered while receiving, so keep calling
+/// [`recv`](Self::recv) for as long as the connection should stay healthy.
+pub struct WebSocketReceiver {
+    reader: ReadHalf<Upgraded>,
+    /// Bytes read from the connection that do not form a whole frame yet.
+    buffer: Vec<u8>,
+    sender: WebSocketSender,
+    max_message_bytes: usize,
+    idle_timeout: Duration,
+    fragments: Option<(u8, Vec<u8>)>,
+    closed: bool,
+    shutdown: Option<ShutdownHandle>,
+}
+
+impl WebSocketReceiver {
+    /// Wait for the next message; `None` once the connection is closed.
+    ///
+    /// Protocol violations close the connection with the matching status
+    /// code and are returned as an error. When the server shuts down the
+    /// connection is closed with `1001` and a [`WebSocketMessage::Close`] is
+    /// returned; a client silent for longer than the idle timeout is closed
+    /// with `1001` too.
+    ///
+    /// Cancel-safe: a frame partly received when the future is dropped, for
+    /// example by losing a `select!`, stays buffered and is completed by the
+    /// next call. Only the automatic pong of a ping can be lost that way.
+    pub async fn recv(&mut self) -> Option<Result<WebSocketMessage, EmberError>> {
+        if self.closed {
+            return None;
+        }
+        loop {
+            let outcome = match parse_frame(&mut self.buffer, self.max_message_bytes) {
+                Ok(Some(frame)) => self.handle_frame(frame).await,
+                Ok(None) => match self.fill().await {
+                    Fill::Read => continue,
+                    Fill::Ended(err) => {
+                        debug!(error = ?err, "WebSocket connection ended without a close frame");
+                        self.closed = true;
+                        return None;
+                    }
+                    Fill::Idle => {
+                        debug!(timeout = ?self.idle_timeout, "closing idle WebSocket connection");
+                        self.closed = true;
+                        self.sender.finish(1001, "idle timeout").await;
+                        return None;
+                    }
+                    Fill::Shutdown => {
+                        self.closed = true;
+                        self.sender.finish(1001, "server shutting down").await;
+                        return Some(Ok(WebSocketMessage::Close(Some(CloseFrame {
+                            code: 1001,
+                            reason: "server shutting down".to_string(),
+                        }))));
+                    }
+                },
+                Err(violation) => Err(violation),
+            };
+            match outcome {
+                Ok(Some(message)) => return Some(Ok(message)),
+                Ok(None) => continue,
+                Err((code, reason)) => {
+                    self.closed = true;
+                    self.sender.finish(code, reason).await;
+                    return Some(Err(EmberError::msg(format!("WebSocket protocol error: {reason}"))));
+                }
+            }
+        }
+    }
+
+    /// Read more of the connection into the buffer.
+    async fn fill(&mut self) -> Fill {
+        self.buffer.reserve(READ_CHUNK_BYTES);
+        let shutdown = async {
+            match &self.shutdown {
+                Some(shutdown) => shutdown.wait().await,
+                None => pending().await,
+            }
+        };
+        // `read_buf` reads nothing when another branch wins, so dropping it
+        // loses no data.
+        tokio::select! {
+            read = self.reader.read_buf(&mut self.buffer) => match read {
+                Ok(0) => Fill::Ended(None),
+                Ok(_) => Fill::Read,
+                Err(err) => Fill::Ended(Some(err)),
+            },
+            () = shutdown => Fill::Shutdown,
+            () = tokio::time::sleep(self.idle_timeout) => Fill::Idle,
+        }
+    }
+
+    /// Apply a frame, returning a message once one is complete.
+    async fn handle_frame(&mut self, frame: Frame) -> Result<Option<WebSocketMessage>, (u16, &'static str)> {
+        match frame.opcode {
+            OPCODE_TEXT | OPCODE_BINARY if self.fragments.is_some() => Err((1002, "expected a continuation frame")),
+            OPCODE_TEXT | OPCODE_BINARY if !frame.fin => {
+                self.fragments = Some((frame.opcode, frame.payload));
+                Ok(None)
+            }
+            OPCODE_TEXT | OPCODE_BINARY => data_message(frame.opcode, frame.payload).map(Some),
+            OPCODE_CONTINUATION => {
+                let Some((_, buffer)) = self.fragments.as_mut() else {
+                    return Err((1002, "unexpected continuation frame"));
+                };
+                if buffer.len() + frame.payload.len() > self.max_message_bytes {
+                    return Err((1009, "message too big"));
+                }
+                buffer.extend_from_slice(&frame.payload);
+                if !frame.fin {
+                    return Ok(None);
+                }
+                let (opcode, payload) = self.fragments.take().unwrap_or_default();
+                data_message(opcode, payload).map(Some)
+            }
+            OPCODE_CLOSE => {
+                let close = parse_close(&frame.payload)?;
+                self.closed = true;
+                self.sender.acknowledge_close(close.as_ref()).await;
+                Ok(Some(WebSocketMessage::Close(close)))
+            }
+            OPCODE_PING => {
+                let _ = self.sender.send(WebSocketMessage::Pong(frame.payload.clone())).await;
+                Ok(Some(WebSocketMessage::Ping(frame.payload)))
+            }
+            OPCODE_PONG => Ok(Some(WebSocketMessage::Pong(frame.payload))),
+            _ => Err((1002, "unknown opcode")),
+        }
+    }
+}
+
+impl fmt::Debug for WebSocketReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocketReceiver")
+            .field("max_message_bytes", &self.max_message_bytes)
+            .field("closed", &self.closed)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Build a complete text or binary message.
+fn data_message(opcode: u8, payload: Vec<u8>) -> Result<WebSocketMessage, (u16, &'static str)> {
+    if opcode == OPCODE_BINARY {
+        return Ok(WebSocketMessage::Binary(payload));
+    }
+    String::from_utf8(payload)
+        .map(WebSocketMessage::Text)
+        .map_err(|_| (1007, "invalid UTF-8 in text message"))
+}
+
+/// Decode a close frame payload.
+fn parse_close(payload: &[u8]) -> Result<Option<CloseFrame>, (u16, &'static str)> {
+    match payload {
+        [] => Ok(None),
+        [_] => Err((1002, "invalid close frame")),
+        [high, low, reason @ ..] => {
+            let code = u16::from_be_bytes([*high, *low]);
+            if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+                return Err((1002, "invalid close code"));
+            }
+            let reason = std::str::from_utf8(reason).map_err(|_| (1007, "invalid UTF-8 in close reason"))?;
+            Ok(Some(CloseFrame {
+                code,
+                reason: reason.to_string(),
+            }))
+        }
+    }
+}
+
+/// Encode a close frame payload, trimming the reason to fit a control frame.
+fn close_payload(frame: Option<&CloseFrame>) -> Vec<u8> {
+    let Some(frame) = frame else {
+        return Vec::new();
+    };
+    let mut end = frame.reason.len().min(123);
+    while !frame.reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut payload = frame.code.to_be_bytes().to_vec();
+    payload.extend_from_slice(&frame.reason.as_bytes()[..end]);
+    payload
+}
+
+/// What waiting for more input produced.
+enum Fill {
+    /// More bytes are buffered.
+    Read,
+    /// The connection closed or failed.
+    Ended(Option<std::io::Error>),
+    /// Nothing arrived within the idle timeout.
+    Idle,
+    /// The server is shutting down.
+    Shutdown,
+}
+
+/// A single frame read from the client.
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Take one client frame off the front of `buffer`; `None` until it holds a
+/// whole frame. Payloads over `max_payload` are refused from the header,
+/// before they are buffered.
+fn parse_frame(buffer: &mut Vec<u8>, max_payload: usize) -> Result<Option<Frame>, (u16, &'static str)> {
+    let [first, second, ..] = buffer[..] else {
+        return Ok(None);
+    };
+    let fin = first & 0x80 != 0;
+    let opcode = first & 0x0f;
+    if first & 0x70 != 0 {
+        return Err((1002, "reserved bits set"));
+    }
+    if second & 0x80 == 0 {
+        return Err((1002, "client frames must be masked"));
+    }
+    let (len, mask_start) = match second & 0x7f {
+        126 => match buffer.get(2..4) {
+            Some(&[high, low]) => (u64::from(u16::from_be_bytes([high, low])), 4),
+            _ => return Ok(None),
+        },
+        127 => match buffer.get(2..10) {
+            Some(bytes) => {
+                let mut len = [0u8; 8];
+                len.copy_from_slice(bytes);
+                (u64::from_be_bytes(len), 10)
+            }
+            None => return Ok(None),
+        },
+        len => (u64::from(len), 2),
+    };
+    if opcode >= OPCODE_CLOSE && (!fin || len > 125) {
+        return Err((1002, "invalid control frame"));
+    }
+    if len > max_payload as u64 {
+        return Err((1009, "message too big"));
+    }
+    let payload_start = mask_start + 4;
+    let frame_len = payload_start + len as usize;
+    if buffer.len() < frame_len {
+        return Ok(None);
+    }
+    let mut mask = [0u8; 4];
+    mask.copy_from_slice(&buffer[mask_start..payload_start]);
+    let mut payload: Vec<u8> = buffer.drain(..frame_len).skip(payload_start).collect();
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+    Ok(Some(Frame { fin, opcode, payload }))
+}
+
+/// Write one unmasked, unfragmented server frame.
+async fn write_frame(io: &mut WriteHalf<Upgraded>, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    io.write_all(&frame).await?;
+    io.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    use super::*;
+
+    /// Encode a masked client frame.
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![first];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(index, byte)| byte ^ mask[index % 4]));
+        frame
+    }
+
+    fn socket(idle: Duration) -> (WebSocket, DuplexStream) {
+        let (server, client) = tokio::io::duplex(64 * 1024);
+        let timeouts = Timeouts {
+            idle,
+            write: Duration::from_secs(5),
+        };
+        let socket = WebSocket::new(Upgraded::new(server, Vec::new()), 1024, timeouts, None, None);
+        (socket, client)
+    }
+
+    #[test]
+    fn unmasks_frames_of_every_length_encoding() {
+        for len in [0, 5, 125, 126, 300, 70_000] {
+            let payload: Vec<u8> = (0..len).map(|index| index as u8).collect();
+            let mut buffer = client_frame(0x82, &payload);
+            buffer.extend_from_slice(b"next");
+            let frame = parse_frame(&mut buffer, usize::MAX).unwrap().unwrap();
+            assert!(frame.fin);
+            assert_eq!(frame.opcode, OPCODE_BINARY);
+            assert_eq!(frame.payload, payload);
+            assert_eq!(buffer, b"next");
+        }
+    }
+
+    #[test]
+    fn waits_for_whole_frames() {
+        let frame = client_frame(0x81, b"hello");
+        let mut buffer = Vec::new();
+        for byte in &frame[..frame.len() - 1] {
+            buffer.push(*byte);
+            assert!(parse_frame(&mut buffer, 1024).unwrap().is_none());
+        }
+        buffer.push(frame[frame.len() - 1]);
+        assert_eq!(parse_frame(&mut buffer, 1024).unwrap().unwrap().payload, b"hello");
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn rejects_protocol_violations_from_the_header() {
+        let mut unmasked = vec![0x81, 0x05];
+        assert_eq!(parse_frame(&mut unmasked, 1024).err(), Some((1002, "client frames must be masked")));
+        let mut reserved = client_frame(0xc1, b"x");
+        assert_eq!(parse_frame(&mut reserved, 1024).err(), Some((1002, "reserved bits set")));
+        let mut fragmented_ping = client_frame(0x09, b"x");
+        assert_eq!(parse_frame(&mut fragmented_ping, 1024).err(), Some((1002, "invalid control frame")));
+        // Only the header of an oversize frame is needed to refuse it.
+        let mut oversize = client_frame(0x82, &[0; 2048])[..4].to_vec();
+        assert_eq!(parse_frame(&mut oversize, 1024).err(), Some((1009, "message too big")));
+    }
+
+    #[test]
+    fn close_codes_follow_the_registry() {
+        for code in [1000u16, 1001, 1003, 1007, 1011, 1012, 1013, 1014, 3000, 4999] {
+            let close = parse_close(&code.to_be_bytes()).unwrap().expect("close frame");
+            assert_eq!(close.code, code);
+        }
+        for code in [0u16, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+            assert_eq!(parse_close(&code.to_be_bytes()).unwrap_err().0, 1002, "code {code}");
+        }
+        assert_eq!(parse_close(&[0x03, 0xe8, 0xff]).unwrap_err().0, 1007);
+    }
+
+    #[tokio::test]
+    async fn reassembles_fragments_around_control_frames() {
+        let (mut socket, mut client) = socket(Duration::from_secs(5));
+        client.write_all(&client_frame(0x01, b"frag")).await.unwrap();
+        client.write_all(&client_frame(0x89, b"hb")).await.unwrap();
+        client.write_all(&client_frame(0x00, b"men")).await.unwrap();
+        client.write_all(&client_frame(0x80, b"ted")).await.unwrap();
+        assert_eq!(socket.recv().await.unwrap().unwrap(), WebSocketMessage::Ping(b"hb".to_vec()));
+        assert_eq!(socket.recv().await.unwrap().unwrap(), WebSocketMessage::text("fragmented"));
+
+        let mut pong = [0u8; 4];
+        client.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong, [0x80 | OPCODE_PONG, 2, b'h', b'b']);
+    }
+
+    #[tokio::test]
+    async fn rejects_interleaved_data_frames() {
+        let (mut socket, mut client) = socket(Duration::from_secs(5));
+        client.write_all(&client_frame(0x01, b"one")).await.unwrap();
+        client.write_all(&client_frame(0x81, b"two")).await.unwrap();
+        assert!(socket.recv().await.unwrap().is_err());
+        let mut close = Vec::new();
+        client.read_to_end(&mut close).await.unwrap();
+        assert_eq!(close[..4], [0x80 | OPCODE_CLOSE, 31, 0x03, 0xea]);
+        assert_eq!(&close[4..], b"expected a continuation frame");
+        assert!(socket.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn recv_is_cancel_safe() {
+        let (mut socket, mut client) = socket(Duration::from_secs(5));
+        let frame = client_frame(0x81, b"split across a cancelled recv");
+        client.write_all(&frame[..10]).await.unwrap();
+        let cancelled = tokio::time::timeout(Duration::from_millis(50), socket.recv()).await;
+        assert!(cancelled.is_err());
+        client.write_all(&frame[10..]).await.unwrap();
+        assert_eq!(
+            socket.recv().await.unwrap().unwrap(),
+            WebSocketMessage::text("split across a cancelled recv")
+        );
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections() {
+        let (mut socket, mut client) = socket(Duration::from_millis(50));
+        assert!(socket.recv().await.is_none());
+        let mut close = Vec::new();
+        client.read_to_end(&mut close).await.unwrap();
+        assert_eq!(close[..4], [0x80 | OPCODE_CLOSE, 14, 0x03, 0xe9]);
+        assert_eq!(&close[4..], b"idle timeout");
+    }
+}
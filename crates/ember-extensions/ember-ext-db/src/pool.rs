@@ -46,6 +46,11 @@ impl DbPool {
         Ok(())
     }
 
+    /// Close all connections, waiting for checked-out connections to be returned.
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+
     /// Access the inner pool.
     pub fn pool(&self) -> &PgPool {
         &self.pool
@@ -5,11 +5,21 @@
 use serde::{Deserialize, Serialize};
 
 /// Ember error type for library crates.
+///
+/// Returned from a handler, a [`EmberError::Message`] is answered with `500`,
+/// a [`EmberError::Status`] with its status and a [`EmberError::Problem`]
+/// with its problem details.
 #[derive(Debug, thiserror::Error)]
 pub enum EmberError {
     /// A generic error message.
     #[error("{message}")]
     Message { message: String },
+    /// An error with the HTTP status it should be answered with.
+    #[error("{message}")]
+    Status { status: u16, message: String },
+    /// An error answered with these problem details as-is.
+    #[error("{}: {}", .0.title, .0.detail)]
+    Problem(Box<ProblemDetails>),
 }
 
 impl EmberError {
@@ -19,9 +29,58 @@ impl EmberError {
             message: message.into(),
         }
     }
+
+    /// Create an error answered with `status`.
+    pub fn status(status: u16, message: impl Into<String>) -> Self {
+        Self::Status {
+            status,
+            message: message.into(),
+        }
+    }
+
+    /// Create a `400 Bad Request` error.
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::status(400, message)
+    }
+
+    /// Create a `401 Unauthorized` error.
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::status(401, message)
+    }
+
+    /// Create a `403 Forbidden` error.
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::status(403, message)
+    }
+
+    /// Create a `404 Not Found` error.
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::status(404, message)
+    }
+
+    /// Create a `409 Conflict` error.
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::status(409, message)
+    }
+
+    /// Create an error answered with `problem`.
+    pub fn problem(problem: ProblemDetails) -> Self {
+        Self::Problem(Box::new(problem))
+    }
+
+    /// The HTTP status this error is answered with.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::Message { .. } => 500,
+            Self::Status { status, .. } => *status,
+            Self::Problem(problem) => problem.status,
+        }
+    }
 }
 
-/// A consistent JSON error payload inspired by Problem Details.
+/// An RFC 9457 problem details payload, sent as `application/problem+json`.
+///
+/// An empty `detail` or `instance` is left out of the JSON.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProblemDetails {
     /// A URI reference that identifies the problem type.
@@ -32,8 +91,10 @@ pub struct ProblemDetails {
     /// The HTTP status code for this occurrence of the problem.
     pub status: u16,
     /// A human-readable explanation specific to this occurrence.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub detail: String,
     /// A URI reference that identifies the specific occurrence.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub instance: String,
 }
 
@@ -0,0 +1,181 @@
+#![forbid(unsafe_code)]
+
+//! Serialization formats for request and response bodies.
+
+use ember_ext_exceptions::EmberError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// A body format selected by `Accept` or `Content-Type`.
+///
+/// JSON is always available; MessagePack, CBOR and YAML are enabled by the
+/// `msgpack`, `cbor` and `yaml` cargo features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFormat {
+    /// `application/json`.
+    Json,
+    /// `application/msgpack`, with structs encoded as maps.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    /// `application/cbor`.
+    #[cfg(feature = "cbor")]
+    Cbor,
+    /// `application/yaml`.
+    #[cfg(feature = "yaml")]
+    Yaml,
+}
+
+impl BodyFormat {
+    /// Formats compiled into this build, in server preference order.
+    pub const ALL: &'static [BodyFormat] = &[
+        BodyFormat::Json,
+        #[cfg(feature = "msgpack")]
+        BodyFormat::MessagePack,
+        #[cfg(feature = "cbor")]
+        BodyFormat::Cbor,
+        #[cfg(feature = "yaml")]
+        BodyFormat::Yaml,
+    ];
+
+    /// The media type sent in `Content-Type`.
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => "application/msgpack",
+            #[cfg(feature = "cbor")]
+            Self::Cbor => "application/cbor",
+            #[cfg(feature = "yaml")]
+            Self::Yaml => "application/yaml",
+        }
+    }
+
+    /// Human-readable name used in error messages.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Json => "JSON",
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => "MessagePack",
+            #[cfg(feature = "cbor")]
+            Self::Cbor => "CBOR",
+            #[cfg(feature = "yaml")]
+            Self::Yaml => "YAML",
+        }
+    }
+
+    /// Other media types clients use for the same format.
+    fn aliases(self) -> &'static [&'static str] {
+        match self {
+            Self::Json => &[],
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => &["application/x-msgpack", "application/vnd.msgpack"],
+            #[cfg(feature = "cbor")]
+            Self::Cbor => &[],
+            #[cfg(feature = "yaml")]
+            Self::Yaml => &["application/x-yaml", "text/yaml", "text/x-yaml"],
+        }
+    }
+
+    /// The format for a `Content-Type` value; parameters are ignored and
+    /// structured suffixes such as `+json` are understood.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        Self::ALL.iter().copied().find(|format| {
+            format.matches(&essence)
+                || essence
+                    .rsplit_once('+')
+                    .is_some_and(|(_, suffix)| format.media_type().ends_with(&format!("/{suffix}")))
+        })
+    }
+
+    /// Pick the response format for an `Accept` header.
+    ///
+    /// A missing or empty header means JSON. Otherwise the most specific
+    /// matching range decides each format's quality, the highest quality wins,
+    /// and ties go to the earlier entry of [`BodyFormat::ALL`]. `None` means
+    /// the client accepts none of them.
+    pub fn negotiate(accept: Option<&str>) -> Option<Self> {
+        let Some(accept) = accept.filter(|accept| !accept.trim().is_empty()) else {
+            return Some(Self::Json);
+        };
+        let ranges: Vec<(String, f32)> = accept
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let range = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+                let quality = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (!range.is_empty()).then_some((range, quality))
+            })
+            .collect();
+
+        let mut best: Option<(Self, f32)> = None;
+        for &format in Self::ALL {
+            let quality = ranges
+                .iter()
+                .filter_map(|(range, quality)| format.specificity(range).map(|specificity| (specificity, *quality)))
+                .max_by_key(|(specificity, _)| *specificity)
+                .map(|(_, quality)| quality);
+            match quality {
+                Some(quality) if quality > 0.0 && best.is_none_or(|(_, current)| quality > current) => {
+                    best = Some((format, quality));
+                }
+                _ => {}
+            }
+        }
+        best.map(|(format, _)| format)
+    }
+
+    /// Serialize `value` in this format.
+    pub fn serialize<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, EmberError> {
+        let encoded = match self {
+            Self::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => rmp_serde::to_vec_named(value).map_err(|err| err.to_string()),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => {
+                let mut body = Vec::new();
+                ciborium::into_writer(value, &mut body).map(|()| body).map_err(|err| err.to_string())
+            }
+            #[cfg(feature = "yaml")]
+            Self::Yaml => serde_yaml::to_string(value).map(String::into_bytes).map_err(|err| err.to_string()),
+        };
+        encoded.map_err(|err| EmberError::msg(format!("encode failed: {err}")))
+    }
+
+    /// Deserialize a body written in this format.
+    pub fn deserialize<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, EmberError> {
+        let decoded = match self {
+            Self::Json => serde_json::from_slice(body).map_err(|err| err.to_string()),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => rmp_serde::from_slice(body).map_err(|err| err.to_string()),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => ciborium::from_reader(body).map_err(|err| err.to_string()),
+            #[cfg(feature = "yaml")]
+            Self::Yaml => serde_yaml::from_slice(body).map_err(|err| err.to_string()),
+        };
+        decoded.map_err(|err| EmberError::msg(format!("invalid {}: {err}", self.name())))
+    }
+
+    fn matches(self, essence: &str) -> bool {
+        essence == self.media_type() || self.aliases().contains(&essence)
+    }
+
+    /// How specifically `range` names this format: 3 for the exact type, 2 for
+    /// `type/*`, 1 for `*/*`.
+    fn specificity(self, range: &str) -> Option<u8> {
+        if self.matches(range) {
+            return Some(3);
+        }
+        if range == "*/*" {
+            return Some(1);
+        }
+        let top = range.strip_suffix("/*")?;
+        std::iter::once(self.media_type())
+            .chain(self.aliases().iter().copied())
+            .any(|media_type| media_type.split('/').next() == Some(top))
+            .then_some(2)
+    }
+}
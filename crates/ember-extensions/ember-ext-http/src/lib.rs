@@ -2,9 +2,40 @@
 
 //! HTTP routing primitives for Ember.
 
+mod format;
+mod middleware;
+mod request;
+mod response;
+mod upgrade;
+
+use std::future::Future;
+use std::pin::Pin;
+
+use ember_ext_exceptions::EmberError;
 use serde::{Deserialize, Serialize};
 
-/// A lightweight JSON wrapper for Ember handlers.
+pub use format::BodyFormat;
+pub use middleware::{Middleware, MiddlewareFuture, Next};
+pub use request::{parse_query, percent_decode, Extensions, Headers, Request};
+pub use response::{reason_phrase, BodyStream, HttpResponse, IntoResponse};
+pub use upgrade::{Upgrade, Upgraded};
+
+/// Future returned by [`HttpHandler::handle`].
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Result<HttpResponse, EmberError>> + Send + 'a>>;
+
+/// Trait for controllers that can handle HTTP requests.
+///
+/// Handlers run on the tokio runtime, so I/O should be awaited inside the
+/// returned future rather than blocking the calling thread.
+pub trait HttpHandler {
+    /// Handle an HTTP request and return a response.
+    fn handle<'a>(&'a self, request: &'a Request) -> HandlerFuture<'a>;
+}
+
+/// A serde value exchanged with clients.
+///
+/// Returned from a handler it is serialized in the format the client's
+/// `Accept` header asks for (JSON by default, see [`BodyFormat`]).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Json<T>(pub T);
 
@@ -26,8 +57,102 @@ impl<T> From<T> for Json<T> {
 pub struct Route {
     /// HTTP method.
     pub method: &'static str,
-    /// Route path.
+    /// Route path; `{name}` segments match any single path segment.
     pub path: &'static str,
+    /// Tag `GET` responses with a strong `ETag` hashed from the body.
+    pub etag: bool,
+    /// `Cache-Control` directives sent with successful responses that set none.
+    pub cache_control: Option<&'static str>,
+}
+
+impl Route {
+    /// Return `true` if the route's path template has no `{name}` segments.
+    pub fn is_static(&self) -> bool {
+        !self.path.contains('{')
+    }
+
+    /// Return `true` if `path` matches this route's template, ignoring the method.
+    ///
+    /// `path` is the raw, still percent-encoded request path: it is split on
+    /// literal `/` only, and each segment is decoded before it is compared.
+    pub fn matches_path(&self, path: &str) -> bool {
+        let mut template = self.path.split('/');
+        let mut actual = path.split('/');
+        loop {
+            match (template.next(), actual.next()) {
+                (None, None) => return true,
+                (Some(expected), Some(segment)) if is_param(expected) => {
+                    if segment.is_empty() {
+                        return false;
+                    }
+                }
+                (Some(expected), Some(segment)) if expected == segment => {}
+                (Some(expected), Some(segment))
+                    if segment.contains('%') && percent_decode(segment, false) == expected => {}
+                _ => return false,
+            }
+        }
+    }
+
+    /// Return `true` if this route handles `method` and `path`.
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        self.method.eq_ignore_ascii_case(method) && self.matches_path(path)
+    }
+
+    /// The raw segment of `path` captured by the `{name}` parameter, if the
+    /// route has one and `path` matches it; decode it with [`percent_decode`].
+    pub fn param<'p>(&self, path: &'p str, name: &str) -> Option<&'p str> {
+        if !self.matches_path(path) {
+            return None;
+        }
+        self.path
+            .split('/')
+            .zip(path.split('/'))
+            .find(|(segment, _)| is_param(segment) && segment[1..segment.len() - 1] == *name)
+            .map(|(_, value)| value)
+    }
+
+    /// Return `true` if both routes would claim the same requests.
+    ///
+    /// Parameter names are ignored, so `/books/{id}` overlaps `/books/{isbn}`.
+    pub fn overlaps(&self, other: &Route) -> bool {
+        if !self.method.eq_ignore_ascii_case(other.method) {
+            return false;
+        }
+        let ours: Vec<&str> = self.path.split('/').collect();
+        let theirs: Vec<&str> = other.path.split('/').collect();
+        ours.len() == theirs.len()
+            && ours
+                .iter()
+                .zip(&theirs)
+                .all(|(a, b)| a == b || (is_param(a) && is_param(b)))
+    }
+}
+
+fn is_param(segment: &str) -> bool {
+    segment.len() > 2 && segment.starts_with('{') && segment.ends_with('}')
+}
+
+/// Position of the route that serves `method` and `path`.
+///
+/// Static routes win over templated ones and declaration order decides among
+/// the rest, so `/books/search` is served by its own route even when
+/// `/books/{id}` is declared first. `HEAD` falls back to the `GET` route when
+/// no route claims it.
+pub fn route_index<'r, I>(routes: I, method: &str, path: &str) -> Option<usize>
+where
+    I: IntoIterator<Item = &'r Route>,
+    I::IntoIter: Clone,
+{
+    let routes = routes.into_iter();
+    let find = |method: &str| {
+        let matching = |route: &Route| route.matches(method, path);
+        routes
+            .clone()
+            .position(|route| route.is_static() && matching(route))
+            .or_else(|| routes.clone().position(matching))
+    };
+    find(method).or_else(|| method.eq_ignore_ascii_case("HEAD").then(|| find("GET")).flatten())
 }
 
 /// A simple route registry.
@@ -44,7 +169,17 @@ impl Router {
 
     /// Register a route.
     pub fn register(&mut self, method: &'static str, path: &'static str) {
-        self.routes.push(Route { method, path });
+        self.routes.push(Route {
+            method,
+            path,
+            etag: false,
+            cache_control: None,
+        });
+    }
+
+    /// Register a route along with its caching settings.
+    pub fn register_route(&mut self, route: Route) {
+        self.routes.push(route);
     }
 
     /// Read the registered routes.
@@ -52,3 +187,116 @@ impl Router {
         &self.routes
     }
 }
+
+/// Methods `routes` accept for `path`, in declaration order.
+///
+/// `GET` routes also accept `HEAD`, and `OPTIONS` is listed whenever any
+/// route matches; the result is empty when none does. The path `*` collects
+/// the methods of every route.
+pub fn allowed_methods<'r>(routes: impl IntoIterator<Item = &'r Route>, path: &str) -> Vec<&'static str> {
+    let mut methods: Vec<&'static str> = Vec::new();
+    for route in routes {
+        if path != "*" && !route.matches_path(path) {
+            continue;
+        }
+        let implied: &[&'static str] = if route.method.eq_ignore_ascii_case("GET") {
+            &["GET", "HEAD"]
+        } else {
+            std::slice::from_ref(&route.method)
+        };
+        for method in implied {
+            if !methods.iter().any(|listed| listed.eq_ignore_ascii_case(method)) {
+                methods.push(method);
+            }
+        }
+    }
+    if !methods.is_empty() && !methods.iter().any(|method| method.eq_ignore_ascii_case("OPTIONS")) {
+        methods.push("OPTIONS");
+    }
+    methods
+}
+
+/// The response for a request that no route accepts.
+///
+/// `OPTIONS` gets `204 No Content` with the allowed methods in `Allow`, a path
+/// that is only routed for other methods gets `405 Method Not Allowed` with the
+/// same header, and any other path `404 Not Found`.
+pub fn unrouted_response<'r>(routes: impl IntoIterator<Item = &'r Route>, request: &Request) -> HttpResponse {
+    let allowed = allowed_methods(routes, &request.raw_path);
+    if allowed.is_empty() {
+        return HttpResponse::problem(404, format!("no route for {}", request.path));
+    }
+    let allow = allowed.join(", ");
+    if request.method.eq_ignore_ascii_case("OPTIONS") {
+        return HttpResponse::no_content().with_header("Allow", allow);
+    }
+    HttpResponse::problem(405, format!("{} is not allowed for {}", request.method, request.path))
+        .with_header("Allow", allow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(method: &'static str, path: &'static str) -> Route {
+        Route {
+            method,
+            path,
+            etag: false,
+            cache_control: None,
+        }
+    }
+
+    #[test]
+    fn static_routes_win_over_earlier_templates() {
+        let routes = [route("GET", "/books/{id}"), route("GET", "/books/search")];
+        assert_eq!(route_index(&routes, "GET", "/books/search"), Some(1));
+        assert_eq!(route_index(&routes, "GET", "/books/7"), Some(0));
+    }
+
+    #[test]
+    fn parameters_match_a_single_segment() {
+        let routes = [route("GET", "/books/{id}")];
+        assert_eq!(route_index(&routes, "GET", "/books/1/reviews"), None);
+        assert_eq!(route_index(&routes, "GET", "/books/"), None);
+        assert_eq!(route_index(&routes, "GET", "/books"), None);
+    }
+
+    #[test]
+    fn routes_match_the_raw_path() {
+        let routes = [route("GET", "/files/{a}/{b}"), route("GET", "/files/{name}")];
+        assert_eq!(route_index(&routes, "GET", "/files/a%2Fb"), Some(1));
+        assert_eq!(routes[1].param("/files/a%2Fb", "name"), Some("a%2Fb"));
+        assert_eq!(route_index(&routes, "GET", "/f%69les/a/b"), Some(0));
+        let request = Request::new("GET", "/files/a%2Fb?x=1");
+        assert_eq!((request.path.as_str(), request.raw_path.as_str()), ("/files/a/b", "/files/a%2Fb"));
+    }
+
+    #[test]
+    fn head_falls_back_to_get_unless_claimed() {
+        let routes = [route("GET", "/books"), route("HEAD", "/books/{id}"), route("GET", "/books/{id}")];
+        assert_eq!(route_index(&routes, "HEAD", "/books"), Some(0));
+        assert_eq!(route_index(&routes, "HEAD", "/books/1"), Some(1));
+        assert_eq!(route_index(&routes, "POST", "/books"), None);
+    }
+
+    #[test]
+    fn param_reads_the_named_segment() {
+        let reviews = route("GET", "/books/{id}/reviews/{review}");
+        assert_eq!(reviews.param("/books/7/reviews/3", "id"), Some("7"));
+        assert_eq!(reviews.param("/books/7/reviews/3", "review"), Some("3"));
+        assert_eq!(reviews.param("/books/7/reviews/3", "isbn"), None);
+        assert_eq!(reviews.param("/books/7", "id"), None);
+        // Prefix and suffix overlap: `/a/` + `/a`.
+        let overlap = route("GET", "/a/{x}/a");
+        assert_eq!(overlap.param("/a/a", "x"), None);
+        assert_eq!(overlap.param("/a/b/a", "x"), Some("b"));
+    }
+
+    #[test]
+    fn allowed_methods_follow_the_route_table() {
+        let routes = [route("GET", "/books/{id}"), route("DELETE", "/books/{id}"), route("POST", "/books")];
+        assert_eq!(allowed_methods(&routes, "/books/1"), ["GET", "HEAD", "DELETE", "OPTIONS"]);
+        assert!(allowed_methods(&routes, "/authors").is_empty());
+    }
+}
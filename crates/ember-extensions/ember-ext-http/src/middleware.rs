@@ -0,0 +1,88 @@
+#![forbid(unsafe_code)]
+
+//! Middleware that wraps request handling.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use ember_ext_exceptions::EmberError;
+
+use crate::{HandlerFuture, HttpHandler, HttpResponse, Request};
+
+/// Future returned by [`Middleware::before`] and [`Middleware::after`].
+pub type MiddlewareFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, EmberError>> + Send + 'a>>;
+
+/// A hook that runs around every request handled by an `App`.
+///
+/// Implement [`before`](Middleware::before) and/or [`after`](Middleware::after)
+/// for the common cases, or override [`around`](Middleware::around) to take
+/// full control of calling the rest of the chain. Middleware runs in the order
+/// it was registered: the first one registered sees the request first and the
+/// response last.
+pub trait Middleware: Send + Sync {
+    /// Inspect or modify the request before the handler runs.
+    ///
+    /// Returning `Some(response)` short-circuits the chain: later middleware
+    /// and the handler are skipped, and `response` is returned to the client.
+    fn before<'a>(&'a self, request: &'a mut Request) -> MiddlewareFuture<'a, Option<HttpResponse>> {
+        let _ = request;
+        Box::pin(async { Ok(None) })
+    }
+
+    /// Inspect or modify the response after the handler runs.
+    ///
+    /// Not called when [`before`](Middleware::before) short-circuited.
+    fn after<'a>(&'a self, request: &'a Request, response: &'a mut HttpResponse) -> MiddlewareFuture<'a, ()> {
+        let _ = (request, response);
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Run this middleware around the rest of the chain.
+    ///
+    /// The default calls `before`, then `next`, then `after`.
+    fn around<'a>(&'a self, request: &'a mut Request, next: Next<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            if let Some(response) = self.before(request).await? {
+                return Ok(response);
+            }
+            let mut response = next.run(request).await?;
+            self.after(request, &mut response).await?;
+            Ok(response)
+        })
+    }
+}
+
+/// The remainder of a middleware chain, ending in the request handler.
+///
+/// `Next` is `Copy`, so a middleware may run the rest of the chain more than
+/// once, e.g. to look up the current state of a resource first.
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    handler: &'a (dyn HttpHandler + Send + Sync),
+}
+
+impl<'a> Next<'a> {
+    /// Build a chain that runs `middleware` in order, then `handler`.
+    pub fn new(middleware: &'a [Arc<dyn Middleware>], handler: &'a (dyn HttpHandler + Send + Sync)) -> Self {
+        Self { middleware, handler }
+    }
+
+    /// Pass the request to the next middleware, or to the handler at the end of the chain.
+    pub fn run<'b>(self, request: &'b mut Request) -> HandlerFuture<'b>
+    where
+        'a: 'b,
+    {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.around(
+                request,
+                Next {
+                    middleware: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.handle(request),
+        }
+    }
+}
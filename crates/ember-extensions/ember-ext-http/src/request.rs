@@ -0,0 +1,267 @@
+#![forbid(unsafe_code)]
+
+//! Incoming HTTP request types.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// An HTTP request passed through the Ember pipeline.
+#[derive(Debug, Default)]
+pub struct Request {
+    /// Request method, e.g. `GET`.
+    pub method: String,
+    /// Percent-decoded request path without the query string.
+    pub path: String,
+    /// Request path as sent, still percent-encoded. Routes match against it,
+    /// so an encoded `/` inside a segment never splits that segment.
+    pub raw_path: String,
+    /// Raw query string without the leading `?`, if present.
+    pub raw_query: Option<String>,
+    /// Decoded query parameters; repeated names keep every value in order.
+    pub query: HashMap<String, Vec<String>>,
+    /// Request headers.
+    pub headers: Headers,
+    /// Trailer fields sent after a chunked body. They are never merged into
+    /// `headers`, which a front proxy may have checked or rewritten.
+    pub trailers: Headers,
+    /// Address of the connected peer, if known.
+    pub remote_addr: Option<SocketAddr>,
+    /// Raw request body bytes.
+    pub body: Vec<u8>,
+    /// Typed values attached by the runtime or middleware.
+    pub extensions: Extensions,
+}
+
+impl Request {
+    /// Build a request from a method and a request target such as `/books?page=2`.
+    pub fn new(method: impl Into<String>, target: &str) -> Self {
+        let (path, raw_query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (target, None),
+        };
+        let query = raw_query.as_deref().map(parse_query).unwrap_or_default();
+        Self {
+            method: method.into(),
+            path: percent_decode(path, false),
+            raw_path: path.to_string(),
+            raw_query,
+            query,
+            ..Self::default()
+        }
+    }
+
+    /// Attach headers to the request.
+    pub fn with_headers(mut self, headers: Headers) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Attach trailer fields to the request.
+    pub fn with_trailers(mut self, trailers: Headers) -> Self {
+        self.trailers = trailers;
+        self
+    }
+
+    /// Attach a body to the request.
+    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// Attach the peer address to the request.
+    pub fn with_remote_addr(mut self, remote_addr: SocketAddr) -> Self {
+        self.remote_addr = Some(remote_addr);
+        self
+    }
+
+    /// First value of a query parameter.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .get(name)
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    }
+
+    /// First value of a header, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// First value of a trailer field, matched case-insensitively.
+    pub fn trailer(&self, name: &str) -> Option<&str> {
+        self.trailers.get(name)
+    }
+}
+
+/// Case-insensitive, multi-valued HTTP headers that keep insertion order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    /// Create an empty header set.
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// First value for a header name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All values for a header name, in the order they were added.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Return `true` if the header is present.
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Add a value, keeping any existing values for the same name.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Set a header, replacing any existing values for the same name.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    /// Remove every value for a header name.
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    /// Iterate over all header name/value pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Number of header values.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Return `true` if there are no headers.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// A type map for values attached to a request, such as a security context.
+#[derive(Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Create an empty extension map.
+    pub fn new() -> Self {
+        Self {
+            values: HashMap::new(),
+        }
+    }
+
+    /// Insert a value, returning the previous value of the same type.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.values
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast::<T>().ok())
+            .map(|previous| *previous)
+    }
+
+    /// Borrow the value of type `T`, if present.
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+    }
+
+    /// Mutably borrow the value of type `T`, if present.
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.values
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut::<T>())
+    }
+
+    /// Remove and return the value of type `T`, if present.
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.values
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast::<T>().ok())
+            .map(|value| *value)
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.values.len())
+            .finish()
+    }
+}
+
+/// Parse an `application/x-www-form-urlencoded` style query string.
+pub fn parse_query(query: &str) -> HashMap<String, Vec<String>> {
+    let mut params: HashMap<String, Vec<String>> = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        params
+            .entry(percent_decode(name, true))
+            .or_default()
+            .push(percent_decode(value, true));
+    }
+    params
+}
+
+/// Decode `%XX` escapes, and `+` as a space when `plus_as_space` is set.
+///
+/// Invalid escapes are kept verbatim and invalid UTF-8 is replaced.
+pub fn percent_decode(value: &str, plus_as_space: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'+' if plus_as_space => out.push(b' '),
+            b'%' if index + 2 < bytes.len() => {
+                match (from_hex(bytes[index + 1]), from_hex(bytes[index + 2])) {
+                    (Some(hi), Some(lo)) => {
+                        out.push(hi << 4 | lo);
+                        index += 2;
+                    }
+                    _ => out.push(b'%'),
+                }
+            }
+            byte => out.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn from_hex(value: u8) -> Option<u8> {
+    match value {
+        b'0'..=b'9' => Some(value - b'0'),
+        b'a'..=b'f' => Some(value - b'a' + 10),
+        b'A'..=b'F' => Some(value - b'A' + 10),
+        _ => None,
+    }
+}
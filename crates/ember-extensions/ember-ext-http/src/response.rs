@@ -0,0 +1,339 @@
+#![forbid(unsafe_code)]
+
+//! Outgoing HTTP response types.
+
+use std::fmt;
+use std::future::poll_fn;
+use std::pin::Pin;
+
+use ember_ext_exceptions::{EmberError, ProblemDetails};
+use futures_core::Stream;
+use serde::Serialize;
+
+use crate::format::BodyFormat;
+use crate::request::{Headers, Request};
+use crate::upgrade::Upgrade;
+use crate::Json;
+
+/// A response body produced incrementally by an async stream of byte chunks.
+///
+/// The server sends each chunk as soon as it is produced, using chunked
+/// transfer coding, or a `Content-Length` when the length is known up front.
+/// An `Err` item ends the response early and closes the connection, since the
+/// client can no longer be told the status.
+pub struct BodyStream {
+    // Boxed together so an `Option<BodyStream>` stays one pointer wide.
+    inner: Box<Chunks>,
+}
+
+struct Chunks {
+    stream: Pin<Box<dyn Stream<Item = Result<Vec<u8>, EmberError>> + Send>>,
+    content_length: Option<u64>,
+}
+
+impl BodyStream {
+    /// Wrap a stream of byte chunks.
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<Vec<u8>, EmberError>> + Send + 'static,
+    {
+        Self {
+            inner: Box::new(Chunks {
+                stream: Box::pin(stream),
+                content_length: None,
+            }),
+        }
+    }
+
+    /// Declare that the stream yields exactly `len` bytes, so the response is
+    /// sent with `Content-Length`. A stream that ends early or runs over is
+    /// treated like one that failed.
+    pub fn with_content_length(mut self, len: u64) -> Self {
+        self.inner.content_length = Some(len);
+        self
+    }
+
+    /// The length declared with [`with_content_length`](Self::with_content_length).
+    pub fn content_length(&self) -> Option<u64> {
+        self.inner.content_length
+    }
+
+    /// Wait for the next chunk; `None` once the stream is finished.
+    pub async fn next_chunk(&mut self) -> Option<Result<Vec<u8>, EmberError>> {
+        poll_fn(|cx| self.inner.stream.as_mut().poll_next(cx)).await
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyStream")
+            .field("content_length", &self.inner.content_length)
+            .finish_non_exhaustive()
+    }
+}
+
+/// HTTP response returned by Ember handlers.
+#[derive(Debug)]
+pub struct HttpResponse {
+    /// HTTP status code.
+    pub status: u16,
+    /// Content type header value, if the response has a body.
+    pub content_type: Option<String>,
+    /// Additional response headers; repeated names are sent as separate lines.
+    pub headers: Headers,
+    /// Response body bytes.
+    pub body: Vec<u8>,
+    /// Streaming body, sent in place of `body` when set.
+    pub stream: Option<BodyStream>,
+    /// Takes over the connection after a `101 Switching Protocols` response.
+    pub upgrade: Option<Upgrade>,
+}
+
+impl HttpResponse {
+    /// Create an empty response with the given status code.
+    pub fn empty(status: u16) -> Self {
+        Self {
+            status,
+            content_type: None,
+            headers: Headers::new(),
+            body: Vec::new(),
+            stream: None,
+            upgrade: None,
+        }
+    }
+
+    /// Create a plain text response.
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self::bytes(status, "text/plain", body.into().into_bytes())
+    }
+
+    /// Create a response with an explicit content type.
+    pub fn bytes(status: u16, content_type: impl Into<String>, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            content_type: Some(content_type.into()),
+            headers: Headers::new(),
+            body,
+            stream: None,
+            upgrade: None,
+        }
+    }
+
+    /// Create a response whose body is streamed from `stream`.
+    pub fn streaming(status: u16, content_type: impl Into<String>, stream: BodyStream) -> Self {
+        Self {
+            status,
+            content_type: Some(content_type.into()),
+            headers: Headers::new(),
+            body: Vec::new(),
+            stream: Some(stream),
+            upgrade: None,
+        }
+    }
+
+    /// Create a JSON response from a serializable value.
+    pub fn json<T: Serialize + ?Sized>(status: u16, value: &T) -> Result<Self, EmberError> {
+        let body = serde_json::to_vec(value)
+            .map_err(|err| EmberError::msg(format!("encode failed: {err}")))?;
+        Ok(Self::bytes(status, "application/json", body))
+    }
+
+    /// Create an `application/problem+json` response titled with the
+    /// status's reason phrase; an empty `detail` is left out.
+    pub fn problem(status: u16, detail: impl Into<String>) -> Self {
+        Self::from_problem(&ProblemDetails::new("about:blank", reason_phrase(status), status, detail, ""))
+    }
+
+    /// Create the `406 Not Acceptable` problem answered when `Accept` allows
+    /// none of the compiled-in [`BodyFormat`]s, listing the available types.
+    pub fn not_acceptable() -> Self {
+        let available: Vec<&str> = BodyFormat::ALL.iter().map(|format| format.media_type()).collect();
+        Self::problem(406, format!("not acceptable; available: {}", available.join(", ")))
+            .with_header("Vary", "Accept")
+    }
+
+    /// Create an `application/problem+json` response with the problem's status.
+    pub fn from_problem(problem: &ProblemDetails) -> Self {
+        // Only strings and a number, which always serialize.
+        let body = serde_json::to_vec(problem).unwrap_or_default();
+        Self::bytes(problem.status, "application/problem+json", body)
+    }
+
+    /// Create the problem response for a failed handler.
+    ///
+    /// The error's message becomes the `detail` of client errors. Server
+    /// errors only carry it when `expose_details` is set, since it may
+    /// describe internals such as queries or file paths.
+    pub fn from_error(err: &EmberError, expose_details: bool) -> Self {
+        if let EmberError::Problem(problem) = err {
+            return Self::from_problem(problem);
+        }
+        let status = err.status_code();
+        if status < 500 || expose_details {
+            Self::problem(status, err.to_string())
+        } else {
+            Self::problem(status, "")
+        }
+    }
+
+    /// Create a redirect to `location`.
+    ///
+    /// `status` should be one of the 3xx redirect codes, typically 301, 302,
+    /// 303, 307 or 308.
+    pub fn redirect(status: u16, location: impl Into<String>) -> Self {
+        Self::empty(status).with_header("Location", location)
+    }
+
+    /// Create a `101 Switching Protocols` response that hands the connection to `upgrade`.
+    pub fn switching_protocols(protocol: impl Into<String>, upgrade: Upgrade) -> Self {
+        let mut response = Self::empty(101).with_header("Upgrade", protocol);
+        response.upgrade = Some(upgrade);
+        response
+    }
+
+    /// Create a `204 No Content` response.
+    pub fn no_content() -> Self {
+        Self::empty(204)
+    }
+
+    /// Set a header, replacing any existing values for the same name.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Add a header value, keeping existing values (e.g. several `Set-Cookie`).
+    pub fn append_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Replace the content type.
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    /// The reason phrase for this response's status code.
+    pub fn reason_phrase(&self) -> &'static str {
+        reason_phrase(self.status)
+    }
+}
+
+/// Values a controller method may return from a route.
+///
+/// `request` is the request being answered, for types that negotiate their
+/// representation. `status` is the route's default success status: `201` for
+/// `POST` and `200` otherwise. Types that carry their own status, like
+/// [`HttpResponse`], ignore it.
+pub trait IntoResponse {
+    /// Convert into the response sent to the client.
+    fn into_response(self, request: &Request, status: u16) -> Result<HttpResponse, EmberError>;
+}
+
+impl IntoResponse for HttpResponse {
+    fn into_response(self, _request: &Request, _status: u16) -> Result<HttpResponse, EmberError> {
+        Ok(self)
+    }
+}
+
+/// Serialized in the [`BodyFormat`] the client's `Accept` header prefers, or
+/// [`HttpResponse::not_acceptable`] when it accepts none of the compiled-in
+/// formats. Controllers generated by `#[controller]` check `Accept` before
+/// running a handler that returns `Json<T>`, so this only applies to
+/// hand-written handlers.
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self, request: &Request, status: u16) -> Result<HttpResponse, EmberError> {
+        let Some(format) = BodyFormat::negotiate(request.header("accept")) else {
+            return Ok(HttpResponse::not_acceptable());
+        };
+        let response = HttpResponse::bytes(status, format.media_type(), format.serialize(&self.0)?);
+        if BodyFormat::ALL.len() > 1 {
+            return Ok(response.with_header("Vary", "Accept"));
+        }
+        Ok(response)
+    }
+}
+
+impl IntoResponse for ProblemDetails {
+    fn into_response(self, _request: &Request, _status: u16) -> Result<HttpResponse, EmberError> {
+        Ok(HttpResponse::from_problem(&self))
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for Result<T, EmberError> {
+    fn into_response(self, request: &Request, status: u16) -> Result<HttpResponse, EmberError> {
+        self?.into_response(request, status)
+    }
+}
+
+/// The registered reason phrase for an HTTP status code.
+///
+/// Covers every code in the IANA HTTP Status Code Registry. Unregistered codes
+/// return an empty string, which is a valid reason phrase on the wire.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        102 => "Processing",
+        103 => "Early Hints",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        203 => "Non-Authoritative Information",
+        204 => "No Content",
+        205 => "Reset Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        208 => "Already Reported",
+        226 => "IM Used",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        305 => "Use Proxy",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Content",
+        423 => "Locked",
+        424 => "Failed Dependency",
+        425 => "Too Early",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        506 => "Variant Also Negotiates",
+        507 => "Insufficient Storage",
+        508 => "Loop Detected",
+        510 => "Not Extended",
+        511 => "Network Authentication Required",
+        _ => "",
+    }
+}
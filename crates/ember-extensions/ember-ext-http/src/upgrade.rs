@@ -0,0 +1,112 @@
+#![forbid(unsafe_code)]
+
+//! Handing a connection over to another protocol after `101 Switching Protocols`.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Byte stream the upgraded connection runs over.
+trait UpgradedIo: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T> UpgradedIo for T where T: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+/// A connection taken over from the HTTP server.
+///
+/// Bytes the client sent right behind the upgrade request, which the server
+/// had already buffered, are read first.
+pub struct Upgraded {
+    io: Box<dyn UpgradedIo>,
+    buffered: Vec<u8>,
+    position: usize,
+}
+
+impl Upgraded {
+    /// Wrap a connection and the bytes already read past the upgrade request.
+    pub fn new<S>(io: S, buffered: Vec<u8>) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        Self {
+            io: Box::new(io),
+            buffered,
+            position: 0,
+        }
+    }
+}
+
+impl fmt::Debug for Upgraded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upgraded")
+            .field("buffered", &(self.buffered.len() - self.position))
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for Upgraded {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.position < this.buffered.len() {
+            let available = &this.buffered[this.position..];
+            let len = available.len().min(buf.remaining());
+            buf.put_slice(&available[..len]);
+            this.position += len;
+            if this.position == this.buffered.len() {
+                this.buffered = Vec::new();
+                this.position = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Upgraded {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// Boxed callback driving the upgraded protocol.
+type UpgradeCallback = Box<dyn FnOnce(Upgraded) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+/// Runs on the connection once a `101 Switching Protocols` response is sent.
+pub struct Upgrade {
+    callback: UpgradeCallback,
+}
+
+impl Upgrade {
+    /// Run `callback` with the connection after the response is written.
+    pub fn new<F, Fut>(callback: F) -> Self
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            callback: Box::new(move |upgraded| Box::pin(callback(upgraded))),
+        }
+    }
+
+    /// Hand the connection over and drive the new protocol to completion.
+    pub async fn run(self, upgraded: Upgraded) {
+        (self.callback)(upgraded).await;
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upgrade").finish_non_exhaustive()
+    }
+}
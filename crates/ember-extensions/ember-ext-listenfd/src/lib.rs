@@ -0,0 +1,98 @@
+#![deny(unsafe_code)]
+
+//! Socket-activation descriptors passed in by a service manager.
+//!
+//! This is the one place in the workspace that adopts raw descriptors, so the
+//! crates that serve them can keep `#![forbid(unsafe_code)]`.
+
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::os::fd::OwnedFd;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// First descriptor passed by the service manager.
+pub const LISTEN_FDS_START: i32 = 3;
+
+/// Set once the inherited descriptors have been adopted.
+#[cfg(unix)]
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Take ownership of the sockets passed through `LISTEN_FDS`.
+///
+/// `LISTEN_PID` must name this process. Each descriptor must be an open
+/// socket; it is marked close-on-exec so child processes do not inherit it.
+/// The environment is only read, never changed: children that see the
+/// variables ignore them because `LISTEN_PID` does not name them. The
+/// descriptors can be taken once per process; later calls fail.
+#[cfg(unix)]
+pub fn take() -> io::Result<Vec<OwnedFd>> {
+    let pid = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.trim().parse::<u32>().ok());
+    if pid != Some(std::process::id()) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "LISTEN_PID is not this process"));
+    }
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.trim().parse::<i32>().ok())
+        .filter(|count| (1..=i32::MAX - LISTEN_FDS_START).contains(count))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "LISTEN_FDS is not set"))?;
+    let fds = LISTEN_FDS_START..LISTEN_FDS_START + count;
+    for fd in fds.clone() {
+        check_socket(fd)?;
+    }
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "LISTEN_FDS were already taken"));
+    }
+    fds.map(adopt).collect()
+}
+
+/// Fail unless `fd` is an open socket.
+#[cfg(unix)]
+#[allow(unsafe_code)]
+fn check_socket(fd: i32) -> io::Result<()> {
+    // SAFETY: `fstat` only writes the zeroed `stat` it is given, and reports
+    // EBADF for a descriptor that is not open.
+    let mode = unsafe {
+        let mut stat: libc::stat = std::mem::zeroed();
+        if libc::fstat(fd, &mut stat) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.st_mode
+    };
+    if mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("descriptor {fd} is not a socket")));
+    }
+    Ok(())
+}
+
+/// Own `fd` and mark it close-on-exec.
+#[cfg(unix)]
+#[allow(unsafe_code)]
+fn adopt(fd: i32) -> io::Result<OwnedFd> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    // SAFETY: `fd` is open (checked by `check_socket`), was handed to this
+    // process by the service manager as LISTEN_PID confirms, and `TAKEN`
+    // guarantees it is adopted at most once, so nothing else owns it.
+    let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+    // SAFETY: `fcntl(F_SETFD)` on a descriptor we own has no memory effects.
+    if unsafe { libc::fcntl(owned.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(owned)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_sockets_are_accepted() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(check_socket(std::os::fd::AsRawFd::as_raw_fd(&file)).is_err());
+        assert!(check_socket(std::os::fd::AsRawFd::as_raw_fd(&listener)).is_ok());
+        assert!(check_socket(-1).is_err());
+    }
+}
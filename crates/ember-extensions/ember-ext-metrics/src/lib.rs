@@ -7,13 +7,44 @@ use std::sync::{atomic::AtomicU64, atomic::Ordering, Arc, Mutex, OnceLock};
 
 use serde::Serialize;
 
-use ember_core::App;
+use ember_core::{App, HttpResponse, Middleware, MiddlewareFuture, Request};
 
 /// Install metrics instrumentation for an Ember app.
 ///
-/// This initializes the global metrics registry for use by the service.
-pub fn install_metrics(_app: &mut App) {
-    let _ = global_registry();
+/// This initializes the global metrics registry, registers a
+/// [`MetricsMiddleware`] that counts requests and responses, and counts load
+/// shed by the server's concurrency limits in `http.requests.shed`.
+pub fn install_metrics(app: &mut App) {
+    let shed = MetricsHandle::global().counter("http.requests.shed");
+    app.on_load_shed(move || shed.inc());
+    app.register_middleware(MetricsMiddleware::new(MetricsHandle::global()));
+}
+
+/// Counts requests in `http.requests.total` and responses by status class,
+/// e.g. `http.responses.2xx`.
+#[derive(Debug, Clone)]
+pub struct MetricsMiddleware {
+    metrics: MetricsHandle,
+}
+
+impl MetricsMiddleware {
+    /// Record into the given metrics handle.
+    pub fn new(metrics: MetricsHandle) -> Self {
+        Self { metrics }
+    }
+}
+
+impl Middleware for MetricsMiddleware {
+    fn before<'a>(&'a self, _request: &'a mut Request) -> MiddlewareFuture<'a, Option<HttpResponse>> {
+        self.metrics.counter("http.requests.total").inc();
+        Box::pin(async { Ok(None) })
+    }
+
+    fn after<'a>(&'a self, _request: &'a Request, response: &'a mut HttpResponse) -> MiddlewareFuture<'a, ()> {
+        let class = response.status / 100;
+        self.metrics.counter(format!("http.responses.{class}xx")).inc();
+        Box::pin(async { Ok(()) })
+    }
 }
 
 /// Global metrics registry initializer.
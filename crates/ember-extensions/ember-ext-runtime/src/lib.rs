@@ -2,8 +2,13 @@
 
 //! Ember runtime facade types.
 
+use std::fmt;
+use std::sync::Arc;
+
 use ember_ext_exceptions::EmberError;
-use ember_ext_http::{Route, Router};
+use ember_ext_http::{
+    route_index, unrouted_response, HandlerFuture, HttpHandler, HttpResponse, Middleware, Next, Request, Route, Router,
+};
 
 /// Metadata provided by controller macros.
 pub trait ControllerMetadata {
@@ -11,10 +16,72 @@ pub trait ControllerMetadata {
     fn routes() -> &'static [Route];
 }
 
+/// Called for every connection or request shed by the server's concurrency limits.
+pub type LoadShedHook = Arc<dyn Fn() + Send + Sync>;
+
+/// A controller registered with an [`App`].
+type SharedHandler = Arc<dyn HttpHandler + Send + Sync>;
+
+/// A route in the dispatch table and the controller that serves it.
+struct DispatchEntry {
+    route: Route,
+    controller: usize,
+    controller_name: &'static str,
+}
+
+/// Routes from every registered controller and the controllers that serve them.
+#[derive(Default)]
+struct DispatchTable {
+    controllers: Vec<SharedHandler>,
+    entries: Vec<DispatchEntry>,
+    expose_error_details: bool,
+}
+
+impl DispatchTable {
+    /// Find the controller for a request, preferring static routes over
+    /// templated ones and serving `HEAD` from `GET` routes.
+    fn find_controller(&self, method: &str, path: &str) -> Option<&SharedHandler> {
+        route_index(self.entries.iter().map(|entry| &entry.route), method, path)
+            .map(|index| &self.controllers[self.entries[index].controller])
+    }
+
+    /// The problem response for `err`, logging server errors.
+    fn error_response(&self, request: &Request, err: &EmberError) -> HttpResponse {
+        if err.status_code() >= 500 {
+            tracing::error!(error = %err, method = %request.method, path = %request.path, "handler failed");
+        }
+        HttpResponse::from_error(err, self.expose_error_details)
+    }
+}
+
+impl HttpHandler for DispatchTable {
+    /// Route the request; handler errors become problem responses so that
+    /// middleware always sees a response.
+    ///
+    /// Requests no route accepts get `405` with `Allow`, `204` for `OPTIONS`,
+    /// or `404`.
+    fn handle<'a>(&'a self, request: &'a Request) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let response = match self.find_controller(&request.method, &request.raw_path) {
+                Some(controller) => controller.handle(request).await,
+                None => Ok(unrouted_response(self.entries.iter().map(|entry| &entry.route), request)),
+            };
+            Ok(response.unwrap_or_else(|err| self.error_response(request, &err)))
+        })
+    }
+}
+
 /// An Ember application builder.
-#[derive(Debug, Default)]
+///
+/// `App` collects the routes of every registered controller into one
+/// dispatch table and runs each request through the registered middleware
+/// before forwarding it to the controller that declared the matching route.
+#[derive(Default)]
 pub struct App {
     router: Router,
+    dispatch: DispatchTable,
+    middleware: Vec<Arc<dyn Middleware>>,
+    load_shed: Option<LoadShedHook>,
 }
 
 impl App {
@@ -22,20 +89,58 @@ impl App {
     pub fn new() -> Self {
         Self {
             router: Router::new(),
+            dispatch: DispatchTable::default(),
+            middleware: Vec::new(),
+            load_shed: None,
         }
     }
 
-    /// Register a controller with the application.
+    /// Register a controller and add its routes to the dispatch table.
     ///
-    /// This is a placeholder that does not yet persist metadata.
-    pub fn register_controller<T: ControllerMetadata>(&mut self, _controller: T) -> &mut Self {
-        for route in T::routes() {
-            self.router.register(route.method, route.path);
+    /// Fails if any of the controller's routes claims the same method and
+    /// path as a route that is already registered.
+    pub fn register_controller<T>(&mut self, controller: T) -> Result<&mut Self, EmberError>
+    where
+        T: ControllerMetadata + HttpHandler + Send + Sync + 'static,
+    {
+        let controller_name = std::any::type_name::<T>();
+        let routes = T::routes();
+        for (position, route) in routes.iter().enumerate() {
+            let existing = self
+                .dispatch
+                .entries
+                .iter()
+                .find(|entry| entry.route.overlaps(route));
+            if let Some(existing) = existing {
+                return Err(EmberError::msg(format!(
+                    "duplicate route {} {}: declared by {} and {}",
+                    route.method, route.path, existing.controller_name, controller_name
+                )));
+            }
+            if routes[..position].iter().any(|other| other.overlaps(route)) {
+                return Err(EmberError::msg(format!(
+                    "duplicate route {} {}: declared twice by {}",
+                    route.method, route.path, controller_name
+                )));
+            }
         }
-        self
+
+        let index = self.dispatch.controllers.len();
+        self.dispatch.controllers.push(Arc::new(controller));
+        for route in routes {
+            self.router.register_route(route.clone());
+            self.dispatch.entries.push(DispatchEntry {
+                route: route.clone(),
+                controller: index,
+                controller_name,
+            });
+        }
+        Ok(self)
     }
 
     /// Register a route with the application.
+    ///
+    /// The route is listed in [`App::routes`] but has no handler attached.
     pub fn register_route(&mut self, method: &'static str, path: &'static str) -> &mut Self {
         self.router.register(method, path);
         self
@@ -46,14 +151,84 @@ impl App {
         self.router.routes()
     }
 
+    /// Add a middleware to the end of the chain.
+    ///
+    /// Middleware runs in registration order, so the first one registered
+    /// sees the request first and the response last.
+    pub fn register_middleware<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Add a middleware to the front of the chain, ahead of everything registered so far.
+    ///
+    /// Useful for middleware that must see requests before authentication,
+    /// such as CORS preflight handling.
+    pub fn register_middleware_first<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Self {
+        self.middleware.insert(0, Arc::new(middleware));
+        self
+    }
+
+    /// Include the messages of server errors in problem responses.
+    ///
+    /// Off by default, so a `500` says nothing about its cause; the
+    /// `run_with_*` functions turn it on for the `dev` profile.
+    pub fn expose_error_details(&mut self, expose: bool) -> &mut Self {
+        self.dispatch.expose_error_details = expose;
+        self
+    }
+
+    /// Run `hook` whenever the server sheds a connection or request because it
+    /// is at its concurrency limits; hooks added earlier keep running.
+    pub fn on_load_shed(&mut self, hook: impl Fn() + Send + Sync + 'static) -> &mut Self {
+        self.load_shed = Some(match self.load_shed.take() {
+            Some(previous) => Arc::new(move || {
+                previous();
+                hook();
+            }),
+            None => Arc::new(hook),
+        });
+        self
+    }
+
+    /// The hooks added with [`App::on_load_shed`], if any.
+    pub fn load_shed_hook(&self) -> Option<LoadShedHook> {
+        self.load_shed.clone()
+    }
+
+    /// The problem response for an error that escaped the middleware chain.
+    pub fn error_response(&self, request: &Request, err: &EmberError) -> HttpResponse {
+        self.dispatch.error_response(request, err)
+    }
+
+    /// Run a request through the middleware chain and the matching controller.
+    pub fn handle<'a>(&'a self, request: &'a mut Request) -> HandlerFuture<'a> {
+        Next::new(&self.middleware, &self.dispatch).run(request)
+    }
+
     /// Run the application.
-    pub fn run(self) -> Result<(), EmberError> {
+    pub fn run(&self) -> Result<(), EmberError> {
         let routes = self.router.routes().len();
         if routes > 0 {
-            tracing::info!(routes, "ember-> app started");
+            tracing::info!(
+                routes,
+                controllers = self.dispatch.controllers.len(),
+                middleware = self.middleware.len(),
+                "ember-> app started"
+            );
         } else {
             tracing::info!("ember-> app started");
         }
         Ok(())
     }
 }
+
+impl fmt::Debug for App {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("App")
+            .field("routes", &self.router.routes())
+            .field("controllers", &self.dispatch.controllers.len())
+            .field("middleware", &self.middleware.len())
+            .finish()
+    }
+}
@@ -3,25 +3,19 @@
 //! Procedural macros for Ember.
 
 use proc_macro::TokenStream;
-use quote::{format_ident, quote};
+use quote::quote;
 use syn::{spanned::Spanned, DeriveInput, FnArg, ItemImpl, ItemStruct, LitStr, Pat, Path, Token, Type};
 
-fn parse_route_attr(args: TokenStream, macro_name: &str) -> Result<LitStr, TokenStream> {
-    match syn::parse2::<LitStr>(args.into()) {
-        Ok(lit) => {
-            if lit.value().is_empty() {
-                let error = syn::Error::new(lit.span(), "route path must not be empty");
-                Err(error.to_compile_error().into())
-            } else {
-                Ok(lit)
-            }
-        }
-        Err(err) => {
-            let message = format!("{} expects a single string literal path", macro_name);
-            let error = syn::Error::new(err.span(), message);
-            Err(error.to_compile_error().into())
-        }
+fn parse_route_attr(args: TokenStream, macro_name: &str) -> Result<RouteArgs, TokenStream> {
+    let args = syn::parse2::<RouteArgs>(args.into()).map_err(|err| {
+        let message = format!("{}: {}", macro_name, err);
+        TokenStream::from(syn::Error::new(err.span(), message).to_compile_error())
+    })?;
+    if args.etag && macro_name != "#[get]" {
+        let message = format!("{} does not support `etag`; tag the #[get] route for the path instead", macro_name);
+        return Err(syn::Error::new(args.path.span(), message).to_compile_error().into());
     }
+    Ok(args)
 }
 
 /// Marks a controller type or impl block.
@@ -44,7 +38,7 @@ pub fn controller(_args: TokenStream, input: TokenStream) -> TokenStream {
                 let Some(ident) = ident else { continue; };
 
                 let method_str = match ident.as_str() {
-                    "get" => "GET",
+                    "get" | "websocket" => "GET",
                     "post" => "POST",
                     "put" => "PUT",
                     "patch" => "PATCH",
@@ -54,12 +48,20 @@ pub fn controller(_args: TokenStream, input: TokenStream) -> TokenStream {
                     _ => continue,
                 };
 
-                let path_lit = match attr.parse_args::<LitStr>() {
-                    Ok(lit) => lit,
-                    Err(err) => return err.to_compile_error().into(),
+                let (route, websocket) = if ident == "websocket" {
+                    match attr.parse_args::<WebSocketArgs>() {
+                        Ok(args) => (RouteArgs::plain(args.path), Some(args.max_message_bytes)),
+                        Err(err) => return err.to_compile_error().into(),
+                    }
+                } else {
+                    match attr.parse_args::<RouteArgs>() {
+                        Ok(args) => (args, None),
+                        Err(err) => return err.to_compile_error().into(),
+                    }
                 };
+                let path_lit = route.path.clone();
 
-                routes.push((method_str, path_lit.clone()));
+                routes.push((method_str, route));
 
                 let mut args = Vec::new();
                 for input in &method.sig.inputs {
@@ -68,13 +70,28 @@ pub fn controller(_args: TokenStream, input: TokenStream) -> TokenStream {
                     args.push((ident.ident.clone(), (*pat.ty).clone()));
                 }
 
-                handlers.push((method_str.to_string(), path_lit, method.sig.ident.clone(), args));
+                handlers.push(HandlerSpec {
+                    method: method_str.to_string(),
+                    path: path_lit,
+                    fn_ident: method.sig.ident.clone(),
+                    args,
+                    is_async: method.sig.asyncness.is_some(),
+                    negotiates: returns_json(&method.sig.output),
+                    websocket,
+                });
             }
         }
     }
 
-    let route_entries = routes.iter().map(|(method, path)| {
-        quote! { ember_core::Route { method: #method, path: #path } }
+    let route_entries = routes.iter().map(|(method, route)| {
+        let RouteArgs { path, etag, cache_control } = route;
+        let cache_control = match cache_control {
+            Some(directives) => quote! { Some(#directives) },
+            None => quote! { None },
+        };
+        quote! {
+            ember_core::Route { method: #method, path: #path, etag: #etag, cache_control: #cache_control }
+        }
     });
 
     let handler_impl = build_http_handler(self_ty, &handlers);
@@ -93,63 +110,84 @@ pub fn controller(_args: TokenStream, input: TokenStream) -> TokenStream {
     expanded.into()
 }
 
-fn to_snake_case(s: &str) -> String {
-    let mut result = String::new();
-    for (i, ch) in s.chars().enumerate() {
-        if ch.is_uppercase() && i > 0 {
-            result.push('_');
-        }
-        result.push(ch.to_ascii_lowercase());
-    }
-    result
-}
-
-fn build_http_handler(
-    self_ty: &std::boxed::Box<syn::Type>,
-    handlers: &[(String, LitStr, syn::Ident, Vec<(syn::Ident, syn::Type)>)],
-) -> proc_macro2::TokenStream {
-    let helper_prefix = match self_ty.as_ref() {
-        syn::Type::Path(path) => path
-            .path
-            .segments
-            .last()
-            .map(|seg| {
-                let name = seg.ident.to_string();
-                let snake = to_snake_case(&name);
-                format_ident!("{}", snake)
-            })
-            .unwrap_or_else(|| format_ident!("controller")),
-        _ => format_ident!("controller"),
-    };
-
-    let decode_fn = format_ident!("__ember_url_decode_{}", helper_prefix);
-    let from_hex_fn = format_ident!("__ember_from_hex_{}", helper_prefix);
-    let query_fn = format_ident!("__ember_query_param_{}", helper_prefix);
+/// A route method collected from a `#[controller]` impl block.
+struct HandlerSpec {
+    /// HTTP method, e.g. `GET`.
+    method: String,
+    /// Route path literal.
+    path: LitStr,
+    /// Name of the controller method.
+    fn_ident: syn::Ident,
+    /// Typed arguments after `self`.
+    args: Vec<(syn::Ident, syn::Type)>,
+    /// Whether the controller method is `async fn`.
+    is_async: bool,
+    /// Whether the method returns `Json<T>`, whose format is negotiated from `Accept`.
+    negotiates: bool,
+    /// Set for `#[websocket]` routes, with the optional `max_message_bytes` limit.
+    websocket: Option<Option<syn::LitInt>>,
+}
 
+fn build_http_handler(self_ty: &std::boxed::Box<syn::Type>, handlers: &[HandlerSpec]) -> proc_macro2::TokenStream {
     let mut arms = Vec::new();
-    for (method_str, path_lit, fn_ident, args) in handlers {
-        let method_lit = LitStr::new(method_str, path_lit.span());
+    for (index, handler) in handlers.iter().enumerate() {
+        let HandlerSpec {
+            method: method_str,
+            path: path_lit,
+            fn_ident,
+            args,
+            is_async,
+            negotiates,
+            websocket,
+        } = handler;
         let path_value = path_lit.value();
-
-        let (prefix, param_name, suffix) = parse_path_template(&path_value);
+        let index = proc_macro2::Literal::usize_unsuffixed(index);
+        let params = path_params(&path_value);
 
         let status = if method_str == "POST" { 201u16 } else { 200u16 };
 
         let mut arg_builders = Vec::new();
         let mut body_arg: Option<(syn::Ident, syn::Type)> = None;
+        let mut socket_arg: Option<&syn::Ident> = None;
 
         for (arg_ident, arg_ty) in args {
             let arg_name = arg_ident.to_string();
-            if let Some(param) = &param_name {
-                if param == &arg_name {
-                    let parse_expr = build_parse_expr(arg_ident, arg_ty, quote! { param_value });
-                    arg_builders.push(parse_expr);
+            if websocket.is_some() {
+                if is_websocket_type(arg_ty) {
+                    socket_arg = Some(arg_ident);
+                    continue;
+                }
+                if request_arg_kind(arg_ty).is_some() {
+                    let err = syn::Error::new(
+                        arg_ty.span(),
+                        "#[websocket] handlers run after the upgrade and cannot take the request; \
+                         use `WebSocket::security_context` or path and query parameters",
+                    );
+                    return err.to_compile_error();
+                }
+            }
+            match request_arg_kind(arg_ty) {
+                Some(true) => {
+                    arg_builders.push(quote! { let #arg_ident: #arg_ty = request; });
                     continue;
                 }
+                Some(false) => {
+                    let err = syn::Error::new(arg_ty.span(), "take the request by reference: `&Request`");
+                    return err.to_compile_error();
+                }
+                None => {}
+            }
+
+            if params.contains(&arg_name) {
+                let source = quote! {
+                    ember_core::percent_decode(routes[#index].param(request.raw_path.as_str(), #arg_name).unwrap_or_default(), false)
+                };
+                arg_builders.push(build_parse_expr(arg_ident, arg_ty, source));
+                continue;
             }
 
             if method_str == "GET" {
-                let parse_expr = build_query_expr(arg_ident, arg_ty, &query_fn);
+                let parse_expr = build_query_expr(arg_ident, arg_ty);
                 arg_builders.push(parse_expr);
                 continue;
             }
@@ -164,49 +202,69 @@ fn build_http_handler(
         }
 
         if let Some((arg_ident, arg_ty)) = body_arg {
+            let extract = match body_type_name(&arg_ty).as_deref() {
+                Some("Form") => quote! { <#arg_ty>::from_request(request) },
+                Some("Multipart") => quote! { ember_core::Multipart::from_request(request).await },
+                _ => quote! { ember_core::decode_body::<#arg_ty>(request) },
+            };
             let parse_expr = quote! {
-                let #arg_ident: #arg_ty = ::serde_json::from_slice(body)
-                    .map_err(|err| ember_core::EmberError::msg(format!("invalid JSON: {err}")))?;
+                let #arg_ident: #arg_ty = match #extract {
+                    Ok(value) => value,
+                    Err(rejection) => return Ok(rejection),
+                };
             };
             arg_builders.push(parse_expr);
         }
 
         let call_args = args.iter().map(|(ident, _)| ident);
-        let call = quote! {
-            let ember_core::Json(payload) = self.#fn_ident(#(#call_args),*);
-            let body = ::serde_json::to_vec(&payload)
-                .map_err(|err| ember_core::EmberError::msg(format!("encode failed: {err}")))?;
-            Ok(ember_core::HttpResponse {
-                status: #status,
-                content_type: "application/json",
-                body,
-            })
+        let invoke = if *is_async {
+            quote! { self.#fn_ident(#(#call_args),*).await }
+        } else {
+            quote! { self.#fn_ident(#(#call_args),*) }
         };
-
-        let arm = if param_name.is_some() {
-            let prefix_lit = LitStr::new(&prefix, path_lit.span());
-            let suffix_lit = LitStr::new(&suffix, path_lit.span());
-            let guard = if suffix.is_empty() {
-                quote! { path.starts_with(#prefix_lit) }
-            } else {
-                quote! { path.starts_with(#prefix_lit) && path.ends_with(#suffix_lit) }
-            };
-            quote! {
-                (#method_lit, path) if #guard => {
-                    let start = #prefix_lit.len();
-                    let end = path.len().saturating_sub(#suffix_lit.len());
-                    let param_value = &path[start..end];
-                    #(#arg_builders)*
-                    #call
+        let call = match websocket {
+            Some(max_message_bytes) => {
+                let Some(socket_ident) = socket_arg else {
+                    let err = syn::Error::new(fn_ident.span(), "#[websocket] handlers take a `WebSocket` argument");
+                    return err.to_compile_error();
+                };
+                let call_args = args.iter().map(|(ident, _)| ident);
+                let session = if *is_async {
+                    quote! { controller.#fn_ident(#(#call_args),*).await }
+                } else {
+                    quote! { controller.#fn_ident(#(#call_args),*) }
+                };
+                let limit = max_message_bytes
+                    .as_ref()
+                    .map(|limit| quote! { .with_max_message_bytes(#limit) });
+                quote! {
+                    let controller = ::std::clone::Clone::clone(self);
+                    Ok(match ember_core::WebSocketUpgrade::from_request(request) {
+                        Ok(upgrade) => upgrade #limit .on_upgrade(move |#socket_ident| async move { #session }),
+                        Err(rejection) => rejection,
+                    })
                 }
             }
-        } else {
+            None => quote! {
+                ember_core::IntoResponse::into_response(#invoke, __ember_request, #status)
+            },
+        };
+
+        // Refuse unacceptable requests before the handler has any side effects.
+        let negotiate = negotiates.then(|| {
             quote! {
-                (#method_lit, #path_lit) => {
-                    #(#arg_builders)*
-                    #call
+                if ember_core::BodyFormat::negotiate(request.header("accept")).is_none() {
+                    return Ok(ember_core::HttpResponse::not_acceptable());
                 }
             }
+        });
+        let arm = quote! {
+            Some(#index) => {
+                let __ember_request = request;
+                #negotiate
+                #(#arg_builders)*
+                #call
+            }
         };
 
         arms.push(arm);
@@ -214,70 +272,168 @@ fn build_http_handler(
 
     quote! {
         impl ember_core::HttpHandler for #self_ty {
-            fn handle(&self, method: &str, path: &str, body: &[u8]) -> Result<ember_core::HttpResponse, ember_core::EmberError> {
-                let (path_only, query) = match path.split_once('?') {
-                    Some((p, q)) => (p, Some(q)),
-                    None => (path, None),
-                };
-                match (method, path_only) {
-                    #(#arms,)*
-                    _ => Ok(ember_core::HttpResponse::text(404, "not found")),
-                }
+            fn handle<'a>(&'a self, request: &'a ember_core::Request) -> ember_core::HandlerFuture<'a> {
+                Box::pin(async move {
+                    let routes = <Self as ember_ext_runtime::ControllerMetadata>::routes();
+                    match ember_core::route_index(routes, request.method.as_str(), request.raw_path.as_str()) {
+                        #(#arms,)*
+                        _ => Ok(ember_core::unrouted_response(routes, request)),
+                    }
+                })
             }
         }
+    }
+}
+
+/// Classify a handler argument that receives the request itself.
+///
+/// Returns `Some(true)` for `&Request`, `Some(false)` for `Request` taken by
+/// value and `None` for any other type.
+fn request_arg_kind(ty: &Type) -> Option<bool> {
+    let (inner, by_ref) = match ty {
+        Type::Reference(reference) if reference.mutability.is_none() => (&*reference.elem, true),
+        other => (other, false),
+    };
+    match inner {
+        Type::Path(path)
+            if path.qself.is_none()
+                && path.path.segments.last().is_some_and(|seg| seg.ident == "Request") =>
+        {
+            Some(by_ref)
+        }
+        _ => None,
+    }
+}
+
+/// Return `true` for the `WebSocket` argument of a `#[websocket]` handler.
+fn is_websocket_type(ty: &Type) -> bool {
+    matches!(ty, Type::Path(path)
+        if path.qself.is_none() && path.path.segments.last().is_some_and(|seg| seg.ident == "WebSocket"))
+}
+
+/// Return `true` if a handler returns `Json<T>`, directly or as the `Ok` type
+/// of a `Result`.
+fn returns_json(output: &syn::ReturnType) -> bool {
+    let syn::ReturnType::Type(_, ty) = output else {
+        return false;
+    };
+    let Type::Path(path) = &**ty else {
+        return false;
+    };
+    let Some(last) = path.path.segments.last() else {
+        return false;
+    };
+    if last.ident == "Json" {
+        return true;
+    }
+    match &last.arguments {
+        syn::PathArguments::AngleBracketed(generics) if last.ident == "Result" => matches!(
+            generics.args.first(),
+            Some(syn::GenericArgument::Type(ok)) if body_type_name(ok).as_deref() == Some("Json")
+        ),
+        _ => false,
+    }
+}
+
+/// Name of the last path segment of a body argument's type, e.g. `Form` for `Form<Login>`.
+fn body_type_name(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(path) if path.qself.is_none() => path.path.segments.last().map(|seg| seg.ident.to_string()),
+        _ => None,
+    }
+}
+
+/// Arguments of `#[get("/path", etag, cache_control = "...")]` and the other
+/// method attributes; both options are optional.
+struct RouteArgs {
+    path: LitStr,
+    etag: bool,
+    cache_control: Option<LitStr>,
+}
 
-        fn #query_fn(query: Option<&str>, name: &str) -> Option<String> {
-            let query = query?;
-            let target = format!("{}=", name);
-            query
-                .split('&')
-                .find(|pair| pair.starts_with(&target))
-                .and_then(|pair| pair.split_once('=').map(|(_, value)| #decode_fn(value)))
+impl RouteArgs {
+    fn plain(path: LitStr) -> Self {
+        Self {
+            path,
+            etag: false,
+            cache_control: None,
         }
+    }
+}
 
-        fn #decode_fn(value: &str) -> String {
-            let mut out = String::with_capacity(value.len());
-            let mut chars = value.as_bytes().iter().copied();
-            while let Some(ch) = chars.next() {
-                match ch {
-                    b'+' => out.push(' '),
-                    b'%' => {
-                        let hi = chars.next();
-                        let lo = chars.next();
-                        if let (Some(hi), Some(lo)) = (hi, lo) {
-                            if let (Some(hi), Some(lo)) = (#from_hex_fn(hi), #from_hex_fn(lo)) {
-                                out.push((hi << 4 | lo) as char);
-                            }
-                        }
-                    }
-                    _ => out.push(ch as char),
+impl syn::parse::Parse for RouteArgs {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let path: LitStr = input
+            .parse()
+            .map_err(|err| syn::Error::new(err.span(), "expected a string literal path"))?;
+        if path.value().is_empty() {
+            return Err(syn::Error::new(path.span(), "route path must not be empty"));
+        }
+        let mut args = Self::plain(path);
+        while input.peek(Token![,]) {
+            let _comma: Token![,] = input.parse()?;
+            if input.is_empty() {
+                break;
+            }
+            let key: Path = input.parse()?;
+            if key.is_ident("etag") {
+                args.etag = true;
+            } else if key.is_ident("cache_control") {
+                let _eq: Token![=] = input.parse()?;
+                let directives: LitStr = input.parse()?;
+                if directives.value().trim().is_empty() {
+                    return Err(syn::Error::new(directives.span(), "cache_control must not be empty"));
                 }
+                args.cache_control = Some(directives);
+            } else {
+                return Err(syn::Error::new(key.span(), "expected `etag` or `cache_control = \"...\"`"));
             }
-            out
         }
-
-        fn #from_hex_fn(value: u8) -> Option<u8> {
-            match value {
-                b'0'..=b'9' => Some(value - b'0'),
-                b'a'..=b'f' => Some(value - b'a' + 10),
-                b'A'..=b'F' => Some(value - b'A' + 10),
-                _ => None,
-            }
+        if !input.is_empty() {
+            return Err(input.error("expected `,`"));
         }
+        Ok(args)
     }
 }
 
-fn parse_path_template(path: &str) -> (String, Option<String>, String) {
-    if let Some(start) = path.find('{') {
-        if let Some(end) = path[start + 1..].find('}') {
-            let end = start + 1 + end;
-            let prefix = path[..start].to_string();
-            let param = path[start + 1..end].to_string();
-            let suffix = path[end + 1..].to_string();
-            return (prefix, Some(param), suffix);
+/// Arguments of `#[websocket("/path", max_message_bytes = N)]`.
+struct WebSocketArgs {
+    path: LitStr,
+    max_message_bytes: Option<syn::LitInt>,
+}
+
+impl syn::parse::Parse for WebSocketArgs {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let path: LitStr = input.parse()?;
+        if path.value().is_empty() {
+            return Err(syn::Error::new(path.span(), "route path must not be empty"));
         }
+        let mut max_message_bytes = None;
+        while input.peek(Token![,]) {
+            let _comma: Token![,] = input.parse()?;
+            if input.is_empty() {
+                break;
+            }
+            let key: Path = input.parse()?;
+            let _eq: Token![=] = input.parse()?;
+            if !key.is_ident("max_message_bytes") {
+                return Err(syn::Error::new(key.span(), "expected max_message_bytes = <bytes>"));
+            }
+            max_message_bytes = Some(input.parse()?);
+        }
+        if !input.is_empty() {
+            return Err(input.error("expected `,`"));
+        }
+        Ok(Self { path, max_message_bytes })
     }
-    (path.to_string(), None, String::new())
+}
+
+/// Names of the `{name}` segments of a route path.
+fn path_params(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| segment.len() > 2 && segment.starts_with('{') && segment.ends_with('}'))
+        .map(|segment| segment[1..segment.len() - 1].to_string())
+        .collect()
 }
 
 fn is_string_type(ty: &Type) -> bool {
@@ -290,44 +446,52 @@ fn is_string_type(ty: &Type) -> bool {
 }
 
 fn build_parse_expr(ident: &syn::Ident, ty: &Type, source: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    let invalid = format!("invalid path parameter `{ident}`");
     if is_string_type(ty) {
         quote! { let #ident: #ty = #source.to_string(); }
     } else {
         quote! {
             let #ident: #ty = #source
                 .parse::<#ty>()
-                .map_err(|_| ember_core::EmberError::msg("invalid parameter"))?;
+                .map_err(|_| ember_core::EmberError::bad_request(#invalid))?;
         }
     }
 }
 
-fn build_query_expr(
-    ident: &syn::Ident,
-    ty: &Type,
-    query_fn: &syn::Ident,
-) -> proc_macro2::TokenStream {
+fn build_query_expr(ident: &syn::Ident, ty: &Type) -> proc_macro2::TokenStream {
     let name = ident.to_string();
+    let missing = format!("missing query parameter `{name}`");
+    let invalid = format!("invalid query parameter `{name}`");
     if is_string_type(ty) {
         quote! {
-            let #ident: #ty = #query_fn(query, #name)
+            let #ident: #ty = request
+                .query_param(#name)
+                .map(str::to_string)
                 .unwrap_or_default();
         }
     } else {
         quote! {
-            let raw = #query_fn(query, #name)
-                .ok_or_else(|| ember_ext_exceptions::EmberError::msg("missing query parameter"))?;
+            let raw = request
+                .query_param(#name)
+                .ok_or_else(|| ember_core::EmberError::bad_request(#missing))?;
             let #ident: #ty = raw
                 .parse::<#ty>()
-                .map_err(|_| ember_ext_exceptions::EmberError::msg("invalid query parameter"))?;
+                .map_err(|_| ember_core::EmberError::bad_request(#invalid))?;
         }
     }
 }
 
 /// Marks a GET handler and validates the route attribute shape.
+///
+/// Usage: `#[get("/books")]`, `#[get("/books", etag)]` or
+/// `#[get("/books/{id}", etag, cache_control = "max-age=60")]`. With `etag`
+/// responses carry a strong `ETag` hashed from the body, `If-None-Match` is
+/// answered with `304` and `If-Match` on other methods for the path is checked
+/// against it. `cache_control` is accepted by every method attribute.
 #[proc_macro_attribute]
 pub fn get(args: TokenStream, input: TokenStream) -> TokenStream {
     match parse_route_attr(args, "#[get]") {
-        Ok(_lit) => input,
+        Ok(_args) => input,
         Err(err) => err,
     }
 }
@@ -336,7 +500,7 @@ pub fn get(args: TokenStream, input: TokenStream) -> TokenStream {
 #[proc_macro_attribute]
 pub fn post(args: TokenStream, input: TokenStream) -> TokenStream {
     match parse_route_attr(args, "#[post]") {
-        Ok(_lit) => input,
+        Ok(_args) => input,
         Err(err) => err,
     }
 }
@@ -345,7 +509,7 @@ pub fn post(args: TokenStream, input: TokenStream) -> TokenStream {
 #[proc_macro_attribute]
 pub fn put(args: TokenStream, input: TokenStream) -> TokenStream {
     match parse_route_attr(args, "#[put]") {
-        Ok(_lit) => input,
+        Ok(_args) => input,
         Err(err) => err,
     }
 }
@@ -354,7 +518,7 @@ pub fn put(args: TokenStream, input: TokenStream) -> TokenStream {
 #[proc_macro_attribute]
 pub fn patch(args: TokenStream, input: TokenStream) -> TokenStream {
     match parse_route_attr(args, "#[patch]") {
-        Ok(_lit) => input,
+        Ok(_args) => input,
         Err(err) => err,
     }
 }
@@ -363,7 +527,7 @@ pub fn patch(args: TokenStream, input: TokenStream) -> TokenStream {
 #[proc_macro_attribute]
 pub fn delete(args: TokenStream, input: TokenStream) -> TokenStream {
     match parse_route_attr(args, "#[delete]") {
-        Ok(_lit) => input,
+        Ok(_args) => input,
         Err(err) => err,
     }
 }
@@ -372,7 +536,7 @@ pub fn delete(args: TokenStream, input: TokenStream) -> TokenStream {
 #[proc_macro_attribute]
 pub fn head(args: TokenStream, input: TokenStream) -> TokenStream {
     match parse_route_attr(args, "#[head]") {
-        Ok(_lit) => input,
+        Ok(_args) => input,
         Err(err) => err,
     }
 }
@@ -381,11 +545,26 @@ pub fn head(args: TokenStream, input: TokenStream) -> TokenStream {
 #[proc_macro_attribute]
 pub fn options(args: TokenStream, input: TokenStream) -> TokenStream {
     match parse_route_attr(args, "#[options]") {
-        Ok(_lit) => input,
+        Ok(_args) => input,
         Err(err) => err,
     }
 }
 
+/// Marks a WebSocket route and validates the attribute shape.
+///
+/// Usage: `#[websocket("/orders/live")]` or
+/// `#[websocket("/docs/{id}", max_message_bytes = 1048576)]`. The handler takes
+/// a `WebSocket` plus any path or query parameters, runs once the handshake
+/// completes and may return `()` or `Result<(), EmberError>`. Controllers with
+/// WebSocket routes must implement `Clone`.
+#[proc_macro_attribute]
+pub fn websocket(args: TokenStream, input: TokenStream) -> TokenStream {
+    match syn::parse2::<WebSocketArgs>(args.into()) {
+        Ok(_args) => input,
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
 /// Marks a service type for DI registration.
 #[proc_macro_attribute]
 pub fn service(_args: TokenStream, input: TokenStream) -> TokenStream {
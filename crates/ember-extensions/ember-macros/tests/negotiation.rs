@@ -0,0 +1,46 @@
+//! `Accept` negotiation in macro-generated controllers.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ember_core::{EmberError, HttpHandler, HttpResponse, Json, Request};
+use ember_macros::{controller, post};
+
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+struct Orders;
+
+#[controller]
+impl Orders {
+    #[post("/orders")]
+    pub async fn create(&self) -> Result<Json<usize>, EmberError> {
+        Ok(Json(CREATED.fetch_add(1, Ordering::SeqCst) + 1))
+    }
+
+    #[post("/orders/raw")]
+    pub fn raw(&self) -> HttpResponse {
+        HttpResponse::text(200, "raw")
+    }
+}
+
+async fn post_with_accept(path: &str, accept: &str) -> HttpResponse {
+    let mut request = Request::new("POST", path);
+    request.headers.insert("Accept", accept);
+    Orders.handle(&request).await.unwrap()
+}
+
+#[tokio::test]
+async fn unacceptable_requests_never_reach_the_handler() {
+    let response = post_with_accept("/orders", "text/html").await;
+    assert_eq!(response.status, 406);
+    assert_eq!(response.headers.get("vary"), Some("Accept"));
+    assert_eq!(CREATED.load(Ordering::SeqCst), 0);
+
+    let response = post_with_accept("/orders", "application/*").await;
+    assert_eq!(response.status, 201);
+    assert_eq!(CREATED.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn handlers_not_returning_json_are_not_negotiated() {
+    assert_eq!(post_with_accept("/orders/raw", "text/html").await.status, 200);
+}
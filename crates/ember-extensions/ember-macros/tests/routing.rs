@@ -0,0 +1,98 @@
+//! Dispatch of macro-generated controllers.
+
+use ember_core::{HttpHandler, HttpResponse, Json, Request};
+use ember_macros::{controller, get, head};
+
+struct Books;
+
+#[controller]
+impl Books {
+    #[get("/books/{id}")]
+    pub fn book(&self, id: u32) -> Json<String> {
+        Json(format!("book {id}"))
+    }
+
+    #[get("/books/search")]
+    pub fn search(&self) -> Json<String> {
+        Json("search".to_string())
+    }
+
+    #[get("/authors/{author}/books/{id}")]
+    pub fn by_author(&self, author: String, id: u32) -> Json<String> {
+        Json(format!("{author} {id}"))
+    }
+
+    #[get("/a/{x}/a")]
+    pub fn overlap(&self, x: String) -> Json<String> {
+        Json(x)
+    }
+
+    #[head("/authors/{author}")]
+    pub fn author_head(&self, author: String) -> HttpResponse {
+        HttpResponse::empty(204).with_header("X-Author", author)
+    }
+
+    #[get("/authors/{author}")]
+    pub fn author(&self, author: String) -> Json<String> {
+        Json(author)
+    }
+}
+
+async fn send(method: &str, path: &str) -> HttpResponse {
+    Books.handle(&Request::new(method, path)).await.unwrap()
+}
+
+fn body(response: &HttpResponse) -> String {
+    String::from_utf8(response.body.clone()).unwrap()
+}
+
+#[tokio::test]
+async fn static_route_wins_over_earlier_template() {
+    let response = send("GET", "/books/search").await;
+    assert_eq!(response.status, 200);
+    assert_eq!(body(&response), "\"search\"");
+    assert_eq!(body(&send("GET", "/books/7").await), "\"book 7\"");
+}
+
+#[tokio::test]
+async fn parameters_do_not_match_across_segments() {
+    assert_eq!(send("GET", "/books/1/reviews").await.status, 404);
+    assert_eq!(send("GET", "/books/").await.status, 404);
+}
+
+#[tokio::test]
+async fn overlapping_prefix_and_suffix_do_not_panic() {
+    assert_eq!(send("GET", "/a/a").await.status, 404);
+    assert_eq!(body(&send("GET", "/a/b/a").await), "\"b\"");
+}
+
+#[tokio::test]
+async fn every_parameter_is_bound() {
+    assert_eq!(body(&send("GET", "/authors/le-guin/books/3").await), "\"le-guin 3\"");
+    let invalid = Books.handle(&Request::new("GET", "/authors/le-guin/books/x")).await;
+    assert_eq!(invalid.unwrap_err().status_code(), 400);
+}
+
+#[tokio::test]
+async fn encoded_slashes_stay_inside_one_parameter() {
+    assert_eq!(send("GET", "/authors/a%2Fb/books/3").await.status, 200);
+    assert_eq!(body(&send("GET", "/authors/a%2Fb/books/3").await), "\"a/b 3\"");
+    assert_eq!(body(&send("GET", "/authors/le%20guin").await), "\"le guin\"");
+    assert_eq!(send("GET", "/authors/x%2F..%2Fbooks/7").await.status, 404);
+    assert_eq!(body(&send("GET", "/b%6Foks/search").await), "\"search\"");
+}
+
+#[tokio::test]
+async fn head_uses_explicit_route_or_get() {
+    let explicit = send("HEAD", "/authors/le-guin").await;
+    assert_eq!(explicit.status, 204);
+    assert_eq!(explicit.headers.get("x-author"), Some("le-guin"));
+    assert_eq!(body(&send("HEAD", "/books/7").await), "\"book 7\"");
+}
+
+#[tokio::test]
+async fn wrong_method_gets_405_with_allow() {
+    let response = send("DELETE", "/books/7").await;
+    assert_eq!(response.status, 405);
+    assert_eq!(response.headers.get("allow"), Some("GET, HEAD, OPTIONS"));
+}
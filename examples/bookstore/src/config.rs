@@ -4,7 +4,7 @@
 
 use ember_ext_db::DbConfig;
 use ember_ext_db::HasDbConfig;
-use ember_core::HasEmberService;
+use ember_core::{HasEmberService, ServerConfig};
 use serde::Deserialize;
 
 /// Ember service configuration.
@@ -21,6 +21,9 @@ pub struct EmberServiceConfig {
 pub struct EmberConfig {
     /// Service configuration.
     pub service: EmberServiceConfig,
+    /// Built-in HTTP server settings.
+    #[serde(default)]
+    pub server: ServerConfig,
 }
 
 /// Bookstore application configuration.
@@ -63,4 +66,8 @@ impl HasEmberService for AppConfig {
     fn listen_addr(&self) -> Option<&str> {
         Some(self.ember.service.listen.as_str())
     }
+
+    fn server_config(&self) -> Option<&ServerConfig> {
+        Some(&self.ember.server)
+    }
 }
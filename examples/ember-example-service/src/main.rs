@@ -71,7 +71,7 @@ fn run_ember() -> Result<()> {
     let _ = controller.health();
 
     let mut app = ember_core::App::new();
-    app.register_controller(controller);
+    app.register_controller(controller).map_err(anyhow::Error::new)?;
     log_startup("ember-example-service", "ember-runtime");
     app.run().map_err(anyhow::Error::new)?;
 